@@ -15,6 +15,7 @@ use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, Responder,
 use serde_json::json;
 use std::{borrow::Cow, fmt::Display};
 
+use crate::app::bmc_error::BmcError;
 use crate::serial_service::serial_handler::SerialError;
 
 /// Specifies the different repsonses that this legacy API can return. Implements
@@ -83,6 +84,27 @@ impl From<anyhow::Error> for LegacyResponse {
     }
 }
 
+impl From<BmcError> for LegacyResponse {
+    fn from(e: BmcError) -> Self {
+        if let BmcError::Other(e) = e {
+            return e.into();
+        }
+
+        let status = match &e {
+            BmcError::InvalidNodeMask(_) => StatusCode::BAD_REQUEST,
+            BmcError::NotSupported(..) => StatusCode::NOT_IMPLEMENTED,
+            BmcError::MaintenanceMode
+            | BmcError::Cancelled
+            | BmcError::NodeLimit(_)
+            | BmcError::NodeBusy(_)
+            | BmcError::NodeRunning(_) => StatusCode::CONFLICT,
+            BmcError::PowerGoodTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        LegacyResponse::Error(status, e.to_string().into())
+    }
+}
+
 impl From<serde_json::Error> for LegacyResponse {
     fn from(value: serde_json::Error) -> Self {
         LegacyResponse::Error(StatusCode::INTERNAL_SERVER_ERROR, value.to_string().into())
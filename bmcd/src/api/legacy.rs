@@ -19,13 +19,16 @@ use crate::app::bmc_application::{BmcApplication, UsbConfig};
 use crate::app::bmc_info::{
     get_fs_stat, get_ipv4_address, get_mac_address, get_net_interfaces, get_storage_info,
 };
+use crate::app::flash_jobs::{FlashJobError, FlashJobId, FlashJobState};
 use crate::app::transfer_action::InitializeTransfer;
 use crate::app::transfer_action::UpgradeCommand;
-use crate::hal::{NodeId, UsbMode, UsbRoute};
+use crate::app::upgrade_worker::{BlockSize, FlashOptions, VerifyMode};
+use crate::hal::{NodeId, UsbMode, UsbRoute, ALL_NODES};
 use crate::serial_service::serial::SerialConnections;
 use crate::serial_service::{legacy_serial_get_handler, legacy_serial_set_handler};
 use crate::streaming_data_service::data_transfer::DataTransfer;
 use crate::streaming_data_service::StreamingDataService;
+use crate::utils::ChecksumAlgo;
 use actix_files::file_extension_to_mime;
 use actix_multipart::Multipart;
 use actix_web::guard::{fn_guard, GuardContext};
@@ -36,6 +39,7 @@ use async_compression::tokio::bufread::GzipEncoder;
 use async_compression::Level;
 use board_info::{self, BoardInfoAttribute};
 use humansize::{format_size, DECIMAL};
+use serde::Serialize;
 use serde_json::json;
 use std::collections::HashMap;
 use std::ffi::c_ulong;
@@ -46,6 +50,7 @@ use std::process::Command;
 use std::str::FromStr;
 use std::time::Duration;
 use tokio::io::AsyncBufReadExt;
+use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 
@@ -84,6 +89,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     )
     .service(handle_file_upload)
     .service(cancel_file_upload)
+    .service(flash_stream_handler)
     .service(backup_handler);
 }
 
@@ -92,24 +98,30 @@ pub fn info_config(cfg: &mut web::ServiceConfig) {
 }
 
 fn flash_status_guard(context: &GuardContext<'_>) -> bool {
-    let Some(query) = context.head().uri.query() else {
-        return false;
-    };
-    query.contains("opt=get") && (query.contains("type=flash") || query.contains("type=firmware"))
+    query_selects(context, "get", &["flash", "firmware"])
 }
 
 fn flash_guard(context: &GuardContext<'_>) -> bool {
-    let Some(query) = context.head().uri.query() else {
-        return false;
-    };
-    query.contains("opt=set") && (query.contains("type=flash") || query.contains("type=firmware"))
+    query_selects(context, "set", &["flash", "firmware"])
 }
 
 fn set_node_info_guard(context: &GuardContext<'_>) -> bool {
+    query_selects(context, "set", &["node_info"])
+}
+
+/// Whether the request has exactly the given `opt` and one of `types` as
+/// `type`, e.g. `flash` does not select `flash_job`.
+fn query_selects(context: &GuardContext<'_>, opt: &str, types: &[&str]) -> bool {
     let Some(query) = context.head().uri.query() else {
         return false;
     };
-    query.contains("opt=set") && query.contains("type=node_info")
+    let Ok(query) = Query::from_query(query) else {
+        return false;
+    };
+    query.get("opt").map(String::as_str) == Some(opt)
+        && query
+            .get("type")
+            .is_some_and(|ty| types.contains(&ty.as_str()))
 }
 
 #[get("/backup")]
@@ -162,33 +174,89 @@ async fn api_entry(
         return LegacyResponse::bad_request("Missing `type` parameter");
     };
 
+    let bmc_data = bmc.clone();
     let bmc = bmc.as_ref();
     match (ty.as_ref(), is_set) {
-        ("usb_boot", true) => usb_boot(bmc, query).await.into(),
+        ("usb_boot", true) => usb_boot(bmc, query).into(),
+        ("usb_boot", false) => get_usb_boot(bmc).into(),
+        ("flash_job", true) => start_flash_job(bmc_data, query).await.into(),
+        ("flash_job", false) => get_flash_job(bmc, query).into(),
+        ("cancel_flash_job", true) => cancel_flash_job(bmc, query).into(),
+        ("pause_flash_job", true) => pause_flash_job(bmc, query, true).into(),
+        ("resume_flash_job", true) => pause_flash_job(bmc, query, false).into(),
+        ("usb_timings", true) => set_usb_timings(bmc, query).await.into(),
+        ("usb_timings", false) => get_usb_timings(bmc).await.into(),
+        ("node_by_serial", false) => get_node_by_serial(bmc, query).await.into(),
+        ("forget_serial", true) => forget_serial(bmc, query).await.into(),
+        ("gpio", false) => get_gpio_snapshot(bmc).into(),
+        ("usb_devices", false) => get_usb_devices(bmc).into(),
+        ("supported_device", true) => add_supported_device(bmc, query).await.into(),
+        ("supported_device", false) => json!(bmc.custom_devices()).into(),
         ("clear_usb_boot", true) => clear_usb_boot(bmc).into(),
         ("network", true) => reset_network(bmc).await.into(),
         ("nodeinfo", true) => set_node_info().into(),
         ("nodeinfo", false) => get_node_info(bmc).into(),
         ("node_info", false) => get_node_aux_info(bmc).await.into(),
+        ("uptime", false) => get_node_uptimes(bmc).await.into(),
         ("node_to_msd", true) => set_node_to_msd(bmc, query).await.into(),
+        ("cancel_msd", true) => cancel_msd(bmc, query).into(),
+        ("images", false) => get_images(bmc, query).await.into(),
+        ("mount_storage", true) => mount_storage(bmc_data.clone(), query).await.into(),
+        ("unmount_storage", true) => unmount_storage(bmc, query).await.into(),
+        ("rpiboot", true) => set_rpiboot(bmc, query).await.into(),
         ("other", false) => get_system_information().await.into(),
         ("power", true) => set_node_power(bmc, query).await,
         ("power", false) => get_node_power(bmc).await.into(),
+        ("power_preview", false) => preview_node_power(bmc, query).await.into(),
+        ("stage_power", true) => stage_node_power(bmc, query).into(),
+        ("stage_power", false) => get_staged_power(bmc).await.into(),
+        ("commit_power", true) => commit_node_power(bmc).await.into(),
+        ("discard_power", true) => discard_node_power(bmc).into(),
+        ("power_button", true) => press_power_button(bmc, query).await.into(),
+        ("quick_toggle_power", true) => quick_toggle_power(bmc).await.into(),
+        ("node_priority", true) => set_node_priority(bmc, query).await.into(),
+        ("node_priority", false) => get_node_priorities(bmc).await.into(),
+        ("resync_power", true) => resync_power(bmc).await.into(),
+        ("slots", true) => set_activated_slots(bmc, query).await.into(),
+        ("configure_nodes", true) => configure_nodes(bmc, query).await.into(),
+        ("power_profile", true) => save_power_profile(bmc, query).await.into(),
+        ("power_profile", false) => get_power_profiles(bmc).await.into(),
+        ("apply_power_profile", true) => apply_power_profile(bmc, query).await.into(),
+        ("max_active_nodes", true) => set_max_active_nodes(bmc, query).await.into(),
+        ("max_active_nodes", false) => get_max_active_nodes(bmc).await.into(),
+        ("long_press", true) => set_long_press(bmc, query).await.into(),
+        ("long_press", false) => get_long_press(bmc).await.into(),
+        ("power_off_on_exit", true) => set_power_off_on_exit(bmc, query).await.into(),
+        ("power_off_on_exit", false) => get_power_off_on_exit(bmc).await.into(),
+        ("ignore_power_good", true) => set_ignore_power_good(bmc, query).await.into(),
+        ("maintenance", true) => set_maintenance(bmc, query).await.into(),
+        ("maintenance", false) => get_maintenance(bmc).await.into(),
+        ("ignore_power_good", false) => get_ignore_power_good(bmc).await.into(),
         ("reboot", true) => reboot(bmc, query).await.into(),
         ("reload", true) => reload_self().into(),
         ("reset", true) => reset_node(bmc, query).await.into(),
+        ("locate", true) => locate_node(bmc, query).into(),
         ("sdcard", true) => format_sdcard().into(),
         ("sdcard", false) => get_sdcard_info(),
         ("uart", false) => legacy_serial_get_handler(serial, query).await.into(),
         ("uart", true) => legacy_serial_set_handler(serial, query).await.into(),
         ("usb", true) => set_usb_mode(bmc, query).await.into(),
         ("usb", false) => get_usb_mode(bmc).await.into(),
+        ("usb_node_modes", false) => get_node_usb_modes(bmc).await.into(),
+        ("usb_node_mode", true) => set_node_usb_mode(bmc, query).await.into(),
+        ("node_busy", false) => get_node_busy(bmc).into(),
+        ("status", false) => get_status(bmc).await.into(),
+        ("presence", false) => get_node_presence(bmc).await.into(),
         ("usb_node1", true) => set_node1_usb_mode(bmc, query).await.into(),
         ("usb_node1", false) => get_node1_usb_mode(bmc).await,
         ("info", false) => get_info().await.into(),
         ("cooling", false) => get_cooling_info().await.into(),
         ("cooling", true) => set_cooling_info(bmc, query).await.into(),
-        ("about", false) => get_about().await.into(),
+        ("about", false) => get_about(bmc).await.into(),
+        ("ready", false) => get_ready(bmc, query).await,
+        ("selftest", false) => json!(bmc.selftest_report()).into(),
+        ("initialize", true) => initialize(bmc).await.into(),
+        ("factory_reset", true) => factory_reset(bmc, query).await.into(),
         _ => (
             StatusCode::BAD_REQUEST,
             format!("Invalid `type` parameter {}", ty),
@@ -209,7 +277,7 @@ fn reload_self() -> impl Into<LegacyResponse> {
     ()
 }
 
-async fn get_about() -> impl Into<LegacyResponse> {
+async fn get_about(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
     let bmcd_version = env!("CARGO_PKG_VERSION");
     let build_time = build_time::build_time_utc!("%Y-%m-%d %H:%M:%S-00:00");
 
@@ -232,6 +300,7 @@ async fn get_about() -> impl Into<LegacyResponse> {
         {
             "board_model": board_model,
             "board_revision": board_revision,
+            "hardware_revision": bmc.board_revision(),
             "hostname": hostname,
             "api": API_VERSION,
             "version": version,
@@ -242,6 +311,39 @@ async fn get_about() -> impl Into<LegacyResponse> {
     )
 }
 
+/// Reports whether the BMC finished initializing. Returns 503 when it did not.
+/// An optional `timeout` (seconds) waits for the BMC to become ready.
+async fn get_ready(bmc: &BmcApplication, query: Query) -> LegacyResponse {
+    if let Some(timeout) = query.get("timeout") {
+        let Ok(secs) = u64::from_str(timeout) else {
+            return LegacyResponse::bad_request("`timeout` parameter is not a number");
+        };
+
+        let _ = tokio::time::timeout(Duration::from_secs(secs), bmc.wait_ready()).await;
+    }
+
+    if bmc.ready() {
+        json!({ "ready": true }).into()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "BMC is not ready").into()
+    }
+}
+
+/// Retries the initialization of the BMC, see [`BmcApplication::initialize`].
+async fn initialize(bmc: &BmcApplication) -> LegacyResult<()> {
+    bmc.initialize().await.map_err(Into::into)
+}
+
+/// Requires the `confirm` parameter, as all settings are lost.
+async fn factory_reset(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    if !query.contains_key("confirm") {
+        return Err(LegacyResponse::bad_request(
+            "factory reset clears all settings, pass `confirm` to proceed",
+        ));
+    }
+    Ok(bmc.factory_reset().await?)
+}
+
 async fn get_info() -> impl Into<LegacyResponse> {
     let storage = get_storage_info();
     let ips = get_net_interfaces().await;
@@ -254,19 +356,120 @@ async fn get_info() -> impl Into<LegacyResponse> {
 }
 
 async fn reboot(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
-    bmc.reboot(query.contains_key("fel"))
+    bmc.reboot_self(query.contains_key("fel"))
         .await
         .map_err(Into::into)
 }
 
 async fn reset_node(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
     let node = get_node_param(&query)?;
-    Ok(bmc.reset_node(node).await?)
+    Ok(bmc.reset_node(node, query.contains_key("wait")).await?)
+}
+
+/// Blinks the node number on the status LED, for `duration` seconds or 30
+/// seconds by default.
+fn locate_node(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    let duration = query
+        .get("duration")
+        .map(|secs| u64::from_str(secs))
+        .transpose()
+        .map_err(|_| LegacyResponse::bad_request("`duration` parameter is not a number"))?
+        .unwrap_or(30);
+    bmc.locate_node(node, Duration::from_secs(duration));
+    Ok(())
+}
+
+fn usb_boot(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    bmc.set_usb_boot(node).map_err(Into::into)
+}
+
+fn get_usb_boot(bmc: &BmcApplication) -> LegacyResult<serde_json::Value> {
+    let node = bmc.get_usb_boot()?;
+    Ok(json!({ "node": node.map(|n| n as u8) }))
 }
 
-async fn usb_boot(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+async fn forget_serial(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
     let node = get_node_param(&query)?;
-    bmc.usb_boot(node, true).await.map_err(Into::into)
+    bmc.forget_serial(node).await;
+    Ok(())
+}
+
+async fn get_node_by_serial(bmc: &BmcApplication, query: Query) -> LegacyResult<serde_json::Value> {
+    let serial = query
+        .get("serial")
+        .ok_or(LegacyResponse::bad_request("Missing `serial` parameter"))?;
+    let node = bmc.find_node_by_serial(serial).await;
+    Ok(json!({ "node": node.map(|n| n as u8) }))
+}
+
+fn get_gpio_snapshot(bmc: &BmcApplication) -> LegacyResult<serde_json::Value> {
+    let snapshot = bmc.gpio_snapshot()?;
+    Ok(serde_json::to_value(snapshot)?)
+}
+
+fn get_usb_devices(bmc: &BmcApplication) -> LegacyResult<serde_json::Value> {
+    let devices = bmc.list_usb_devices()?;
+    Ok(serde_json::to_value(devices)?)
+}
+
+/// Adds a module type by its USB ids, in hex, and the `vendor` of the block
+/// device it exposes.
+async fn add_supported_device(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let hex_id = |param: &str| -> LegacyResult<u16> {
+        let value = query.get(param).ok_or(LegacyResponse::bad_request(format!(
+            "Missing `{param}` parameter"
+        )))?;
+        u16::from_str_radix(value.trim_start_matches("0x"), 16)
+            .map_err(|_| LegacyResponse::bad_request(format!("`{param}` is not a hex number")))
+    };
+    let vid = hex_id("vid")?;
+    let pid = hex_id("pid")?;
+    let vendor = query
+        .get("vendor")
+        .ok_or(LegacyResponse::bad_request("Missing `vendor` parameter"))?;
+
+    bmc.add_supported_device(vid, pid, vendor.clone())
+        .await
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))
+}
+
+/// Updates the given delays, in milliseconds. Omitted delays are unchanged.
+async fn set_usb_timings(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let millis = |param: &str| -> LegacyResult<Option<Duration>> {
+        query
+            .get(param)
+            .map(|v| {
+                u64::from_str(v).map(Duration::from_millis).map_err(|_| {
+                    LegacyResponse::bad_request(format!("`{param}` parameter is not a number"))
+                })
+            })
+            .transpose()
+    };
+
+    let mut timings = bmc.usb_timings().await;
+    if let Some(delay) = millis("power_on_settle")? {
+        timings.power_on_settle = delay;
+    }
+    if let Some(delay) = millis("rpiboot_stage_delay")? {
+        timings.rpiboot_stage_delay = delay;
+    }
+    if let Some(delay) = millis("rockusb_reconnect")? {
+        timings.rockusb_reconnect = delay;
+    }
+
+    bmc.set_usb_timings(timings).await;
+    Ok(())
+}
+
+async fn get_usb_timings(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let timings = bmc.usb_timings().await;
+    json!({
+        "power_on_settle": timings.power_on_settle.as_millis(),
+        "rpiboot_stage_delay": timings.rpiboot_stage_delay.as_millis(),
+        "rockusb_reconnect": timings.rockusb_reconnect.as_millis(),
+    })
 }
 
 fn clear_usb_boot(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
@@ -308,12 +511,87 @@ async fn get_node_aux_info(bmc: &BmcApplication) -> LegacyResult<serde_json::Val
     Ok(serde_json::to_value(infos)?)
 }
 
+/// Reports per node the seconds since it was powered on (`null` when off), and
+/// the total seconds it was powered.
+async fn get_node_uptimes(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let mut uptimes = serde_json::Map::new();
+    for node in NodeId::all() {
+        let uptime = bmc.node_uptime(node).await.map(|t| t.as_secs());
+        let total = bmc.node_total_on_time(node).await.as_secs();
+        uptimes.insert(
+            format!("node{}", node as u8 + 1),
+            json!({ "uptime": uptime, "total": total }),
+        );
+    }
+
+    json!(uptimes)
+}
+
 async fn set_node_to_msd(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
     let node = get_node_param(&query)?;
-    bmc.node_in_msd(node).await?;
+    let msd = bmc.node_in_msd(node, query.contains_key("wait")).await?;
+    tracing::info!(
+        "{node}: {} exposed as {}",
+        msd.module,
+        msd.path.to_string_lossy()
+    );
     Ok(())
 }
 
+/// Exposes the eMMC of a node on the BMC until `unmount_storage`, see
+/// [`BmcApplication::mount_node_storage`].
+async fn mount_storage(
+    bmc: web::Data<BmcApplication>,
+    query: Query,
+) -> LegacyResult<serde_json::Value> {
+    let node = get_node_param(&query)?;
+    let bmc = bmc.into_inner();
+    let device = bmc
+        .mount_node_storage(node, query.contains_key("wait"))
+        .await?;
+    let response = json!({ "device": device.path, "module": device.module.to_string() });
+    bmc.hold_mount(device);
+    Ok(response)
+}
+
+async fn unmount_storage(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    if bmc.release_mount(node).await {
+        Ok(())
+    } else {
+        Err(LegacyResponse::bad_request(format!(
+            "storage of {node} is not mounted"
+        )))
+    }
+}
+
+async fn set_rpiboot(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    let wait = query.contains_key("wait");
+    if get_enabled_param(&query)? {
+        Ok(bmc.enter_rpiboot(node, wait).await?)
+    } else {
+        Ok(bmc.exit_rpiboot(node, wait).await?)
+    }
+}
+
+/// Lists the images kept on the BMC. Checksums that are not known yet are
+/// computed with `checksums`, which reads every such image.
+async fn get_images(bmc: &BmcApplication, query: Query) -> impl Into<LegacyResponse> {
+    json!(bmc.images().list(query.contains_key("checksums")).await)
+}
+
+fn cancel_msd(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    if bmc.cancel_msd(node) {
+        Ok(())
+    } else {
+        Err(LegacyResponse::bad_request(format!(
+            "{node} is not being set up as mass storage device"
+        )))
+    }
+}
+
 async fn read_os_release() -> std::io::Result<HashMap<String, String>> {
     let buffer = tokio::fs::read("/etc/os-release").await?;
     let mut lines = buffer.lines();
@@ -378,21 +656,94 @@ async fn get_system_information() -> impl Into<LegacyResponse> {
 }
 
 async fn set_node_power(bmc: &BmcApplication, query: Query) -> LegacyResponse {
+    let (states, mask) = match get_node_states(&query) {
+        Ok(states) => states,
+        Err(e) => return e,
+    };
+
+    if let Some(node) =
+        NodeId::all().find(|node| mask & node.to_bitfield() != 0 && bmc.is_flashing(*node))
+    {
+        let msg = format!("{} is being flashed, power cannot be changed", node);
+        return (StatusCode::CONFLICT, msg).into();
+    }
+
+    bmc.activate_slot(states, mask).await.into()
+}
+
+/// Reports the power state of all nodes that a `type=power` request with the
+/// same parameters would result in.
+async fn preview_node_power(
+    bmc: &BmcApplication,
+    query: Query,
+) -> LegacyResult<impl Into<LegacyResponse>> {
+    let (states, mask) = get_node_states(&query)?;
+    let new_state = bmc.preview_activation(states, mask).await?;
+
+    let mut nodes = serde_json::Map::new();
+    for node in NodeId::all() {
+        let on = new_state & node.to_bitfield() != 0;
+        nodes.insert(format!("node{}", node as u8 + 1), json!(u8::from(on)));
+    }
+    Ok(json!(nodes))
+}
+
+/// Stages the `nodeN=0|1` parameters, see
+/// [`BmcApplication::stage_activation`].
+fn stage_node_power(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let (states, mask) = get_node_states(&query)?;
+    for node in NodeId::all().filter(|node| mask & node.to_bitfield() != 0) {
+        bmc.stage_activation(node, states & node.to_bitfield() != 0);
+    }
+    Ok(())
+}
+
+/// Reports the staged change of each node, `null` for nodes without one, and
+/// the activated slots a commit would result in.
+async fn get_staged_power(bmc: &BmcApplication) -> LegacyResult<impl Into<LegacyResponse>> {
+    let (states, mask) = bmc.staged_activation();
+    let preview = bmc.preview_activation(states, mask).await?;
+
+    let mut staged = serde_json::Map::new();
+    let mut result = serde_json::Map::new();
+    for node in NodeId::all() {
+        let key = format!("node{}", node as u8 + 1);
+        let bit = node.to_bitfield();
+        let change = (mask & bit != 0).then(|| u8::from(states & bit != 0));
+        staged.insert(key.clone(), json!(change));
+        result.insert(key, json!(u8::from(preview & bit != 0)));
+    }
+    Ok(json!({ "staged": staged, "result": result }))
+}
+
+async fn commit_node_power(bmc: &BmcApplication) -> LegacyResult<impl Into<LegacyResponse>> {
+    let activated = bmc.commit_activation().await?;
+    Ok(json!({ "activated": activated }))
+}
+
+fn discard_node_power(bmc: &BmcApplication) -> LegacyResult<()> {
+    bmc.discard_activation();
+    Ok(())
+}
+
+/// Parses the `nodeN=0|1` parameters into node states and the mask of the
+/// nodes given.
+fn get_node_states(query: &Query) -> Result<(u8, u8), LegacyResponse> {
     let mut mask = 0;
     let mut states = 0;
 
-    for idx in 0..4 {
-        let param = format!("node{}", idx + 1);
+    for node in NodeId::all() {
+        let param = format!("node{}", node as u8 + 1);
         let req_status = match query.get(&param).map(String::as_str) {
             Some("0") => false,
             Some("1") => true,
             Some(x) => {
                 let msg = format!("Invalid value `{}` for parameter `{}`", x, param);
-                return (StatusCode::BAD_REQUEST, msg).into();
+                return Err((StatusCode::BAD_REQUEST, msg).into());
             }
             None => continue,
         };
-        let bit = 1 << idx;
+        let bit = node.to_bitfield();
 
         mask |= bit;
 
@@ -401,26 +752,214 @@ async fn set_node_power(bmc: &BmcApplication, query: Query) -> LegacyResponse {
         }
     }
 
-    bmc.activate_slot(states, mask)
+    Ok((states, mask))
+}
+
+async fn set_activated_slots(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let mask = query
+        .get("mask")
+        .ok_or(LegacyResponse::bad_request("Missing `mask` parameter"))?;
+    let mask = u8::from_str(mask)
+        .ok()
+        .filter(|mask| *mask <= ALL_NODES)
+        .ok_or(LegacyResponse::bad_request(
+            "`mask` parameter is not a valid node mask",
+        ))?;
+
+    bmc.set_activated_slots(mask).await.map_err(Into::into)
+}
+
+/// Expects a `nodeN=<activate><power>` parameter per node to configure, e.g.
+/// `node1=11&node2=00`.
+async fn configure_nodes(
+    bmc: &BmcApplication,
+    query: Query,
+) -> LegacyResult<impl Into<LegacyResponse>> {
+    let mut spec = Vec::new();
+    for node in NodeId::all() {
+        let param = format!("node{}", node as u8 + 1);
+        let (activate, power) = match query.get(&param).map(String::as_str) {
+            Some("00") => (false, false),
+            Some("01") => (false, true),
+            Some("10") => (true, false),
+            Some("11") => (true, true),
+            Some(x) => {
+                let msg = format!("Invalid value `{}` for parameter `{}`", x, param);
+                return Err(LegacyResponse::bad_request(msg));
+            }
+            None => continue,
+        };
+        spec.push((node, activate, power));
+    }
+
+    let suppressed = bmc
+        .configure_nodes(&spec)
+        .await?
+        .into_iter()
+        .map(|node| node as u8 + 1)
+        .collect::<Vec<_>>();
+    Ok(json!({ "suppressed": suppressed }))
+}
+
+async fn save_power_profile(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let name = query
+        .get("name")
+        .ok_or(LegacyResponse::bad_request("Missing `name` parameter"))?;
+    let mask = query
+        .get("mask")
+        .ok_or(LegacyResponse::bad_request("Missing `mask` parameter"))?;
+    let mask = u8::from_str(mask)
+        .map_err(|_| LegacyResponse::bad_request("`mask` parameter is not a number"))?;
+
+    bmc.save_power_profile(name, mask)
+        .await
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))
+}
+
+async fn apply_power_profile(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let name = query
+        .get("name")
+        .ok_or(LegacyResponse::bad_request("Missing `name` parameter"))?;
+    bmc.apply_power_profile(name).await.map_err(Into::into)
+}
+
+async fn get_power_profiles(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!(bmc.power_profiles().await)
+}
+
+/// Sets the maximum of simultaneously powered nodes. Omitting `limit` removes
+/// the limit.
+async fn set_max_active_nodes(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let limit = query
+        .get("limit")
+        .map(|l| u8::from_str(l))
+        .transpose()
+        .map_err(|_| LegacyResponse::bad_request("`limit` parameter is not a number"))?;
+
+    bmc.set_max_active_nodes(limit)
         .await
-        .context("set power state")
-        .into()
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))
+}
+
+async fn get_max_active_nodes(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!({ "max_active_nodes": bmc.max_active_nodes().await })
+}
+
+/// Sets the long press duration of the power button, in milliseconds.
+async fn set_long_press(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let duration = query
+        .get("duration")
+        .ok_or(LegacyResponse::bad_request("Missing `duration` parameter"))
+        .and_then(|ms| {
+            u64::from_str(ms)
+                .map(Duration::from_millis)
+                .map_err(|_| LegacyResponse::bad_request("`duration` parameter is not a number"))
+        })?;
+
+    bmc.set_long_press_duration(duration)
+        .await
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))
+}
+
+async fn get_long_press(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!({ "duration": bmc.long_press_duration().await.as_millis() as u64 })
+}
+
+/// Behaves as a press of the power button on the front panel. Pass
+/// `long_press` to simulate holding the button.
+async fn press_power_button(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    bmc.toggle_power(query.contains_key("long_press"))
+        .await
+        .map_err(Into::into)
+}
+
+/// Sets the boot priority of a node, see [`BmcApplication::set_node_priority`].
+async fn set_node_priority(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    let priority = query
+        .get("priority")
+        .ok_or(LegacyResponse::bad_request("Missing `priority` parameter"))
+        .and_then(|p| {
+            u8::from_str(p)
+                .map_err(|_| LegacyResponse::bad_request("`priority` parameter is not a number"))
+        })?;
+    bmc.set_node_priority(node, priority).await;
+    Ok(())
+}
+
+async fn get_node_priorities(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let priorities = bmc.node_priorities().await;
+    let priorities = priorities
+        .iter()
+        .enumerate()
+        .map(|(idx, priority)| (format!("node{}", idx + 1), json!(priority)))
+        .collect::<serde_json::Map<_, _>>();
+    json!([priorities])
+}
+
+/// Toggles the power of the nodes of the current layout, see
+/// [`BmcApplication::quick_toggle_power`].
+async fn quick_toggle_power(bmc: &BmcApplication) -> LegacyResult<()> {
+    bmc.quick_toggle_power()
+        .await
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))
+}
+
+/// Corrects the power state after manual changes to the hardware, see
+/// [`BmcApplication::resync_power_state`]. Responds with the corrected state.
+async fn resync_power(bmc: &BmcApplication) -> LegacyResult<serde_json::Value> {
+    let state = bmc.resync_power_state().await?;
+    let mut states = serde_json::Map::new();
+    for node in NodeId::all() {
+        let powered = state & node.to_bitfield() != 0;
+        states.insert(format!("node{}", node as u8 + 1), u8::from(powered).into());
+    }
+    Ok(json!([states]))
+}
+
+fn get_enabled_param(query: &Query) -> LegacyResult<bool> {
+    match query.get("enabled").map(String::as_str) {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(LegacyResponse::bad_request("`enabled` should equal 0 or 1")),
+    }
+}
+
+async fn set_power_off_on_exit(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    bmc.set_power_off_on_exit(get_enabled_param(&query)?).await;
+    Ok(())
+}
+
+async fn get_power_off_on_exit(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!({ "power_off_on_exit": bmc.power_off_on_exit().await })
+}
+
+async fn set_ignore_power_good(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    bmc.set_ignore_power_good(get_enabled_param(&query)?).await;
+    Ok(())
+}
+
+async fn set_maintenance(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    bmc.set_maintenance(get_enabled_param(&query)?).await;
+    Ok(())
+}
+
+async fn get_maintenance(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!({ "maintenance": bmc.maintenance().await })
+}
+
+async fn get_ignore_power_good(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    json!({ "ignore_power_good": bmc.ignore_power_good().await })
 }
 
 async fn get_node_power(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
-    let n1 = get_node_power_status(bmc, NodeId::Node1).await;
-    let n2 = get_node_power_status(bmc, NodeId::Node2).await;
-    let n3 = get_node_power_status(bmc, NodeId::Node3).await;
-    let n4 = get_node_power_status(bmc, NodeId::Node4).await;
+    let mut states = serde_json::Map::new();
+    for node in NodeId::all() {
+        let status = get_node_power_status(bmc, node).await;
+        states.insert(format!("node{}", node as u8 + 1), status.into());
+    }
 
-    json!(
-     [{
-        "node1": n1,
-        "node2": n2,
-        "node3": n3,
-        "node4": n4,
-    }]
-    )
+    json!([states])
 }
 
 async fn get_node_power_status(bmc: &BmcApplication, node: NodeId) -> String {
@@ -498,29 +1037,32 @@ async fn set_usb_mode(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
         UsbRoute::AlternativePort
     };
 
-    let cfg = match (mode, route) {
-        (UsbMode::Device, UsbRoute::AlternativePort) => UsbConfig::UsbA(node),
-        (UsbMode::Device, UsbRoute::Bmc) => UsbConfig::Bmc(node),
-        (UsbMode::Host, route) => UsbConfig::Node(node, route),
-        (UsbMode::Flash, route) => UsbConfig::Flashing(node, route),
-    };
-
-    bmc.configure_usb(cfg)
+    bmc.configure_usb(UsbConfig::from_parts(node, mode, route))
         .await
         .context("set USB mode")
         .map_err(Into::into)
 }
 
+/// Like [`set_usb_mode`], but keeps the route of the USB bus. The route bit of
+/// `mode` is ignored.
+async fn set_node_usb_mode(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let node = get_node_param(&query)?;
+    let mode = query
+        .get("mode")
+        .ok_or(LegacyResponse::bad_request("Missing `mode` parameter"))?;
+    let mode = i32::from_str(mode)
+        .map_err(|_| LegacyResponse::bad_request("Parameter `mode` is not a number"))?;
+
+    bmc.set_node_usb_mode(node, UsbMode::from_api_mode(mode))
+        .await
+        .context("set node USB mode")
+        .map_err(Into::into)
+}
+
 /// gets the USB configuration from the POV of the configured node.
 async fn get_usb_mode(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
     let (config, bus_type) = bmc.get_usb_mode().await;
-
-    let (node, mode, route) = match config {
-        UsbConfig::UsbA(node) => (node, UsbMode::Device, UsbRoute::AlternativePort),
-        UsbConfig::Bmc(node) => (node, UsbMode::Device, UsbRoute::Bmc),
-        UsbConfig::Node(node, route) => (node, UsbMode::Host, route),
-        UsbConfig::Flashing(node, route) => (node, UsbMode::Flash, route),
-    };
+    let (node, mode, route) = config.parts();
 
     json!(
         [{
@@ -532,6 +1074,63 @@ async fn get_usb_mode(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
     )
 }
 
+/// Reports whether each slot holds a module, see
+/// [`BmcApplication::detect_present_nodes`].
+async fn get_node_presence(bmc: &BmcApplication) -> LegacyResult<serde_json::Value> {
+    let presence = bmc.detect_present_nodes().await?;
+    let mut nodes = serde_json::Map::new();
+    for (node, presence) in NodeId::all().zip(presence) {
+        nodes.insert(format!("node{}", node as u8 + 1), json!(presence));
+    }
+    Ok(json!(nodes))
+}
+
+/// Reports the operation that claims each node, `null` for idle nodes.
+fn get_node_busy(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let mut busy = serde_json::Map::new();
+    for node in NodeId::all() {
+        busy.insert(
+            format!("node{}", node as u8 + 1),
+            json!(bmc.node_busy_with(node)),
+        );
+    }
+    json!(busy)
+}
+
+/// Reports the state of the BMC and all nodes in a single response, see
+/// [`BmcApplication::status_snapshot`].
+async fn get_status(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let status = bmc.status_snapshot().await;
+    let (usb_node, usb_mode, usb_route) = status.usb_config.parts();
+
+    let mut nodes = serde_json::Map::new();
+    for (node, node_status) in NodeId::all().zip(status.nodes) {
+        nodes.insert(format!("node{}", node as u8 + 1), json!(node_status));
+    }
+
+    json!({
+        "activated": status.activated,
+        "maintenance": status.maintenance,
+        "power_good": status.power_good,
+        "usb": {
+            "mode": usb_mode,
+            "node": usb_node.to_string(),
+            "route": usb_route,
+        },
+        "nodes": nodes,
+    })
+}
+
+/// Reports the USB mode of each node, `null` for nodes that are not connected
+/// to the USB bus.
+async fn get_node_usb_modes(bmc: &BmcApplication) -> impl Into<LegacyResponse> {
+    let mut modes = serde_json::Map::new();
+    for (node, mode) in NodeId::all().zip(bmc.get_node_usb_modes().await) {
+        modes.insert(format!("node{}", node as u8 + 1), json!(mode));
+    }
+    json!(modes)
+}
+
 async fn set_cooling_info(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
     let device = query
         .get("device")
@@ -572,9 +1171,10 @@ async fn handle_transfer_request(
         ),
         Some("flash") => {
             let node = get_node_param(&query)?;
+            let options = flash_options(&bmc, &query)?;
             (
                 format!("{node} os install service"),
-                UpgradeCommand::Module(node, bmc.clone().into_inner()),
+                UpgradeCommand::Module(node, bmc.clone().into_inner(), options, None),
             )
         }
         _ => {
@@ -594,6 +1194,211 @@ async fn handle_transfer_request(
     Ok(json.to_string())
 }
 
+/// Starts flashing a node in the background. Only images that the BMC can
+/// fetch itself (`image`, `local` or http) are supported. With `await_done`, the
+/// response is sent once the flash finished, and holds its outcome.
+async fn start_flash_job(
+    bmc: web::Data<BmcApplication>,
+    query: Query,
+) -> LegacyResult<serde_json::Value> {
+    let node = get_node_param(&query)?;
+    let data_transfer = flash_job_transfer(&bmc, &query).await?;
+    let options = flash_options(&bmc, &query)?;
+    let do_crc = !query.contains_key("skip_crc");
+    let (done, outcome) = if query.contains_key("await_done") {
+        let (sender, receiver) = oneshot::channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    let id = bmc
+        .into_inner()
+        .start_flash(node, data_transfer, options, do_crc, done)?;
+
+    match outcome {
+        Some(outcome) => {
+            let state = outcome.await.context("flash job dropped")?;
+            Ok(json!({ "id": id, "state": state }))
+        }
+        None => Ok(json!({ "id": id })),
+    }
+}
+
+/// Parses the size in bytes of the `name` parameter, if present.
+fn block_size_param(query: &Query, name: &str) -> LegacyResult<Option<BlockSize>> {
+    query
+        .get(name)
+        .map(|size| {
+            let size = usize::from_str(size).map_err(|_| {
+                LegacyResponse::bad_request(format!("`{name}` parameter is not a number"))
+            })?;
+            BlockSize::new(size).map_err(|e| LegacyResponse::bad_request(e.to_string()))
+        })
+        .transpose()
+}
+
+fn flash_options(bmc: &BmcApplication, query: &Query) -> LegacyResult<FlashOptions> {
+    let retries = query
+        .get("retries")
+        .map(|r| u8::from_str(r))
+        .transpose()
+        .map_err(|_| LegacyResponse::bad_request("`retries` parameter is not a number"))?
+        .unwrap_or_default();
+
+    let block_size = block_size_param(query, "block_size")?.unwrap_or_default();
+    let read_ahead = block_size_param(query, "read_ahead")?;
+
+    // a plain name in the manifest directory, the API must not be able to
+    // write to arbitrary files.
+    let manifest_file = query
+        .get("manifest_file")
+        .map(|name| bmc.manifest_path(name))
+        .transpose()
+        .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))?;
+
+    let timeout = query
+        .get("timeout")
+        .map(|secs| u64::from_str(secs).map(Duration::from_secs))
+        .transpose()
+        .map_err(|_| LegacyResponse::bad_request("`timeout` parameter is not a number"))?;
+
+    let write_offset = query
+        .get("offset")
+        .map(|offset| u64::from_str(offset))
+        .transpose()
+        .map_err(|_| LegacyResponse::bad_request("`offset` parameter is not a number"))?
+        .unwrap_or_default();
+
+    let verify = match query.get("verify").map(String::as_str) {
+        None | Some("full") => VerifyMode::Full,
+        Some("inline") => VerifyMode::Inline,
+        Some("none") => VerifyMode::None,
+        Some(_) => {
+            return Err(LegacyResponse::bad_request(
+                "`verify` should equal 'full', 'inline' or 'none'",
+            ))
+        }
+    };
+
+    let checksum = match query.get("checksum").map(String::as_str) {
+        None | Some("crc64") => ChecksumAlgo::Crc64,
+        Some("crc32") => ChecksumAlgo::Crc32,
+        Some("sha256") => ChecksumAlgo::Sha256,
+        Some(_) => {
+            return Err(LegacyResponse::bad_request(
+                "`checksum` should equal 'crc64', 'crc32' or 'sha256'",
+            ))
+        }
+    };
+
+    Ok(FlashOptions {
+        device_override: query.get("device").map(PathBuf::from),
+        allow_flash_running: query.contains_key("allow_running"),
+        wait_for_node: query.contains_key("wait"),
+        retries,
+        dry_run: query.contains_key("dry_run"),
+        checksum,
+        delta: query.contains_key("delta"),
+        block_size,
+        read_ahead,
+        verify,
+        timeout,
+        write_offset,
+        power_on_after: query.contains_key("power_on"),
+        run_hooks: query.contains_key("post_flash"),
+        manifest: query.contains_key("manifest"),
+        manifest_file,
+    })
+}
+
+/// Starts a flash job like the `flash_job` request, and streams its progress
+/// as server-sent `progress` events. The stream ends with a `done` event that
+/// holds the outcome of the job.
+#[get("/flash_stream")]
+async fn flash_stream_handler(
+    bmc: web::Data<BmcApplication>,
+    query: Query,
+) -> LegacyResult<HttpResponse> {
+    let node = get_node_param(&query)?;
+    let data_transfer = flash_job_transfer(&bmc, &query).await?;
+    let options = flash_options(&bmc, &query)?;
+    let do_crc = !query.contains_key("skip_crc");
+    let job = bmc
+        .into_inner()
+        .start_flash_stream(node, data_transfer, options, do_crc)?;
+
+    let id = job.id;
+    let progress = job
+        .progress
+        .map(|progress| server_sent_event("progress", &progress));
+    let done = futures::stream::once(async move {
+        let state = job
+            .done
+            .await
+            .unwrap_or_else(|_| FlashJobState::Error(FlashJobError::new("flash job dropped")));
+        server_sent_event("done", &json!({ "id": id, "state": state }))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(progress.chain(done)))
+}
+
+fn server_sent_event(event: &str, data: &impl Serialize) -> Result<web::Bytes, serde_json::Error> {
+    let data = serde_json::to_string(data)?;
+    Ok(web::Bytes::from(format!(
+        "event: {event}\ndata: {data}\n\n"
+    )))
+}
+
+/// Flash jobs only support images that the BMC can fetch itself.
+async fn flash_job_transfer(bmc: &BmcApplication, query: &Query) -> LegacyResult<DataTransfer> {
+    if let Some(name) = query.get("image") {
+        let path = bmc
+            .images()
+            .resolve(name)
+            .map_err(|e| LegacyResponse::bad_request(format!("{:#}", e)))?;
+        return Ok(DataTransfer::local(path));
+    }
+    if !query.contains_key("local") && !query.get("file").is_some_and(|f| f.starts_with("http")) {
+        return Err(LegacyResponse::bad_request(
+            "flash jobs require an `image`, a `local` or an http `file`",
+        ));
+    }
+    create_data_transfer(query).await
+}
+
+fn get_flash_job_id(query: &Query) -> LegacyResult<FlashJobId> {
+    let id = query
+        .get("id")
+        .ok_or(LegacyResponse::bad_request("Missing `id` parameter"))?;
+    FlashJobId::from_str(id)
+        .map_err(|_| LegacyResponse::bad_request("`id` parameter is not a number"))
+}
+
+fn get_flash_job(bmc: &BmcApplication, query: Query) -> LegacyResult<serde_json::Value> {
+    let id = get_flash_job_id(&query)?;
+    let status = bmc
+        .flash_status(id)
+        .ok_or((StatusCode::NOT_FOUND, "flash job does not exist"))?;
+    Ok(serde_json::to_value(status)?)
+}
+
+fn cancel_flash_job(bmc: &BmcApplication, query: Query) -> LegacyResult<()> {
+    let id = get_flash_job_id(&query)?;
+    if !bmc.cancel_flash(id) {
+        return Err((StatusCode::NOT_FOUND, "flash job does not exist").into());
+    }
+    Ok(())
+}
+
+fn pause_flash_job(bmc: &BmcApplication, query: Query, paused: bool) -> LegacyResult<()> {
+    let id = get_flash_job_id(&query)?;
+    if !bmc.pause_flash(id, paused) {
+        return Err((StatusCode::NOT_FOUND, "flash job does not exist").into());
+    }
+    Ok(())
+}
+
 async fn create_data_transfer(query: &Query) -> LegacyResult<DataTransfer> {
     let file = query.get("file").ok_or(LegacyResponse::bad_request(
         "Invalid `file` query parameter",
@@ -711,4 +1516,39 @@ mod test {
         };
         let _: HashMap<NodeId, NodeInfo> = serde_json::from_value(json).unwrap();
     }
+
+    #[test]
+    fn flash_guards_leave_flash_jobs_to_the_api_entry() {
+        let guard = |uri: &str, guard: fn(&GuardContext<'_>) -> bool| {
+            let request = actix_web::test::TestRequest::with_uri(uri).to_srv_request();
+            guard(&request.guard_ctx())
+        };
+
+        assert!(guard("/api/bmc?opt=set&type=flash&node=1", flash_guard));
+        assert!(guard("/api/bmc?type=firmware&opt=set", flash_guard));
+        assert!(guard("/api/bmc?opt=get&type=flash", flash_status_guard));
+        assert!(!guard(
+            "/api/bmc?opt=set&type=flash_job&node=1",
+            flash_guard
+        ));
+        assert!(!guard(
+            "/api/bmc?opt=get&type=flash_job&id=1",
+            flash_status_guard
+        ));
+        assert!(!guard("/api/bmc?opt=get&type=flash", flash_guard));
+        assert!(guard(
+            "/api/bmc?opt=set&type=node_info",
+            set_node_info_guard
+        ));
+        assert!(!guard(
+            "/api/bmc?opt=settings&type=node_info",
+            set_node_info_guard
+        ));
+    }
+
+    #[test]
+    fn server_sent_events_are_framed() {
+        let event = server_sent_event("done", &json!({ "id": 3 })).unwrap();
+        assert_eq!(event, "event: done\ndata: {\"id\":3}\n\n");
+    }
 }
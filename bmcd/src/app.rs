@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 pub mod bmc_application;
+pub mod bmc_error;
 pub mod bmc_info;
 pub mod cooling_device;
 pub mod event_application;
+pub mod flash_jobs;
+pub mod image_library;
+pub mod selftest;
 pub mod transfer_action;
 pub mod upgrade_worker;
 pub mod usb_gadget;
@@ -11,35 +11,68 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use super::event_application::EventAction;
+use crate::config::Config;
+use crate::hal::board_revision::BoardRevision;
 use crate::hal::helpers::bit_iterator;
-use crate::hal::{NodeId, PinController, UsbMode, UsbRoute};
-use crate::hal::{PowerController, UsbArchitecture};
+use crate::hal::led::{Led, LedController};
+use crate::hal::node_bits::{denied_nodes, limit_nodes, next_state};
+use crate::hal::power_sensor::{PowerMetrics, PowerSensors};
+use crate::hal::{GpioSnapshot, NodeId, PinController, UsbMode, UsbRoute, ALL_NODES, NODE_COUNT};
+use crate::hal::{PartialPowerError, PowerController, UsbArchitecture};
 use crate::persistency::app_persistency::ApplicationPersistency;
 use crate::persistency::app_persistency::PersistencyBuilder;
-use crate::usb_boot::NodeDrivers;
+use crate::streaming_data_service::data_transfer::DataTransfer;
+use crate::streaming_data_service::transfer_context::TransferProgress;
+use crate::usb_boot::{
+    DataTransport, DetectedModule, NodeDrivers, SupportedDevice, UsbDeviceInfo, UsbTimings,
+};
 use crate::utils::{self, get_timestamp_unix};
 use crate::{
     app::usb_gadget::append_msd_config_to_usb_gadget,
     app::usb_gadget::remove_msd_function_from_usb_gadget,
 };
 
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
+use evdev::KeyCode;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::c_ulong;
+use std::fmt::Display;
+use std::future::Future;
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
+use thiserror::Error;
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{oneshot, watch, Mutex, MutexGuard, OwnedMutexGuard};
+use tokio::task::spawn_blocking;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, instrument, trace};
 
+use super::bmc_error::BmcError;
 use super::cooling_device::{get_cooling_state, set_cooling_state, CoolingDevice};
+use super::flash_jobs::{FlashJobHandle, FlashJobId, FlashJobState, FlashJobStatus, FlashJobs};
+use super::image_library::ImageLibrary;
+use super::selftest::SelfTestReport;
+use super::transfer_action::{InitializeTransfer, UpgradeCommand};
+use super::upgrade_worker::FlashOptions;
 
-pub type NodeInfos = [NodeInfo; 4];
+pub type NodeInfos = [NodeInfo; NODE_COUNT as usize];
 type CoolingMap = HashMap<u64, c_ulong>;
+type NodeSerials = [Option<String>; NODE_COUNT as usize];
+type NodeModules = [Option<(u16, u16)>; NODE_COUNT as usize];
+type NodeOnTimes = [u64; NODE_COUNT as usize];
+type NodeOperations = [Option<NodeOperation>; NODE_COUNT as usize];
+type StorageMounts = [Option<MountedDevice>; NODE_COUNT as usize];
+/// Named node layouts, mapping a profile name to its activated nodes bitfield.
+pub type PowerProfiles = BTreeMap<String, u8>;
+/// Boot priority of each node, higher priorities are powered on first.
+pub type NodePriorities = [u8; NODE_COUNT as usize];
 
 /// Stores which slots are actually used. This information is used to determine
 /// for instance, which nodes need to be powered on, when such command is given
@@ -50,7 +83,52 @@ pub const USB_CONFIG: &str = "usb_config";
 pub const NODE_INFO_KEY: &str = "node_info";
 pub const NODE1_USB_MODE: &str = "node1_usb";
 pub const COOLING_DEVICES: &str = "cooling_devices";
+/// Stores the power profiles, see [PowerProfiles].
+pub const POWER_PROFILES_KEY: &str = "power_profiles";
+/// Stores the delays used while booting a module into USB mode, see [UsbTimings].
+pub const USB_TIMINGS_KEY: &str = "usb_timings";
+/// Stores the USB serial number of the module last detected in each slot.
+pub const NODE_SERIALS_KEY: &str = "node_serials";
+/// Stores the USB vendor and product id of the module last detected in each
+/// slot.
+pub const NODE_MODULES_KEY: &str = "node_modules";
+/// Stores the amount of seconds each node was powered, excluding the time
+/// since it was last powered on.
+pub const NODE_ON_TIME_KEY: &str = "node_on_time";
+/// Stores the maximum amount of nodes that are allowed to be powered at the
+/// same time. `None` means no limit.
+pub const MAX_ACTIVE_NODES_KEY: &str = "max_active_nodes";
+/// Stores whether all nodes get powered off when the daemon exits.
+pub const POWER_OFF_ON_EXIT_KEY: &str = "power_off_on_exit";
+/// Stores whether powering on nodes skips waiting for power-good.
+pub const IGNORE_POWER_GOOD_KEY: &str = "ignore_power_good";
+/// Stores whether the BMC is in maintenance mode, see
+/// [`BmcApplication::set_maintenance`].
+pub const MAINTENANCE_KEY: &str = "maintenance";
+/// Stores the module types added at runtime, see
+/// [`BmcApplication::add_supported_device`].
+pub const SUPPORTED_DEVICES_KEY: &str = "supported_devices";
+/// Stores how long the power button must be held for a long press, see
+/// [`BmcApplication::set_long_press_duration`].
+pub const LONG_PRESS_KEY: &str = "long_press_duration";
+/// Stores the nodes that were powered off by
+/// [`BmcApplication::quick_toggle_power`], to power them on again.
+pub const QUICK_TOGGLE_KEY: &str = "quick_toggle";
+/// Stores the boot priorities of the nodes, see [NodePriorities].
+pub const NODE_PRIORITIES_KEY: &str = "node_priorities";
+/// Range of the long press duration.
+const LONG_PRESS_RANGE: std::ops::RangeInclusive<Duration> =
+    Duration::from_secs(1)..=Duration::from_secs(10);
 const COOLING_CAPACITY: usize = 10;
+/// Time the power supply gets to assert power-good before nodes are powered.
+const POWER_GOOD_TIMEOUT: Duration = Duration::from_secs(2);
+const POWER_GOOD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Delay that replaces the power-good check when it is ignored, see
+/// [`BmcApplication::set_ignore_power_good`].
+const POWER_SETTLE_DELAY: Duration = Duration::from_millis(500);
+/// Attempts per initialization step, see [`BmcApplication::initialize`].
+const INITIALIZE_ATTEMPTS: usize = 3;
+const INITIALIZE_RETRY_DELAY: Duration = Duration::from_millis(200);
 
 /// Describes the different configuration the USB bus can be setup
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -65,6 +143,223 @@ pub enum UsbConfig {
     Flashing(NodeId, UsbRoute),
 }
 
+impl UsbConfig {
+    /// The inverse of [`Self::parts`].
+    pub fn from_parts(node: NodeId, mode: UsbMode, route: UsbRoute) -> Self {
+        match (mode, route) {
+            (UsbMode::Device, UsbRoute::AlternativePort) => UsbConfig::UsbA(node),
+            (UsbMode::Device, UsbRoute::Bmc) => UsbConfig::Bmc(node),
+            (UsbMode::Host, route) => UsbConfig::Node(node, route),
+            (UsbMode::Flash, route) => UsbConfig::Flashing(node, route),
+        }
+    }
+
+    /// Splits the configuration into the node that is connected to the USB
+    /// bus, its mode and the route of the bus.
+    pub fn parts(self) -> (NodeId, UsbMode, UsbRoute) {
+        match self {
+            UsbConfig::UsbA(device) => (device, UsbMode::Device, UsbRoute::AlternativePort),
+            UsbConfig::Bmc(device) => (device, UsbMode::Device, UsbRoute::Bmc),
+            UsbConfig::Flashing(device, route) => (device, UsbMode::Flash, route),
+            UsbConfig::Node(host, route) => (host, UsbMode::Host, route),
+        }
+    }
+
+    /// The mode of `node`, or `None` if `node` is not connected to the USB
+    /// bus. The bus is multiplexed, so at most one node is connected.
+    pub fn node_mode(self, node: NodeId) -> Option<UsbMode> {
+        let (connected, mode, _) = self.parts();
+        (connected == node).then_some(mode)
+    }
+}
+
+/// Returned when powering on nodes would exceed the maximum of simultaneously
+/// powered nodes.
+#[derive(Debug, Error)]
+#[error(
+    "at most {limit} node(s) may be powered at once, denied: {}",
+    .denied.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+)]
+pub struct NodeLimitError {
+    pub limit: u8,
+    pub denied: Vec<NodeId>,
+}
+
+/// A node that is exposed as USB mass storage device, see
+/// [`BmcApplication::node_in_msd`].
+#[derive(Debug)]
+pub struct MsdNode {
+    /// Block device of the node.
+    pub path: PathBuf,
+    pub module: DetectedModule,
+}
+
+/// Whether a slot holds a module, separate from its activation, see
+/// [`BmcApplication::detect_present_nodes`]. There are no presence pins on the
+/// board: an empty slot cannot be told apart from a module that never showed
+/// up on the USB bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodePresence {
+    /// The module is currently visible on the USB bus of the BMC.
+    Enumerated,
+    /// A module was detected in this slot by an earlier flash or MSD boot.
+    Recorded,
+    Unknown,
+}
+
+/// Long running operations that claim a node, see
+/// [`BmcApplication::lock_node`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeOperation {
+    Flash,
+    Msd,
+    Reset,
+    Rpiboot,
+}
+
+impl Display for NodeOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeOperation::Flash => f.write_str("flashing"),
+            NodeOperation::Msd => f.write_str("mass storage mode"),
+            NodeOperation::Reset => f.write_str("a reset"),
+            NodeOperation::Rpiboot => f.write_str("entering rpiboot"),
+        }
+    }
+}
+
+/// State of the BMC and its nodes at a single point in time, see
+/// [`BmcApplication::status_snapshot`].
+#[derive(Debug, Clone, Serialize)]
+pub struct BmcStatus {
+    /// Bitfield of the activated, i.e. powered, nodes.
+    pub activated: u8,
+    pub usb_config: UsbConfig,
+    pub maintenance: bool,
+    /// See [`BmcApplication::supply_power_good`], `None` when it cannot be
+    /// read either.
+    pub power_good: Option<bool>,
+    pub nodes: [NodeStatus; NODE_COUNT as usize],
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NodeStatus {
+    pub powered: bool,
+    /// `None` when the node is not connected to the USB bus.
+    pub usb_mode: Option<UsbMode>,
+    /// See [`BmcApplication::power_metrics`].
+    pub power: Option<PowerMetrics>,
+    /// The operation that claims the node, e.g. a running flash.
+    pub operation: Option<NodeOperation>,
+}
+
+/// Returned when an operation on a node is requested while another operation
+/// on that node is still in progress.
+#[derive(Debug, Error)]
+#[error("{node} is busy with {}", busy_with(.operation))]
+pub struct NodeBusyError {
+    pub node: NodeId,
+    /// `None` when the other operation finished in the meantime.
+    pub operation: Option<NodeOperation>,
+}
+
+fn busy_with(operation: &Option<NodeOperation>) -> String {
+    operation.map_or_else(|| "another operation".to_string(), |op| op.to_string())
+}
+
+/// Holds a node claimed by [`BmcApplication::lock_node`], releasing it when
+/// dropped.
+pub struct NodeGuard<'a> {
+    _lock: MutexGuard<'a, ()>,
+    busy: &'a std::sync::Mutex<NodeOperations>,
+    node: NodeId,
+}
+
+impl Drop for NodeGuard<'_> {
+    fn drop(&mut self) {
+        lock_busy(self.busy)[self.node as usize] = None;
+    }
+}
+
+/// A [`NodeGuard`] that owns its claim, see
+/// [`BmcApplication::lock_node_owned`].
+struct OwnedNodeGuard {
+    _lock: OwnedMutexGuard<()>,
+    bmc: Arc<BmcApplication>,
+    node: NodeId,
+}
+
+impl Drop for OwnedNodeGuard {
+    fn drop(&mut self) {
+        lock_busy(&self.bmc.node_busy)[self.node as usize] = None;
+    }
+}
+
+/// The eMMC of a node, exposed as USB mass storage device on the BMC, see
+/// [`BmcApplication::mount_node_storage`]. The node stays claimed as long as
+/// this value lives. [`MountedDevice::release`] restores the USB
+/// configuration and powers the node as it was before. Dropping the value
+/// does the same in the background.
+pub struct MountedDevice {
+    /// Block device of the node.
+    pub path: PathBuf,
+    pub module: DetectedModule,
+    node: NodeId,
+    powered_before: bool,
+    /// Taken by whoever releases the node.
+    guard: Option<OwnedNodeGuard>,
+}
+
+impl MountedDevice {
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    /// Releases the node and waits until its USB configuration and power are
+    /// restored.
+    pub async fn release(mut self) {
+        if let Some(guard) = self.guard.take() {
+            guard
+                .bmc
+                .release_storage(self.node, self.powered_before)
+                .await;
+        }
+    }
+}
+
+impl Drop for MountedDevice {
+    fn drop(&mut self) {
+        let Some(guard) = self.guard.take() else {
+            return;
+        };
+        let (node, powered_before) = (self.node, self.powered_before);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    guard.bmc.release_storage(node, powered_before).await;
+                    drop(guard);
+                });
+            }
+            Err(_) => tracing::warn!(
+                "{node}: storage dropped outside of a runtime, USB configuration not restored"
+            ),
+        }
+    }
+}
+
+/// Returned when the module that shows up on the USB bus is known to sit in
+/// another slot than the one the USB multiplexer was switched to. This points
+/// at a stuck multiplexer rather than at the module.
+#[derive(Debug, Error)]
+#[error("USB multiplexer did not switch to {expected}: detected module {serial} of {detected}")]
+pub struct UsbMuxError {
+    pub expected: NodeId,
+    pub detected: NodeId,
+    pub serial: String,
+}
+
 #[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct NodeInfo {
     pub name: Option<String>,
@@ -74,44 +369,166 @@ pub struct NodeInfo {
 }
 
 pub struct BmcApplication {
+    board_revision: BoardRevision,
     pub(super) pin_controller: PinController,
     pub(super) power_controller: PowerController,
     pub(super) app_db: ApplicationPersistency,
+    pub(super) leds: LedController,
+    power_sensors: PowerSensors,
     node_drivers: NodeDrivers,
+    post_flash_hooks: Vec<String>,
+    images: ImageLibrary,
+    manifest_dir: Option<PathBuf>,
+    /// Time between powering nodes of different priorities.
+    power_on_gap: Duration,
+    /// Whether nodes are only powered on once power-good is asserted, see
+    /// [`Self::wait_for_power_good`].
+    require_power_good: bool,
+    flash_jobs: FlashJobs,
+    /// Serializes long running operations per node, see [`Self::lock_node`].
+    node_locks: [Arc<Mutex<()>>; NODE_COUNT as usize],
+    /// The operation that currently holds each of the `node_locks`.
+    node_busy: std::sync::Mutex<NodeOperations>,
+    selftest: OnceLock<SelfTestReport>,
+    ready: watch::Sender<bool>,
+    /// Cancels the running [`Self::node_in_msd`] of each node.
+    msd_setups: std::sync::Mutex<[Option<CancellationToken>; NODE_COUNT as usize]>,
+    /// Front-panel actions registered with [`Self::with_event_action`].
+    pub(super) event_actions: Vec<(KeyCode, i32, EventAction)>,
+    /// Node states and mask of the changes staged with
+    /// [`Self::stage_activation`].
+    staged_activation: std::sync::Mutex<(u8, u8)>,
+    /// Storage mounted through [`Self::hold_mount`].
+    storage_mounts: std::sync::Mutex<StorageMounts>,
 }
 
 impl BmcApplication {
-    pub async fn new(database_write_timeout: Option<Duration>) -> anyhow::Result<Self> {
-        let model_string = std::fs::read_to_string("/proc/device-tree/model");
-        let is_legacy_dts = matches!(model_string, Ok(model) if model.contains("v2.4"));
-        let pin_controller = PinController::new(is_legacy_dts).context("pin_controller")?;
-        let power_controller = PowerController::new(is_legacy_dts).context("power_controller")?;
-        let app_db = PersistencyBuilder::default()
-            .register_key(ACTIVATED_NODES_KEY, &0u8)
-            .register_key(USB_CONFIG, &UsbConfig::UsbA(NodeId::Node1))
-            .register_key(NODE_INFO_KEY, &NodeInfos::default())
-            .register_key(NODE1_USB_MODE, &false)
-            .register_key(
-                COOLING_DEVICES,
-                &CoolingMap::with_capacity(COOLING_CAPACITY),
-            )
-            .write_timeout(database_write_timeout)
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let board_revision = BoardRevision::detect();
+        info!("board revision {}", board_revision);
+        let pin_controller = PinController::new(board_revision).context("pin_controller")?;
+        let power_controller = PowerController::new(board_revision).context("power_controller")?;
+        let app_db = persistency_keys()
+            .write_timeout(config.store.write_timeout)
             .build()
             .await?;
 
-        let node_drivers = NodeDrivers::new();
+        let node_drivers = NodeDrivers::new(
+            config.flash.device_timeout,
+            app_db.get(SUPPORTED_DEVICES_KEY).await,
+        );
+        let leds = LedController::new(config.leds.power.clone(), config.leds.status.clone());
+        let power_sensors = PowerSensors::new(config.power_sensors.paths());
 
         let instance = Self {
+            board_revision,
             pin_controller,
             power_controller,
             app_db,
+            leds,
+            power_sensors,
             node_drivers,
+            post_flash_hooks: config.flash.post_flash_hooks.clone(),
+            images: ImageLibrary::new(config.flash.image_dir.clone()),
+            manifest_dir: config.flash.manifest_dir.clone(),
+            power_on_gap: config.power_on_gap,
+            require_power_good: config.require_power_good,
+            flash_jobs: FlashJobs::default(),
+            node_locks: Default::default(),
+            node_busy: Default::default(),
+            selftest: OnceLock::new(),
+            ready: watch::Sender::new(false),
+            msd_setups: Default::default(),
+            event_actions: Vec::new(),
+            staged_activation: Default::default(),
+            storage_mounts: Default::default(),
         };
 
-        instance.initialize().await?;
+        // A partially initialized BMC is still able to serve requests, the
+        // initialization can be retried by the user.
+        if let Err(e) = instance.initialize().await {
+            tracing::error!("{:#}", e);
+        }
+        if config.resync_power_on_start {
+            if let Err(e) = instance.resync_power_state().await {
+                tracing::error!("power state resync: {:#}", e);
+            }
+        }
+
+        let report = instance.selftest(config.input.device.as_deref()).await;
+        info!("self-test: {}", report);
+        let _ = instance.selftest.set(report);
         Ok(instance)
     }
 
+    /// Registers an additional front-panel action, executed when the input
+    /// device reports `value` for `key`. Takes precedence over the default
+    /// action of that key and value, see
+    /// [`run_event_listener`](super::event_application::run_event_listener).
+    pub fn with_event_action<F>(mut self, key: KeyCode, value: i32, action: F) -> Self
+    where
+        F: Fn(Arc<BmcApplication>) + Send + Sync + 'static,
+    {
+        self.event_actions.push((key, value, Arc::new(action)));
+        self
+    }
+
+    /// Revision of the board, detected at startup.
+    pub fn board_revision(&self) -> BoardRevision {
+        self.board_revision
+    }
+
+    /// Results of the self-test that ran at startup.
+    pub fn selftest_report(&self) -> Option<&SelfTestReport> {
+        self.selftest.get()
+    }
+
+    /// Returns true when the BMC finished its initialization sequence (USB mux
+    /// configured, power state restored) and is ready to accept commands.
+    pub fn ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Resolves once [`Self::ready`] returns true.
+    pub async fn wait_ready(&self) {
+        let mut receiver = self.ready.subscribe();
+        // the sender is owned by `self`, hence cannot be dropped while waiting.
+        let _ = receiver.wait_for(|ready| *ready).await;
+    }
+
+    /// Replicates a press of the power button on the front panel. A short press
+    /// powers all nodes on when none is powered, or otherwise powers all nodes
+    /// off. A long press (`long_press` == true) inverts the latter: when only
+    /// part of the nodes is powered, all nodes are powered on instead. Note
+    /// that this changes the activated slots, see [`Self::set_activated_slots`],
+    /// so a previously configured partial layout is replaced by all or none.
+    pub async fn toggle_power(&self, long_press: bool) -> anyhow::Result<()> {
+        self.ensure_not_in_maintenance().await?;
+        self.toggle_power_states(long_press).await
+    }
+
+    /// Toggles the power of the nodes without changing which nodes are part of
+    /// the layout. Powered nodes are powered off and remembered, and when no
+    /// node is powered, the nodes powered off by the last toggle are powered on
+    /// again. Unlike [`Self::toggle_power`], nodes that were not powered are
+    /// never switched on. Fails when there is nothing to power on.
+    pub async fn quick_toggle_power(&self) -> anyhow::Result<()> {
+        self.ensure_not_in_maintenance().await?;
+        let state = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
+        if state != 0 {
+            info!("quick toggle: powering off {:#06b}", state);
+            self.activate_slot(0, state).await?;
+            self.app_db.set(QUICK_TOGGLE_KEY, Some(state)).await;
+            return Ok(());
+        }
+
+        let Some(nodes) = self.app_db.get::<Option<u8>>(QUICK_TOGGLE_KEY).await else {
+            bail!("no nodes to power on, none were powered off by a quick toggle");
+        };
+        info!("quick toggle: powering on {:#06b}", nodes);
+        Ok(self.activate_slot(nodes, nodes).await?)
+    }
+
     /// toggles the power state of the nodes. When `inverse_toggle` == true, and
     /// not all nodes are off nor on, it will turn off all nodes instead of
     /// turning them on.
@@ -131,36 +548,266 @@ impl BmcApplication {
     ///
     /// returns Err(e) on an internal gpio error or when there is an error
     /// writing power LED status.
-    pub async fn toggle_power_states(&self, inverse_toggle: bool) -> anyhow::Result<()> {
-        let node_values = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
+    async fn toggle_power_states(&self, inverse_toggle: bool) -> anyhow::Result<()> {
+        let node_values = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
 
         let mut on = node_values == 0;
-        if inverse_toggle && node_values != 0 && node_values != 0b1111 {
+        if inverse_toggle && node_values != 0 && node_values != ALL_NODES {
             on = !on;
         }
 
-        let node_values = if on { 0b1111 } else { 0b0000 };
-        self.activate_slot(node_values, 0b1111).await
+        let node_values = if on {
+            self.limit_active_nodes(ALL_NODES).await
+        } else {
+            0b0000
+        };
+        Ok(self.set_activated_slots(node_values).await?)
     }
 
-    async fn initialize(&self) -> anyhow::Result<()> {
-        self.initialize_usb_mode().await?;
-        let power_state = self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await?;
-        self.activate_slot(power_state, 0b1111).await?;
-        self.initialize_cooling().await
+    /// Stores `mask` under `name`, replacing any existing profile by that name.
+    pub async fn save_power_profile(&self, name: &str, mask: u8) -> anyhow::Result<()> {
+        ensure!(mask <= ALL_NODES, "invalid node mask {:#06b}", mask);
+        let mut profiles = self.app_db.get::<PowerProfiles>(POWER_PROFILES_KEY).await;
+        profiles.insert(name.to_string(), mask);
+        self.app_db.set(POWER_PROFILES_KEY, profiles).await;
+        Ok(())
     }
 
-    #[instrument(skip(self), fields(alternative_port, config))]
-    async fn initialize_usb_mode(&self) -> anyhow::Result<()> {
+    /// Activates exactly the nodes of the given profile. Like
+    /// [`Self::toggle_power_states`], all slots are written, so nodes that are
+    /// not part of the profile get deactivated.
+    pub async fn apply_power_profile(&self, name: &str) -> anyhow::Result<()> {
+        let profiles = self.app_db.get::<PowerProfiles>(POWER_PROFILES_KEY).await;
+        let mask = *profiles
+            .get(name)
+            .with_context(|| format!("power profile '{name}' does not exist"))?;
+        info!("applying power profile '{}' ({:#06b})", name, mask);
+        Ok(self.set_activated_slots(mask).await?)
+    }
+
+    pub async fn power_profiles(&self) -> PowerProfiles {
+        self.app_db.get::<PowerProfiles>(POWER_PROFILES_KEY).await
+    }
+
+    /// Sets the boot priority of `node`. When several nodes are powered on at
+    /// once, higher priorities go first and each priority waits for the
+    /// configured gap after the previous one. All nodes start at priority 0.
+    pub async fn set_node_priority(&self, node: NodeId, priority: u8) {
+        let mut priorities = self.node_priorities().await;
+        priorities[node as usize] = priority;
+        self.app_db.set(NODE_PRIORITIES_KEY, priorities).await;
+    }
+
+    pub async fn node_priorities(&self) -> NodePriorities {
+        self.app_db.get::<NodePriorities>(NODE_PRIORITIES_KEY).await
+    }
+
+    /// Limits the amount of nodes that can be powered at the same time. Passing
+    /// `None` removes the limit. Nodes that are currently powered stay powered.
+    pub async fn set_max_active_nodes(&self, limit: Option<u8>) -> anyhow::Result<()> {
+        if let Some(limit) = limit {
+            ensure!(
+                (1..=NODE_COUNT).contains(&limit),
+                "limit must be between 1 and {NODE_COUNT}"
+            );
+        }
+        self.app_db.set(MAX_ACTIVE_NODES_KEY, limit).await;
+        Ok(())
+    }
+
+    pub async fn max_active_nodes(&self) -> Option<u8> {
+        self.app_db.get::<Option<u8>>(MAX_ACTIVE_NODES_KEY).await
+    }
+
+    /// Sets how long the power button must be held for a long press, see
+    /// [`Self::toggle_power`]. Takes effect on the next press. Must be between 1 and 10 seconds.
+    pub async fn set_long_press_duration(&self, duration: Duration) -> anyhow::Result<()> {
+        ensure!(
+            LONG_PRESS_RANGE.contains(&duration),
+            "long press must be between {:?} and {:?}",
+            LONG_PRESS_RANGE.start(),
+            LONG_PRESS_RANGE.end()
+        );
+        self.app_db.set(LONG_PRESS_KEY, duration).await;
+        Ok(())
+    }
+
+    pub async fn long_press_duration(&self) -> Duration {
+        self.app_db.get::<Duration>(LONG_PRESS_KEY).await
+    }
+
+    pub async fn set_power_off_on_exit(&self, enabled: bool) {
+        self.app_db.set(POWER_OFF_ON_EXIT_KEY, enabled).await;
+    }
+
+    pub async fn power_off_on_exit(&self) -> bool {
+        self.app_db.get::<bool>(POWER_OFF_ON_EXIT_KEY).await
+    }
+
+    /// Bypasses the power-good check when powering on nodes, for supplies
+    /// that never assert power-good. A fixed delay is used instead.
+    pub async fn set_ignore_power_good(&self, ignore: bool) {
+        self.app_db.set(IGNORE_POWER_GOOD_KEY, ignore).await;
+    }
+
+    pub async fn ignore_power_good(&self) -> bool {
+        self.app_db.get::<bool>(IGNORE_POWER_GOOD_KEY).await
+    }
+
+    /// In maintenance mode, all requests that change the power or USB state
+    /// of the nodes are refused with [`BmcError::MaintenanceMode`]. Entering
+    /// maintenance leaves the current state as it is. The persisted state is
+    /// still restored when the BMC starts.
+    pub async fn set_maintenance(&self, on: bool) {
+        tracing::info!("maintenance mode {}", if on { "on" } else { "off" });
+        self.app_db.set(MAINTENANCE_KEY, on).await;
+    }
+
+    pub async fn maintenance(&self) -> bool {
+        self.app_db.get::<bool>(MAINTENANCE_KEY).await
+    }
+
+    pub async fn ensure_not_in_maintenance(&self) -> Result<(), BmcError> {
+        if self.maintenance().await {
+            return Err(BmcError::MaintenanceMode);
+        }
+        Ok(())
+    }
+
+    /// Called when the daemon exits. Powers off all nodes if
+    /// [`Self::set_power_off_on_exit`] is enabled and the BMC is not in
+    /// maintenance mode. The activated slots are left untouched, so the nodes
+    /// get powered again on the next start.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        if !self.power_off_on_exit().await || self.maintenance().await {
+            return Ok(());
+        }
+
+        tracing::info!("powering off all nodes");
+        self.power_controller.set_power_node(0, ALL_NODES).await?;
+        self.leds.set_led(Led::Power, false).await;
+        Ok(())
+    }
+
+    /// Powers off all nodes and restores all persisted state to its defaults:
+    /// no activated nodes, USB in host mode on node 1, no profiles or limits.
+    /// Also clears maintenance mode, the staged activation and releases the
+    /// storage held by [`Self::hold_mount`]. Fails while another operation on
+    /// a node, e.g. a flash, is in progress.
+    pub async fn factory_reset(&self) -> Result<(), BmcError> {
+        let blocker = factory_reset_blocker(
+            *lock_busy(&self.node_busy),
+            &self.storage_mounts.lock().expect("mounts lock poisoned"),
+        );
+        if let Some((node, operation)) = blocker {
+            return Err(NodeBusyError {
+                node,
+                operation: Some(operation),
+            }
+            .into());
+        }
+
+        tracing::warn!("FACTORY RESET: powering off all nodes and clearing all settings");
+        for device in take_transient_state(&self.staged_activation, &self.storage_mounts) {
+            device.release().await;
+        }
+        self.apply_slots(0, ALL_NODES).await?;
+        self.app_db.factory_reset().await;
+        self.node_drivers.clear_supported_devices();
+        self.initialize().await?;
+        tracing::warn!("FACTORY RESET: done, all settings are back to their defaults");
+        Ok(())
+    }
+
+    /// Reduces `node_states` to its first nodes that fit within the
+    /// configured limit, see [`Self::set_max_active_nodes`].
+    async fn limit_active_nodes(&self, node_states: u8) -> u8 {
+        let Some(limit) = self.max_active_nodes().await else {
+            return node_states;
+        };
+
+        let limited = limit_nodes(node_states, NODE_COUNT.into(), limit.into());
+
+        if limited != node_states {
+            info!(
+                "max active nodes is {}, powering {:#06b} instead of {:#06b}",
+                limit, limited, node_states
+            );
+        }
+        limited
+    }
+
+    /// Restores the persisted state onto the hardware. Every step is retried
+    /// a few times and the remaining steps are still attempted if one of them
+    /// fails. The returned error lists all steps that failed. On success, the
+    /// BMC is marked [ready](Self::ready). Can be called repeatedly.
+    pub async fn initialize(&self) -> anyhow::Result<()> {
+        let mut failed = Vec::new();
+        self.initialize_step("node1 USB route", &mut failed, || {
+            self.initialize_node1_usb_route()
+        })
+        .await;
+        self.initialize_step("USB mode", &mut failed, || self.initialize_usb_mode())
+            .await;
+        self.initialize_step("power state", &mut failed, || self.initialize_power_state())
+            .await;
+        self.initialize_step("cooling", &mut failed, || self.initialize_cooling())
+            .await;
+
+        if !failed.is_empty() {
+            anyhow::bail!("initialization failed: {}", failed.join("; "));
+        }
+
+        self.ready.send_replace(true);
+        Ok(())
+    }
+
+    async fn initialize_step<F, Fut>(&self, name: &str, failed: &mut Vec<String>, step: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        for attempt in 1..=INITIALIZE_ATTEMPTS {
+            match step().await {
+                Ok(()) => return,
+                Err(e) if attempt == INITIALIZE_ATTEMPTS => {
+                    tracing::error!("initializing {} failed: {:#}", name, e);
+                    failed.push(format!("{name}: {e:#}"));
+                }
+                Err(e) => {
+                    tracing::warn!("initializing {} (attempt {}): {:#}", name, attempt, e);
+                    sleep(INITIALIZE_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    #[instrument(skip(self), fields(alternative_port))]
+    async fn initialize_node1_usb_route(&self) -> anyhow::Result<()> {
         if self.pin_controller.usb_bus_type() == UsbArchitecture::UsbHub {
             let alternative_port = self.app_db.get::<bool>(NODE1_USB_MODE).await;
             self.pin_controller.set_node1_usb_route(alternative_port)?;
             tracing::Span::current().record("alternative_port", alternative_port);
         }
+        Ok(())
+    }
 
+    #[instrument(skip(self), fields(config))]
+    async fn initialize_usb_mode(&self) -> anyhow::Result<()> {
         let config = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
         tracing::Span::current().record("config", format!("{:?}", config));
-        self.configure_usb(config).await.context("USB configure")
+        self.configure_usb_internal(config)
+            .await
+            .context("USB configure")
+    }
+
+    async fn initialize_power_state(&self) -> anyhow::Result<()> {
+        let power_state = self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await?;
+        let power_state = valid_node_states(power_state);
+        let power_state = self.limit_active_nodes(power_state).await;
+        // restoring the persisted state is not a change, hence also done in
+        // maintenance mode.
+        Ok(self.apply_slots(power_state, ALL_NODES).await?)
     }
 
     async fn initialize_cooling(&self) -> anyhow::Result<()> {
@@ -190,7 +837,7 @@ impl BmcApplication {
         }
 
         // cleanup storage
-        let map: CoolingMap = HashMap::from_iter(set_devices.into_iter());
+        let map: CoolingMap = HashMap::from_iter(set_devices);
         info!("loaded cooling devices: {:?}", map);
         self.app_db.set(COOLING_DEVICES, map).await;
 
@@ -210,35 +857,254 @@ impl BmcApplication {
         Ok(state & node.to_bitfield() != 0)
     }
 
+    /// Sets the activated state of all slots at once: the nodes in `mask` are
+    /// activated, all others deactivated. The state is persisted and the power
+    /// of the nodes is updated in a single pass, so there are no intermediate
+    /// states in which only part of the slots changed.
+    pub async fn set_activated_slots(&self, mask: u8) -> Result<(), BmcError> {
+        if mask > ALL_NODES {
+            return Err(BmcError::InvalidNodeMask(mask));
+        }
+        self.activate_slot(mask, ALL_NODES).await
+    }
+
+    /// Applies the desired state of several nodes in one go. A node is only
+    /// powered when it is requested to be both activated and powered; power
+    /// requests for nodes that are not activated are dropped and returned.
+    /// Nodes that are not part of `spec` keep their current state.
+    pub async fn configure_nodes(
+        &self,
+        spec: &[(NodeId, bool, bool)],
+    ) -> Result<Vec<NodeId>, BmcError> {
+        let (node_states, mask, suppressed) = resolve_node_config(spec);
+        if mask != 0 {
+            self.activate_slot(node_states, mask).await?;
+        }
+        Ok(suppressed)
+    }
+
     /// This function is used to active a given node. Call this function if a
     /// module is inserted at that slot. Failing to call this method means that
     /// this slot is not considered for power up and power down commands.
-    pub async fn activate_slot(&self, node_states: u8, mask: u8) -> anyhow::Result<()> {
+    #[instrument(
+        skip_all,
+        fields(node_states = %format_args!("{:#06b}", node_states), mask = %format_args!("{:#06b}", mask))
+    )]
+    pub async fn activate_slot(&self, node_states: u8, mask: u8) -> Result<(), BmcError> {
+        self.ensure_not_in_maintenance().await?;
+        self.apply_slots(node_states, mask).await
+    }
+
+    async fn apply_slots(&self, node_states: u8, mask: u8) -> Result<(), BmcError> {
         trace!(
             "activate slot. node_states={:#06b}, mask={:#06b}",
             node_states,
             mask
         );
-        ensure!(mask != 0);
+        if mask == 0 {
+            return Err(BmcError::InvalidNodeMask(mask));
+        }
 
-        let state = self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await;
-        let new_state = (state & !mask) | (node_states & mask);
+        let state = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
+        let new_state = self.checked_next_state(state, node_states, mask).await?;
 
-        self.update_power_on_times(state, node_states, mask).await;
+        if node_states & mask & !state != 0 {
+            self.wait_for_power_good().await?;
+        }
+
+        // also update the actual power state accordingly. When this fails
+        // partway, only the nodes that actually switched are persisted, so the
+        // stored state keeps matching the hardware.
+        let stages = power_stages(node_states, mask, &self.node_priorities().await);
+        let result = self.apply_power_stages(node_states, &stages).await;
+        let (new_state, applied) = match &result {
+            Ok(()) => (new_state, mask),
+            Err(e) => (next_state(state, node_states, e.applied), e.applied),
+        };
+
+        self.update_power_on_times(state, node_states, applied)
+            .await;
 
         self.app_db.set::<u8>(ACTIVATED_NODES_KEY, new_state).await;
         debug!("node activated bits updated:{:#06b}.", new_state);
 
         let led = new_state != 0;
-        self.power_controller
-            .power_led(led)
-            .await
-            .unwrap_or_else(|e| tracing::warn!("power LED error: {:#}", e));
+        self.leds.set_led(Led::Power, led).await;
 
-        // also update the actual power state accordingly
-        self.power_controller
-            .set_power_node(node_states, mask)
+        result.map_err(|e| BmcError::Gpio(e.into()))
+    }
+
+    /// Powers the nodes stage by stage, waiting for the power-on gap in
+    /// between. On failure, the error reports the nodes of all stages.
+    async fn apply_power_stages(
+        &self,
+        node_states: u8,
+        stages: &[u8],
+    ) -> Result<(), PartialPowerError> {
+        let mut applied = 0u8;
+        for (idx, stage) in stages.iter().enumerate() {
+            if idx > 0 {
+                debug!(
+                    "powering next stage {:#06b} in {:?}",
+                    stage, self.power_on_gap
+                );
+                sleep(self.power_on_gap).await;
+            }
+            self.power_controller
+                .set_power_node(node_states, *stage)
+                .await
+                .map_err(|e| PartialPowerError {
+                    applied: applied | e.applied,
+                    ..e
+                })?;
+            applied |= stage;
+        }
+        Ok(())
+    }
+
+    /// Updates the persisted power state to the levels of the enable pins,
+    /// e.g. after the pins were changed out-of-band, or when a power change
+    /// failed partway. The power of the nodes is not changed, every node that
+    /// differed is logged. Returns the state read from the hardware.
+    pub async fn resync_power_state(&self) -> anyhow::Result<u8> {
+        let actual = self.power_controller.power_state()?;
+        let cached = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
+        let drift = actual ^ cached;
+        if drift == 0 {
+            debug!("power state {:#06b} matches the hardware", actual);
+            return Ok(actual);
+        }
+
+        for (idx, state) in bit_iterator(actual, drift) {
+            tracing::warn!(
+                "power state of node {} was {}, but the node is powered {}",
+                idx + 1,
+                if state == 0 { "on" } else { "off" },
+                if state == 0 { "off" } else { "on" },
+            );
+        }
+        self.update_power_on_times(cached, actual, drift).await;
+        self.app_db.set::<u8>(ACTIVATED_NODES_KEY, actual).await;
+        self.leds.set_led(Led::Power, actual != 0).await;
+        Ok(actual)
+    }
+
+    /// Stages powering `node` on or off, without changing its power. Staged
+    /// changes are applied at once by [`Self::commit_activation`], so that
+    /// reconfiguring many slots does not switch power in between. Staging a
+    /// node again overrides its earlier change.
+    pub fn stage_activation(&self, node: NodeId, on: bool) {
+        let mut staged = self.lock_staged();
+        let bit = node.to_bitfield();
+        staged.0 = if on { staged.0 | bit } else { staged.0 & !bit };
+        staged.1 |= bit;
+    }
+
+    /// The staged node states and their mask, see [`Self::stage_activation`].
+    pub fn staged_activation(&self) -> (u8, u8) {
+        *self.lock_staged()
+    }
+
+    /// Applies the staged changes with a single [`Self::activate_slot`], and
+    /// returns the resulting activated slots. When applying fails, the changes
+    /// stay staged, so that they can be corrected and committed again.
+    pub async fn commit_activation(&self) -> Result<u8, BmcError> {
+        let (node_states, mask) = self.staged_activation();
+        if mask != 0 {
+            self.activate_slot(node_states, mask).await?;
+            self.discard_activation();
+        }
+        Ok(valid_node_states(
+            self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await,
+        ))
+    }
+
+    /// Drops all staged changes.
+    pub fn discard_activation(&self) {
+        *self.lock_staged() = (0, 0);
+    }
+
+    fn lock_staged(&self) -> std::sync::MutexGuard<'_, (u8, u8)> {
+        self.staged_activation
+            .lock()
+            .expect("staged activation lock poisoned")
+    }
+
+    /// Returns the activated slots that [`Self::activate_slot`] would result
+    /// in, without changing anything. Fails in the same way when the
+    /// transition exceeds [`Self::max_active_nodes`].
+    pub async fn preview_activation(&self, node_states: u8, mask: u8) -> Result<u8, BmcError> {
+        let state = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
+        self.checked_next_state(state, node_states, mask).await
+    }
+
+    async fn checked_next_state(
+        &self,
+        state: u8,
+        node_states: u8,
+        mask: u8,
+    ) -> Result<u8, BmcError> {
+        let new_state = next_state(state, node_states, mask);
+        if let Some(limit) = self.max_active_nodes().await {
+            check_node_limit(state, new_state, limit)?;
+        }
+        Ok(new_state)
+    }
+
+    /// Voltage, current and power drawn by `node`. `None` when the node has
+    /// no current sensor, see [`Config::power_sensors`], or it cannot be
+    /// read.
+    pub async fn power_metrics(&self, node: NodeId) -> Option<PowerMetrics> {
+        self.power_sensors.read(node).await.unwrap_or_else(|e| {
+            tracing::warn!("power sensor of {}: {:#}", node, e);
+            None
+        })
+    }
+
+    /// Whether the power supply currently reports power-good, i.e. whether the
+    /// 12V rail is up. The BMC does not switch the rail itself, so this is read
+    /// from the supply rather than derived from the powered nodes. `None` on
+    /// boards without a power-good signal.
+    pub fn supply_power_good(&self) -> anyhow::Result<Option<bool>> {
+        Ok(self.pin_controller.power_good()?)
+    }
+
+    /// Polls the power-good signal until it is asserted. When the power supply
+    /// does not report power-good within [`POWER_GOOD_TIMEOUT`], this fails if
+    /// power-good is required, and only warns otherwise. Boards without a
+    /// power-good signal pass immediately. When the check is ignored, waits
+    /// [`POWER_SETTLE_DELAY`] instead.
+    async fn wait_for_power_good(&self) -> Result<(), BmcError> {
+        if self.ignore_power_good().await {
+            tracing::warn!(
+                "power-good verification overridden, waiting {:?}",
+                POWER_SETTLE_DELAY
+            );
+            sleep(POWER_SETTLE_DELAY).await;
+            return Ok(());
+        }
+
+        let poll = async {
+            loop {
+                match self.pin_controller.power_good() {
+                    Ok(Some(false)) => sleep(POWER_GOOD_POLL_INTERVAL).await,
+                    Ok(Some(true) | None) => return Ok(()),
+                    Err(e) => return Err(BmcError::Gpio(e.into())),
+                }
+            }
+        };
+
+        let result = tokio::time::timeout(POWER_GOOD_TIMEOUT, poll)
             .await
+            .map_err(|_| BmcError::PowerGoodTimeout(POWER_GOOD_TIMEOUT))
+            .and_then(|result| result);
+        match result {
+            Err(e) if !self.require_power_good => {
+                tracing::warn!("{}, powering on anyway", e);
+                Ok(())
+            }
+            result => result,
+        }
     }
 
     #[instrument(skip(self))]
@@ -248,9 +1114,10 @@ impl BmcApplication {
             .try_get::<NodeInfos>(NODE_INFO_KEY)
             .await
             .unwrap_or_default();
+        let mut on_times = self.app_db.get::<NodeOnTimes>(NODE_ON_TIME_KEY).await;
 
         for (idx, new_state) in bit_iterator(node_states, mask) {
-            let current_state = activated_nodes & (1 << idx);
+            let current_state = (activated_nodes >> idx) & 1;
             let current_time = get_timestamp_unix();
             let node_info = &mut node_infos[idx];
 
@@ -258,6 +1125,7 @@ impl BmcApplication {
                 if new_state == 1 {
                     node_info.power_on_time = current_time;
                 } else {
+                    on_times[idx] += session_time(node_info.power_on_time, current_time);
                     node_info.power_on_time = None;
                 }
             }
@@ -266,6 +1134,51 @@ impl BmcApplication {
         self.app_db
             .set::<NodeInfos>(NODE_INFO_KEY, node_infos)
             .await;
+        self.app_db
+            .set::<NodeOnTimes>(NODE_ON_TIME_KEY, on_times)
+            .await;
+    }
+
+    /// Time since `node` was powered on, or `None` if it is powered off.
+    pub async fn node_uptime(&self, node: NodeId) -> Option<Duration> {
+        let node_infos = self.app_db.get::<NodeInfos>(NODE_INFO_KEY).await;
+        let power_on_time = node_infos[node as usize].power_on_time?;
+        Some(Duration::from_secs(session_time(
+            Some(power_on_time),
+            get_timestamp_unix(),
+        )))
+    }
+
+    /// Accumulated time `node` was powered, including the time since it was
+    /// last powered on. Survives restarts of the daemon.
+    pub async fn node_total_on_time(&self, node: NodeId) -> Duration {
+        let on_times = self.app_db.get::<NodeOnTimes>(NODE_ON_TIME_KEY).await;
+        let uptime = self.node_uptime(node).await.unwrap_or_default();
+        Duration::from_secs(on_times[node as usize]) + uptime
+    }
+
+    /// Reports which slots contain a module, from the USB devices currently
+    /// on the bus of the BMC and the modules recorded during earlier flashes.
+    /// Does not change the power or USB state of any node.
+    pub async fn detect_present_nodes(
+        &self,
+    ) -> anyhow::Result<[NodePresence; NODE_COUNT as usize]> {
+        let devices = self.node_drivers.list_usb_devices()?;
+        let serials = self.app_db.get::<NodeSerials>(NODE_SERIALS_KEY).await;
+        let modules = self.app_db.get::<NodeModules>(NODE_MODULES_KEY).await;
+        let (node, mode, route) = self.app_db.get::<UsbConfig>(USB_CONFIG).await.parts();
+        let routed = (mode == UsbMode::Device && route == UsbRoute::Bmc).then_some(node);
+        Ok(resolve_presence(&devices, &serials, &modules, routed))
+    }
+
+    /// The USB mode of each node, see [`UsbConfig::node_mode`].
+    pub async fn get_node_usb_modes(&self) -> [Option<UsbMode>; NODE_COUNT as usize] {
+        let config = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
+        std::array::from_fn(|idx| {
+            NodeId::try_from(idx)
+                .ok()
+                .and_then(|node| config.node_mode(node))
+        })
     }
 
     pub async fn set_node1_usb_route(&self, alternative_port: bool) -> anyhow::Result<()> {
@@ -279,20 +1192,38 @@ impl BmcApplication {
         self.app_db.get::<bool>(NODE1_USB_MODE).await
     }
 
+    #[instrument(skip(self))]
     pub async fn configure_usb(&self, config: UsbConfig) -> anyhow::Result<()> {
+        self.ensure_not_in_maintenance().await?;
         self.configure_usb_internal(config).await?;
         self.app_db.set(USB_CONFIG, config).await;
         Ok(())
     }
 
+    /// Switches `node` to `mode`, keeping the persisted route of the USB bus.
+    /// Unlike [`Self::configure_usb`], the route is not reprogrammed, for
+    /// callers that manage the routing separately.
+    #[instrument(skip(self))]
+    pub async fn set_node_usb_mode(&self, node: NodeId, mode: UsbMode) -> anyhow::Result<()> {
+        self.ensure_not_in_maintenance().await?;
+        let (_, _, route) = self.app_db.get::<UsbConfig>(USB_CONFIG).await.parts();
+        let config = UsbConfig::from_parts(node, mode, route);
+        check_usb_config(&self.pin_controller.usb_bus_type(), config)?;
+        tracing::info!(
+            "changing usb mode of {} to {:?}, route {:?}",
+            node,
+            mode,
+            route
+        );
+        self.pin_controller.select_usb(node, mode)?;
+        self.app_db.set(USB_CONFIG, config).await;
+        Ok(())
+    }
+
     async fn configure_usb_internal(&self, config: UsbConfig) -> anyhow::Result<()> {
         tracing::info!("changing usb config to {:?}", config);
-        let (mode, dest, route) = match config {
-            UsbConfig::UsbA(device) => (UsbMode::Device, device, UsbRoute::AlternativePort),
-            UsbConfig::Bmc(device) => (UsbMode::Device, device, UsbRoute::Bmc),
-            UsbConfig::Flashing(device, route) => (UsbMode::Flash, device, route),
-            UsbConfig::Node(host, route) => (UsbMode::Host, host, route),
-        };
+        check_usb_config(&self.pin_controller.usb_bus_type(), config)?;
+        let (dest, mode, route) = config.parts();
 
         if mode != UsbMode::Flash {
             if let Err(e) = remove_msd_function_from_usb_gadget().await {
@@ -306,7 +1237,9 @@ impl BmcApplication {
         Ok(())
     }
 
+    #[instrument(skip(self))]
     pub async fn usb_boot(&self, node: NodeId, on: bool) -> anyhow::Result<()> {
+        self.ensure_not_in_maintenance().await?;
         let node_bits = node.to_bitfield();
         let (state, mask) = if on {
             (node_bits, node_bits)
@@ -316,6 +1249,24 @@ impl BmcApplication {
         Ok(self.pin_controller.set_usb_boot(state, mask)?)
     }
 
+    /// Returns the node that has its usb boot pin asserted, if any.
+    pub fn get_usb_boot(&self) -> anyhow::Result<Option<NodeId>> {
+        let state = self.pin_controller.usb_boot_state()?;
+        if state.count_ones() > 1 {
+            tracing::warn!("usb boot asserted on multiple nodes: {:#06b}", state);
+        }
+        Ok(NodeId::all().find(|node| state & node.to_bitfield() != 0))
+    }
+
+    /// Asserts the usb boot pin of `node` and releases it on all other nodes.
+    /// The USB routing is left untouched. The node boots into rpiboot on its
+    /// next power cycle, until the pin is cleared with [`Self::clear_usb_boot`].
+    pub fn set_usb_boot(&self, node: NodeId) -> anyhow::Result<()> {
+        self.pin_controller
+            .set_usb_boot(node.to_bitfield(), ALL_NODES)
+            .context("error setting usbboot")
+    }
+
     pub async fn rtl_reset(&self) -> anyhow::Result<()> {
         tokio::spawn(async {
             sleep(Duration::from_secs(1)).await;
@@ -334,16 +1285,209 @@ impl BmcApplication {
         Ok(())
     }
 
-    pub async fn reset_node(&self, node: NodeId) -> anyhow::Result<()> {
-        self.power_controller.reset_node(node).await
+    /// Claims `node` for an operation that must not interleave with other
+    /// operations on the same node, such as flashing or resetting. Operations
+    /// on different nodes do not block each other. When `wait` is false, a
+    /// [`NodeBusyError`] is returned instead of waiting for the node.
+    pub async fn lock_node(
+        &self,
+        node: NodeId,
+        operation: NodeOperation,
+        wait: bool,
+    ) -> Result<NodeGuard<'_>, NodeBusyError> {
+        let lock = &self.node_locks[node as usize];
+        let lock = if wait {
+            lock.lock().await
+        } else {
+            lock.try_lock().map_err(|_| NodeBusyError {
+                node,
+                operation: self.node_busy_with(node),
+            })?
+        };
+
+        lock_busy(&self.node_busy)[node as usize] = Some(operation);
+        Ok(NodeGuard {
+            _lock: lock,
+            busy: &self.node_busy,
+            node,
+        })
     }
 
-    pub async fn node_in_msd(&self, node: NodeId) -> anyhow::Result<PathBuf> {
-        // stop_usb_gadget_if_running().await?;
+    /// Like [`Self::lock_node`], for a claim that outlives the borrow of the
+    /// application.
+    async fn lock_node_owned(
+        self: &Arc<Self>,
+        node: NodeId,
+        operation: NodeOperation,
+        wait: bool,
+    ) -> Result<OwnedNodeGuard, NodeBusyError> {
+        let lock = self.node_locks[node as usize].clone();
+        let lock = if wait {
+            lock.lock_owned().await
+        } else {
+            lock.try_lock_owned().map_err(|_| NodeBusyError {
+                node,
+                operation: self.node_busy_with(node),
+            })?
+        };
 
-        self.reboot_into_usb(node, UsbConfig::Flashing(node, UsbRoute::Bmc))
-            .await?;
-        let blk_dev = self.node_drivers.load_as_block_device().await?;
+        lock_busy(&self.node_busy)[node as usize] = Some(operation);
+        Ok(OwnedNodeGuard {
+            _lock: lock,
+            bmc: self.clone(),
+            node,
+        })
+    }
+
+    /// Returns the operation that currently claims `node`, if any.
+    pub fn node_busy_with(&self, node: NodeId) -> Option<NodeOperation> {
+        lock_busy(&self.node_busy)[node as usize]
+    }
+
+    /// Collects the power, USB and busy state of all nodes in one go. Unlike
+    /// combining the results of the separate getters, the busy state of all
+    /// nodes is taken under a single lock, and every node is derived from the
+    /// same persisted state.
+    pub async fn status_snapshot(&self) -> BmcStatus {
+        let activated = valid_node_states(self.app_db.get::<u8>(ACTIVATED_NODES_KEY).await);
+        let usb_config = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
+        let maintenance = self.maintenance().await;
+        let power_good = self.supply_power_good().unwrap_or_else(|e| {
+            tracing::warn!("reading power-good: {:#}", e);
+            None
+        });
+        let busy = *lock_busy(&self.node_busy);
+        let mut power = [None; NODE_COUNT as usize];
+        for node in NodeId::all() {
+            power[node as usize] = self.power_metrics(node).await;
+        }
+
+        BmcStatus {
+            activated,
+            usb_config,
+            maintenance,
+            power_good,
+            nodes: std::array::from_fn(|idx| NodeStatus {
+                powered: activated & (1 << idx) != 0,
+                usb_mode: NodeId::try_from(idx)
+                    .ok()
+                    .and_then(|node| usb_config.node_mode(node)),
+                power: power[idx],
+                operation: busy[idx],
+            }),
+        }
+    }
+
+    /// Helps to find `node` in a rack: blinks the status LED once for node 1,
+    /// twice for node 2 and so on, for `duration`. The power state of the node
+    /// is left as it is. Interrupts the activity blinking of a running flash.
+    pub fn locate_node(&self, node: NodeId, duration: Duration) {
+        tracing::info!("locating {node} for {duration:?}");
+        self.leds.blink_code(Led::Status, node as u8 + 1, duration);
+    }
+
+    pub fn is_flashing(&self, node: NodeId) -> bool {
+        self.node_busy_with(node) == Some(NodeOperation::Flash)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn reset_node(&self, node: NodeId, wait: bool) -> Result<(), BmcError> {
+        self.ensure_not_in_maintenance().await?;
+        if !self.board_revision.has_node_reset() {
+            return Err(BmcError::NotSupported("node reset", self.board_revision));
+        }
+        let _guard = self.lock_node(node, NodeOperation::Reset, wait).await?;
+        self.power_controller
+            .reset_node(node)
+            .await
+            .map_err(BmcError::Gpio)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn node_in_msd(&self, node: NodeId, wait: bool) -> Result<MsdNode, BmcError> {
+        let _guard = self.lock_node(node, NodeOperation::Msd, wait).await?;
+        self.expose_as_msd(node).await
+    }
+
+    /// Exposes the eMMC of `node` as USB mass storage device on the BMC, e.g.
+    /// to inspect or edit the files of the node. Unlike [`Self::node_in_msd`],
+    /// the node is released again when the returned [`MountedDevice`] is
+    /// dropped. A failed setup is rolled back right away.
+    #[instrument(skip(self))]
+    pub async fn mount_node_storage(
+        self: &Arc<Self>,
+        node: NodeId,
+        wait: bool,
+    ) -> Result<MountedDevice, BmcError> {
+        let guard = self.lock_node_owned(node, NodeOperation::Msd, wait).await?;
+        let powered_before = self.get_node_power(node).await.unwrap_or_default();
+        match self.expose_as_msd(node).await {
+            Ok(msd) => Ok(MountedDevice {
+                path: msd.path,
+                module: msd.module,
+                node,
+                powered_before,
+                guard: Some(guard),
+            }),
+            Err(e) => {
+                self.release_storage(node, powered_before).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Keeps `device` mounted until [`Self::release_mount`] is called for its
+    /// node, for callers that cannot hold on to it, such as the API.
+    pub fn hold_mount(&self, device: MountedDevice) {
+        let node = device.node();
+        self.storage_mounts.lock().expect("mounts lock poisoned")[node as usize] = Some(device);
+    }
+
+    /// Releases the mount of `node` held by [`Self::hold_mount`]. Returns
+    /// false when there is none.
+    pub async fn release_mount(&self, node: NodeId) -> bool {
+        let device =
+            self.storage_mounts.lock().expect("mounts lock poisoned")[node as usize].take();
+        let Some(device) = device else {
+            return false;
+        };
+        device.release().await;
+        true
+    }
+
+    async fn release_storage(&self, node: NodeId, powered_before: bool) {
+        self.restore_after_usb_setup(node).await;
+        if powered_before {
+            if let Err(e) = self
+                .apply_slots(node.to_bitfield(), node.to_bitfield())
+                .await
+            {
+                tracing::error!("{node}: {:#}", e);
+            }
+        }
+        info!("{node}: storage released");
+    }
+
+    async fn expose_as_msd(&self, node: NodeId) -> Result<MsdNode, BmcError> {
+        // stop_usb_gadget_if_running().await?;
+        let cancel = CancellationToken::new();
+        self.msd_setups.lock().expect("msd lock poisoned")[node as usize] = Some(cancel.clone());
+        let setup = async {
+            self.reboot_into_usb(node, UsbConfig::Flashing(node, UsbRoute::Bmc))
+                .await?;
+            let progress = watch::Sender::new(TransferProgress::default());
+            self.node_drivers.wait_for_device(node, &progress).await?;
+            self.record_serial(node).await?;
+            let (blk_dev, module) = self
+                .node_drivers
+                .load_as_block_device(node, self.usb_timings().await, &progress)
+                .await?;
+            self.record_module(node, &module).await;
+            Ok((blk_dev, module))
+        };
+        let result = self.cancellable_setup(node, &cancel, setup).await;
+        self.msd_setups.lock().expect("msd lock poisoned")[node as usize] = None;
+        let (blk_dev, module) = result?;
 
         if let Err(e) = append_msd_config_to_usb_gadget(&blk_dev).await {
             tracing::error!("msd usb-gadget: {:#}", e);
@@ -351,20 +1495,125 @@ impl BmcApplication {
             info!("BMC-OTG: Node mass storage CDC enabled");
         }
 
-        Ok(blk_dev)
+        Ok(MsdNode {
+            path: blk_dev,
+            module,
+        })
+    }
+
+    /// Cancels a running [`Self::node_in_msd`] of `node`. Returns false when
+    /// the node is not being set up as mass storage device.
+    pub fn cancel_msd(&self, node: NodeId) -> bool {
+        self.msd_setups.lock().expect("msd lock poisoned")[node as usize]
+            .as_ref()
+            .map(CancellationToken::cancel)
+            .is_some()
     }
 
+    /// Boots `node` into flashing mode and opens the stream to write to. Is
+    /// aborted when `cancel` fires, see [`Self::cancellable_setup`].
+    #[instrument(skip(self, progress, cancel))]
     pub async fn node_in_flash(
         &self,
         node: NodeId,
         router: UsbRoute,
-    ) -> anyhow::Result<impl 'static + AsyncRead + AsyncWrite + AsyncSeek + Unpin> {
-        self.reboot_into_usb(node, UsbConfig::Flashing(node, router))
+        progress: &watch::Sender<TransferProgress>,
+        device_override: Option<&Path>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<(impl 'static + DataTransport, Option<DetectedModule>)> {
+        let setup = async {
+            self.reboot_into_usb(node, UsbConfig::Flashing(node, router))
+                .await?;
+
+            // Skip detection of the node, the given device is taken as-is.
+            if let Some(path) = device_override {
+                tracing::info!("writing to {} as requested", path.to_string_lossy());
+                let file = OpenOptions::new().read(true).write(true).open(path).await?;
+                return Ok((Box::new(file) as Box<dyn DataTransport>, None));
+            }
+
+            self.node_drivers.wait_for_device(node, progress).await?;
+            self.record_serial(node).await?;
+            let (stream, module) = self
+                .node_drivers
+                .load_as_stream(node, self.usb_timings().await, progress)
+                .await?;
+            self.record_module(node, &module).await;
+            Ok((stream, Some(module)))
+        };
+        Ok(self.cancellable_setup(node, cancel, setup).await?)
+    }
+
+    /// Runs `setup`, which boots `node` into a USB mode, until `cancel` fires.
+    /// The sleeps and the enumeration of the setup take seconds, cancelling
+    /// drops them right away, and leaves the node powered off with the
+    /// persisted USB configuration restored.
+    async fn cancellable_setup<T>(
+        &self,
+        node: NodeId,
+        cancel: &CancellationToken,
+        setup: impl Future<Output = anyhow::Result<T>>,
+    ) -> Result<T, BmcError> {
+        tokio::select! {
+            result = setup => Ok(result?),
+            _ = cancel.cancelled() => {
+                info!("{node}: USB setup cancelled");
+                self.restore_after_usb_setup(node).await;
+                Err(BmcError::Cancelled)
+            }
+        }
+    }
+
+    /// Powers `node` off, clears the usbboot pins and restores the persisted
+    /// USB configuration. Failures are logged, so that each step is tried.
+    async fn restore_after_usb_setup(&self, node: NodeId) {
+        if let Err(e) = self.apply_slots(0, node.to_bitfield()).await {
+            tracing::error!("{node}: {:#}", e);
+        }
+        if let Err(e) = self.clear_usb_boot() {
+            tracing::error!("{:#}", e);
+        }
+        let config = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
+        if let Err(e) = self.configure_usb_internal(config).await {
+            tracing::error!("{:#}", e);
+        }
+    }
+
+    /// Powers `node` on in rpiboot mode, with its USB routed to the BMC, and
+    /// leaves it waiting there. Unlike [`Self::node_in_msd`], nothing is
+    /// loaded onto the module, so that external tools can take over. Use
+    /// [`Self::exit_rpiboot`] to release the node.
+    pub async fn enter_rpiboot(&self, node: NodeId, wait: bool) -> Result<(), BmcError> {
+        let _guard = self.lock_node(node, NodeOperation::Rpiboot, wait).await?;
+        self.reboot_into_usb(node, UsbConfig::Flashing(node, UsbRoute::Bmc))
             .await?;
-        Ok(self.node_drivers.load_as_stream().await?)
+        info!("{node} is waiting in rpiboot mode");
+        Ok(())
+    }
+
+    /// Releases a node from [`Self::enter_rpiboot`]: the node is powered off,
+    /// and the persisted USB configuration restored. Powering the node on
+    /// boots it normally.
+    pub async fn exit_rpiboot(&self, node: NodeId, wait: bool) -> Result<(), BmcError> {
+        self.ensure_not_in_maintenance().await?;
+        let _guard = self.lock_node(node, NodeOperation::Rpiboot, wait).await?;
+        self.restore_after_usb_setup(node).await;
+        Ok(())
     }
 
     async fn reboot_into_usb(&self, node: NodeId, config: UsbConfig) -> anyhow::Result<()> {
+        let persisted = self.app_db.get::<UsbConfig>(USB_CONFIG).await;
+        if let Some(corrected) = correct_stale_flash_config(persisted, node) {
+            // restoring the persisted config after this operation would put
+            // the other node into usbboot mode again.
+            tracing::warn!(
+                "stale USB config {:?} routes another node to the BMC, resetting it to {:?}",
+                persisted,
+                corrected
+            );
+            self.app_db.set(USB_CONFIG, corrected).await;
+        }
+
         tracing::info!("Powering off node {:?}...", node);
         self.activate_slot(!node.to_bitfield(), node.to_bitfield())
             .await?;
@@ -374,18 +1623,219 @@ impl BmcApplication {
         self.activate_slot(node.to_bitfield(), node.to_bitfield())
             .await?;
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::time::sleep(self.power_on_settle(node).await).await;
 
         self.clear_usb_boot()
     }
 
+    /// The time given to the module in `node` to enumerate after power on:
+    /// the configured [`UsbTimings::power_on_settle`], or longer when the
+    /// module last detected in this slot is known to need more time.
+    async fn power_on_settle(&self, node: NodeId) -> Duration {
+        let configured = self.usb_timings().await.power_on_settle;
+        let modules = self.app_db.get::<NodeModules>(NODE_MODULES_KEY).await;
+        modules[node as usize]
+            .and_then(|vid_pid| self.node_drivers.power_on_settle(vid_pid))
+            .map_or(configured, |module| module.max(configured))
+    }
+
+    /// The shell commands configured to run after a flash, see
+    /// [`FlashOptions::run_hooks`].
+    pub fn post_flash_hooks(&self) -> &[String] {
+        &self.post_flash_hooks
+    }
+
+    /// Returns the path of the manifest file `name` in the configured manifest
+    /// directory.
+    pub fn manifest_path(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let Some(dir) = &self.manifest_dir else {
+            bail!("no manifest directory configured");
+        };
+        utils::file_in_dir(dir, name)
+    }
+
+    /// The images kept on the BMC.
+    pub fn images(&self) -> &ImageLibrary {
+        &self.images
+    }
+
+    async fn record_module(&self, node: NodeId, module: &DetectedModule) {
+        let mut modules = self.app_db.get::<NodeModules>(NODE_MODULES_KEY).await;
+        let vid_pid = Some((module.vid, module.pid));
+        if modules[node as usize] != vid_pid {
+            modules[node as usize] = vid_pid;
+            self.app_db.set(NODE_MODULES_KEY, modules).await;
+        }
+    }
+
+    /// Flashes `node` in the background. Use the returned id to poll the
+    /// progress with [`Self::flash_status`], or pass `done` to get notified
+    /// once the flash finished.
+    pub fn start_flash(
+        self: &Arc<Self>,
+        node: NodeId,
+        data_transfer: DataTransfer,
+        options: FlashOptions,
+        do_crc_validation: bool,
+        done: Option<oneshot::Sender<FlashJobState>>,
+    ) -> anyhow::Result<FlashJobId> {
+        let request = InitializeTransfer::new(
+            format!("{node} os install job"),
+            UpgradeCommand::Module(node, self.clone(), options, done),
+            data_transfer,
+            do_crc_validation,
+        )
+        .try_into()?;
+        Ok(self.flash_jobs.start(request))
+    }
+
+    /// Like [`Self::start_flash`], but hands out the progress of the job as a
+    /// stream, together with its outcome.
+    pub fn start_flash_stream(
+        self: &Arc<Self>,
+        node: NodeId,
+        data_transfer: DataTransfer,
+        options: FlashOptions,
+        do_crc_validation: bool,
+    ) -> anyhow::Result<FlashJobHandle> {
+        let (done, outcome) = oneshot::channel();
+        let id = self.start_flash(node, data_transfer, options, do_crc_validation, Some(done))?;
+        let progress = self
+            .flash_jobs
+            .progress(id)
+            .expect("flash job was just started");
+        Ok(FlashJobHandle {
+            id,
+            progress,
+            done: outcome,
+        })
+    }
+
+    /// Returns `None` when the job does not exist, or finished longer ago than
+    /// the retention window.
+    pub fn flash_status(&self, id: FlashJobId) -> Option<FlashJobStatus> {
+        self.flash_jobs.status(id)
+    }
+
+    pub fn cancel_flash(&self, id: FlashJobId) -> bool {
+        self.flash_jobs.cancel(id)
+    }
+
+    pub fn pause_flash(&self, id: FlashJobId, paused: bool) -> bool {
+        self.flash_jobs.pause(id, paused)
+    }
+
+    /// Lists every device the BMC sees on its USB bus, for diagnosing modules
+    /// that do not enumerate as expected.
+    pub fn list_usb_devices(&self) -> anyhow::Result<Vec<UsbDeviceInfo>> {
+        Ok(self.node_drivers.list_usb_devices()?)
+    }
+
+    /// Supports flashing modules with the given USB ids, which expose a block
+    /// device of `disk_vendor`, see [`NodeDrivers::add_supported_device`]. The
+    /// addition is persisted.
+    pub async fn add_supported_device(
+        &self,
+        vid: u16,
+        pid: u16,
+        disk_vendor: String,
+    ) -> anyhow::Result<()> {
+        ensure!(!disk_vendor.is_empty(), "disk vendor must not be empty");
+        ensure!(
+            !self.node_drivers.is_supported((vid, pid)),
+            "{vid:04x}:{pid:04x} is supported already"
+        );
+
+        let devices = self.node_drivers.add_supported_device(SupportedDevice {
+            vid,
+            pid,
+            disk_vendor,
+        });
+        self.app_db.set(SUPPORTED_DEVICES_KEY, devices).await;
+        Ok(())
+    }
+
+    pub fn custom_devices(&self) -> Vec<SupportedDevice> {
+        self.node_drivers.custom_devices()
+    }
+
+    /// Returns the node in which the module with the given USB serial number
+    /// was last detected.
+    pub async fn find_node_by_serial(&self, serial: &str) -> Option<NodeId> {
+        let serials = self.app_db.get::<NodeSerials>(NODE_SERIALS_KEY).await;
+        let idx = serials.iter().position(|s| s.as_deref() == Some(serial))?;
+        NodeId::try_from(idx).ok()
+    }
+
+    /// Forgets the serial number recorded for `node`. Needed after swapping
+    /// modules between slots, which is otherwise reported as a stuck USB
+    /// multiplexer.
+    pub async fn forget_serial(&self, node: NodeId) {
+        let mut serials = self.app_db.get::<NodeSerials>(NODE_SERIALS_KEY).await;
+        serials[node as usize] = None;
+        self.app_db.set(NODE_SERIALS_KEY, serials).await;
+    }
+
+    /// Stores the serial number of the module at `node`, if it reports one.
+    /// Failing to read the serial is not an error for the calling flow, but
+    /// detecting a module that belongs to another slot is, see
+    /// [`UsbMuxError`].
+    async fn record_serial(&self, node: NodeId) -> Result<(), UsbMuxError> {
+        let serial = match self.node_drivers.read_serial(node) {
+            Ok(Some(serial)) => serial,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                tracing::debug!("could not read serial of {}: {:#}", node, e);
+                return Ok(());
+            }
+        };
+
+        let mut serials = self.app_db.get::<NodeSerials>(NODE_SERIALS_KEY).await;
+        if serials[node as usize].as_deref() == Some(serial.as_str()) {
+            return Ok(());
+        }
+        check_mux(&serials, node, &serial)?;
+
+        info!("{} has serial {}", node, serial);
+        // a module that moved to another slot is no longer in its old slot
+        for slot in serials.iter_mut() {
+            if slot.as_deref() == Some(serial.as_str()) {
+                *slot = None;
+            }
+        }
+        serials[node as usize] = Some(serial);
+        self.app_db.set(NODE_SERIALS_KEY, serials).await;
+        Ok(())
+    }
+
+    pub async fn usb_timings(&self) -> UsbTimings {
+        self.app_db.get::<UsbTimings>(USB_TIMINGS_KEY).await
+    }
+
+    pub async fn set_usb_timings(&self, timings: UsbTimings) {
+        self.app_db.set(USB_TIMINGS_KEY, timings).await;
+    }
+
+    /// Captures the level of every GPIO pin the BMC manages, for diagnosing
+    /// nodes that do not power on or enumerate.
+    pub fn gpio_snapshot(&self) -> anyhow::Result<GpioSnapshot> {
+        let mut snapshot = self.pin_controller.dump_state()?;
+        snapshot
+            .pins
+            .extend(self.power_controller.dump_state()?.pins);
+        Ok(snapshot)
+    }
+
     pub fn clear_usb_boot(&self) -> anyhow::Result<()> {
         self.pin_controller
-            .set_usb_boot(0u8, 0b1111)
+            .set_usb_boot(0u8, ALL_NODES)
             .context("error clearing usbboot")
     }
 
-    pub async fn reboot(&self, fel: bool) -> anyhow::Result<()> {
+    /// Reboots the BMC, into FEL mode when `fel` is set. The power state of
+    /// the nodes is restored on startup, see [`ACTIVATED_NODES_KEY`]. Fails
+    /// when the reboot could not be initiated.
+    pub async fn reboot_self(&self, fel: bool) -> anyhow::Result<()> {
         if fel {
             let mut mem = OpenOptions::new().write(true).open("/dev/mem").await?;
             mem.seek(std::io::SeekFrom::Start(0x0709_0108)).await?;
@@ -393,12 +1843,12 @@ impl BmcApplication {
             tracing::warn!("system reboot into FEL");
         }
 
-        self.power_controller
-            .status_led(true)
-            .await
-            .unwrap_or_else(|e| tracing::warn!("status_led: {:#}", e));
+        self.leds.set_led(Led::Status, true).await;
 
-        Command::new("shutdown").args(["-r", "now"]).spawn()?;
+        let status = spawn_blocking(|| Command::new("shutdown").args(["-r", "now"]).status())
+            .await?
+            .context("shutdown")?;
+        ensure!(status.success(), "shutdown failed ({})", status);
         Ok(())
     }
 
@@ -471,3 +1921,464 @@ impl BmcApplication {
         Ok(get_cooling_state().await)
     }
 }
+
+/// Rejects a USB configuration that the bus of the board cannot carry, so
+/// that it is refused before any pin is changed. Both architectures have an
+/// alternative port: the USB-A port on mux boards, the 4XNODE USB_OTG port
+/// on hub boards.
+fn check_usb_config(architecture: &UsbArchitecture, config: UsbConfig) -> anyhow::Result<()> {
+    let (node, mode, route) = config.parts();
+    let invalid = match (architecture, mode, route) {
+        // The mux connects a single node straight to the BMC or the USB-A
+        // port, either end of that bus can be host.
+        (
+            UsbArchitecture::UsbMux,
+            UsbMode::Host | UsbMode::Device | UsbMode::Flash,
+            UsbRoute::Bmc | UsbRoute::AlternativePort,
+        ) => None,
+        // The nodes sit on the downstream ports of the hub, whose upstream
+        // port is switched to the BMC or the 4XNODE USB_OTG port. The nodes
+        // can only be devices.
+        (
+            UsbArchitecture::UsbHub,
+            UsbMode::Device | UsbMode::Flash,
+            UsbRoute::Bmc | UsbRoute::AlternativePort,
+        ) => None,
+        (UsbArchitecture::UsbHub, UsbMode::Host, UsbRoute::Bmc) => {
+            Some("the BMC is the host of the USB hub the nodes are connected to")
+        }
+        (UsbArchitecture::UsbHub, UsbMode::Host, UsbRoute::AlternativePort) => {
+            Some("the 4XNODE USB_OTG port connects to the upstream port of the USB hub")
+        }
+    };
+    if let Some(reason) = invalid {
+        bail!("{node} cannot be USB {mode:?} with route {route:?}, {reason}");
+    }
+    Ok(())
+}
+
+/// Returns the config that replaces `persisted` when it holds another node
+/// than `node` in flash mode on the BMC. Such a config is left behind when the
+/// daemon restarted in the middle of an operation. The other node keeps its
+/// USB route, but is no longer booted into USB mode.
+fn correct_stale_flash_config(persisted: UsbConfig, node: NodeId) -> Option<UsbConfig> {
+    match persisted {
+        UsbConfig::Flashing(other, UsbRoute::Bmc) if other != node => Some(UsbConfig::Bmc(other)),
+        _ => None,
+    }
+}
+
+/// Drops the bits of a persisted node state that do not belong to any node.
+/// Such bits can only come from a corrupted store, they are never written.
+fn valid_node_states(state: u8) -> u8 {
+    if state & !ALL_NODES != 0 {
+        tracing::warn!("ignoring invalid bits of stored node state {:#010b}", state);
+    }
+    state & ALL_NODES
+}
+
+/// Checks the transition from `state` to `new_state` against `limit`, see
+/// [`denied_nodes`].
+fn check_node_limit(state: u8, new_state: u8, limit: u8) -> Result<(), NodeLimitError> {
+    let denied: Vec<NodeId> = denied_nodes(state, new_state, NODE_COUNT.into(), limit.into())
+        .into_iter()
+        .filter_map(|n| NodeId::try_from(n as usize).ok())
+        .collect();
+
+    if denied.is_empty() {
+        Ok(())
+    } else {
+        Err(NodeLimitError { limit, denied })
+    }
+}
+
+fn lock_busy(busy: &std::sync::Mutex<NodeOperations>) -> std::sync::MutexGuard<'_, NodeOperations> {
+    busy.lock().expect("node busy lock poisoned")
+}
+
+/// Returns the first node with an operation in progress that prevents a
+/// factory reset. Nodes that are only claimed by held storage do not, the
+/// reset releases them.
+fn factory_reset_blocker(
+    busy: NodeOperations,
+    mounts: &StorageMounts,
+) -> Option<(NodeId, NodeOperation)> {
+    NodeId::all()
+        .zip(busy)
+        .zip(mounts)
+        .find_map(|((node, operation), mount)| Some((node, operation?)).filter(|_| mount.is_none()))
+}
+
+/// Drops the staged activation and takes the held storage, the state of the
+/// nodes that only lives in memory.
+fn take_transient_state(
+    staged: &std::sync::Mutex<(u8, u8)>,
+    mounts: &std::sync::Mutex<StorageMounts>,
+) -> Vec<MountedDevice> {
+    *staged.lock().expect("staged activation lock poisoned") = (0, 0);
+    let mounts = std::mem::take(&mut *mounts.lock().expect("mounts lock poisoned"));
+    mounts.into_iter().flatten().collect()
+}
+
+/// The keys of the persisted state, with their defaults.
+fn persistency_keys() -> PersistencyBuilder {
+    PersistencyBuilder::default()
+        .register_key(ACTIVATED_NODES_KEY, &0u8)
+        .register_key(USB_CONFIG, &UsbConfig::UsbA(NodeId::Node1))
+        .register_key(NODE_INFO_KEY, &NodeInfos::default())
+        .register_key(NODE1_USB_MODE, &false)
+        .register_key(
+            COOLING_DEVICES,
+            &CoolingMap::with_capacity(COOLING_CAPACITY),
+        )
+        .register_key(POWER_PROFILES_KEY, &PowerProfiles::new())
+        .register_key(MAX_ACTIVE_NODES_KEY, &None::<u8>)
+        .register_key(NODE_SERIALS_KEY, &NodeSerials::default())
+        .register_key(NODE_MODULES_KEY, &NodeModules::default())
+        .register_key(NODE_ON_TIME_KEY, &NodeOnTimes::default())
+        .register_key(USB_TIMINGS_KEY, &UsbTimings::default())
+        .register_key(POWER_OFF_ON_EXIT_KEY, &false)
+        .register_key(IGNORE_POWER_GOOD_KEY, &false)
+        .register_key(MAINTENANCE_KEY, &false)
+        .register_key(LONG_PRESS_KEY, &Duration::from_secs(3))
+        .register_key(SUPPORTED_DEVICES_KEY, &Vec::<SupportedDevice>::new())
+        .register_key(QUICK_TOGGLE_KEY, &None::<u8>)
+        .register_key(NODE_PRIORITIES_KEY, &NodePriorities::default())
+}
+
+/// Splits a power change into the masks that are applied one after the other.
+/// Nodes that are powered on are grouped by descending priority, nodes that
+/// are powered off go with the first group.
+fn power_stages(node_states: u8, mask: u8, priorities: &NodePriorities) -> Vec<u8> {
+    let powering = node_states & mask;
+    let mut levels = bit_iterator(powering, powering)
+        .map(|(idx, _)| priorities[idx])
+        .collect::<Vec<_>>();
+    levels.sort_unstable_by(|a, b| b.cmp(a));
+    levels.dedup();
+
+    let mut stages = levels
+        .into_iter()
+        .map(|level| {
+            bit_iterator(powering, powering)
+                .filter(|(idx, _)| priorities[*idx] == level)
+                .fold(0u8, |stage, (idx, _)| stage | 1 << idx)
+        })
+        .collect::<Vec<_>>();
+    match stages.first_mut() {
+        Some(first) => *first |= mask & !node_states,
+        None => stages.push(mask),
+    }
+    stages
+}
+
+/// A `serial` that is recorded for another slot, while `node` has a module of
+/// its own on record, means the USB multiplexer still points to that other
+/// slot. A module that moved into a slot without a recorded serial passes.
+fn check_mux(serials: &NodeSerials, node: NodeId, serial: &str) -> Result<(), UsbMuxError> {
+    if serials[node as usize].is_none() {
+        return Ok(());
+    }
+
+    let other = serials
+        .iter()
+        .position(|s| s.as_deref() == Some(serial))
+        .and_then(|idx| NodeId::try_from(idx).ok());
+    match other {
+        Some(detected) if detected != node => Err(UsbMuxError {
+            expected: node,
+            detected,
+            serial: serial.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// `routed` is the node whose USB is connected to the BMC as a device, if any.
+/// A supported module on the bus belongs to that node, other modules are
+/// matched to a slot by their recorded serial.
+fn resolve_presence(
+    devices: &[UsbDeviceInfo],
+    serials: &NodeSerials,
+    modules: &NodeModules,
+    routed: Option<NodeId>,
+) -> [NodePresence; NODE_COUNT as usize] {
+    std::array::from_fn(|idx| {
+        let serial = serials[idx].as_deref();
+        let enumerated = devices.iter().any(|device| {
+            (serial.is_some() && device.serial.as_deref() == serial)
+                || (routed.is_some_and(|node| node as usize == idx) && device.backend.is_some())
+        });
+
+        if enumerated {
+            NodePresence::Enumerated
+        } else if modules[idx].is_some() || serial.is_some() {
+            NodePresence::Recorded
+        } else {
+            NodePresence::Unknown
+        }
+    })
+}
+
+/// Folds the requested node configurations into a state and mask suitable for
+/// [`BmcApplication::activate_slot`], together with the nodes whose power
+/// request was suppressed because they were not activated.
+fn resolve_node_config(spec: &[(NodeId, bool, bool)]) -> (u8, u8, Vec<NodeId>) {
+    let mut suppressed = Vec::new();
+    let (mut states, mut mask) = (0u8, 0u8);
+    for &(node, activate, power) in spec {
+        if power && !activate {
+            suppressed.push(node);
+        }
+        let bit = node.to_bitfield();
+        if activate && power {
+            states |= bit;
+        } else {
+            states &= !bit;
+        }
+        mask |= bit;
+    }
+    (states, mask, suppressed)
+}
+
+/// Seconds between `power_on_time` and `now`. Clock jumps backwards count as
+/// zero.
+fn session_time(power_on_time: Option<u64>, now: Option<u64>) -> u64 {
+    match (power_on_time, now) {
+        (Some(start), Some(now)) => now.saturating_sub(start),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usb_configs_are_checked_per_architecture() {
+        use UsbArchitecture::{UsbHub, UsbMux};
+        use UsbMode::{Device, Flash, Host};
+        use UsbRoute::{AlternativePort, Bmc};
+
+        let cases = [
+            (UsbMux, Host, Bmc, true),
+            (UsbMux, Host, AlternativePort, true),
+            (UsbMux, Device, Bmc, true),
+            (UsbMux, Device, AlternativePort, true),
+            (UsbMux, Flash, Bmc, true),
+            (UsbMux, Flash, AlternativePort, true),
+            (UsbHub, Host, Bmc, false),
+            (UsbHub, Host, AlternativePort, false),
+            (UsbHub, Device, Bmc, true),
+            (UsbHub, Device, AlternativePort, true),
+            (UsbHub, Flash, Bmc, true),
+            (UsbHub, Flash, AlternativePort, true),
+        ];
+        for (architecture, mode, route, valid) in cases {
+            let config = UsbConfig::from_parts(NodeId::Node2, mode, route);
+            assert_eq!(
+                check_usb_config(&architecture, config).is_ok(),
+                valid,
+                "{architecture} {config:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_state_starts_with_defaults() {
+        let app_db = persistency_keys().build_in_memory();
+        assert_eq!(app_db.get::<u8>(ACTIVATED_NODES_KEY).await, 0);
+        assert_eq!(
+            app_db.get::<UsbConfig>(USB_CONFIG).await,
+            UsbConfig::UsbA(NodeId::Node1)
+        );
+        assert!(app_db
+            .get::<PowerProfiles>(POWER_PROFILES_KEY)
+            .await
+            .is_empty());
+        assert_eq!(
+            app_db.get::<Duration>(LONG_PRESS_KEY).await,
+            Duration::from_secs(3)
+        );
+        assert_eq!(app_db.get::<Option<u8>>(QUICK_TOGGLE_KEY).await, None);
+
+        app_db.set(ACTIVATED_NODES_KEY, 0b0101u8).await;
+        app_db.factory_reset().await;
+        assert_eq!(app_db.get::<u8>(ACTIVATED_NODES_KEY).await, 0);
+    }
+
+    #[test]
+    fn usb_config_round_trips_through_parts() {
+        for config in [
+            UsbConfig::UsbA(NodeId::Node2),
+            UsbConfig::Bmc(NodeId::Node3),
+            UsbConfig::Node(NodeId::Node1, UsbRoute::Bmc),
+            UsbConfig::Flashing(NodeId::Node4, UsbRoute::AlternativePort),
+        ] {
+            let (node, mode, route) = config.parts();
+            assert_eq!(UsbConfig::from_parts(node, mode, route), config);
+        }
+    }
+
+    #[test]
+    fn usb_mode_is_per_node() {
+        let config = UsbConfig::Flashing(NodeId::Node3, UsbRoute::Bmc);
+        assert_eq!(config.node_mode(NodeId::Node3), Some(UsbMode::Flash));
+        assert_eq!(config.node_mode(NodeId::Node1), None);
+
+        let config = UsbConfig::Node(NodeId::Node1, UsbRoute::AlternativePort);
+        assert_eq!(config.node_mode(NodeId::Node1), Some(UsbMode::Host));
+        assert_eq!(config.node_mode(NodeId::Node2), None);
+    }
+
+    #[test]
+    fn session_time_handles_clock_jumps() {
+        assert_eq!(session_time(Some(100), Some(160)), 60);
+        assert_eq!(session_time(Some(160), Some(100)), 0);
+        assert_eq!(session_time(None, Some(100)), 0);
+    }
+
+    #[test]
+    fn mux_check_detects_module_of_other_slot() {
+        let serials: NodeSerials = [Some("a".to_string()), Some("b".to_string()), None, None];
+        let err = check_mux(&serials, NodeId::Node2, "a").unwrap_err();
+        assert_eq!(err.detected, NodeId::Node1);
+
+        // a new module, or a module moved into an unrecorded slot
+        assert!(check_mux(&serials, NodeId::Node2, "c").is_ok());
+        assert!(check_mux(&serials, NodeId::Node3, "a").is_ok());
+    }
+
+    #[test]
+    fn node_config_suppresses_power_of_inactive_nodes() {
+        let (states, mask, suppressed) = resolve_node_config(&[
+            (NodeId::Node1, true, true),
+            (NodeId::Node2, false, true),
+            (NodeId::Node4, true, false),
+        ]);
+        assert_eq!(states, 0b0001);
+        assert_eq!(mask, 0b1011);
+        assert_eq!(suppressed, vec![NodeId::Node2]);
+    }
+
+    #[test]
+    fn stale_flash_config_of_other_node_is_corrected() {
+        assert_eq!(
+            correct_stale_flash_config(
+                UsbConfig::Flashing(NodeId::Node3, UsbRoute::Bmc),
+                NodeId::Node1
+            ),
+            Some(UsbConfig::Bmc(NodeId::Node3))
+        );
+        assert_eq!(
+            correct_stale_flash_config(
+                UsbConfig::Flashing(NodeId::Node1, UsbRoute::Bmc),
+                NodeId::Node1
+            ),
+            None
+        );
+        assert_eq!(
+            correct_stale_flash_config(UsbConfig::Bmc(NodeId::Node3), NodeId::Node1),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_node_state_bits_are_dropped() {
+        assert_eq!(valid_node_states(0xFF), ALL_NODES);
+        assert_eq!(valid_node_states(0b1010_0101), 0b0101);
+        assert_eq!(valid_node_states(0b0011), 0b0011);
+    }
+
+    #[test]
+    fn factory_reset_releases_transient_state() {
+        let mount = |node| MountedDevice {
+            path: PathBuf::from("/dev/sda"),
+            module: DetectedModule {
+                name: "test",
+                vid: 0,
+                pid: 0,
+            },
+            node,
+            powered_before: false,
+            guard: None,
+        };
+        let mounts = std::sync::Mutex::new([None, Some(mount(NodeId::Node2)), None, None]);
+
+        let mut busy = [None, Some(NodeOperation::Msd), None, None];
+        assert!(factory_reset_blocker(busy, &mounts.lock().unwrap()).is_none());
+        busy[3] = Some(NodeOperation::Flash);
+        assert_eq!(
+            factory_reset_blocker(busy, &mounts.lock().unwrap()),
+            Some((NodeId::Node4, NodeOperation::Flash))
+        );
+
+        let staged = std::sync::Mutex::new((0b0001, 0b0011));
+        let released = take_transient_state(&staged, &mounts);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].node(), NodeId::Node2);
+        assert_eq!(*staged.lock().unwrap(), (0, 0));
+        assert!(mounts.lock().unwrap().iter().all(Option::is_none));
+        assert_eq!(
+            factory_reset_blocker(
+                [None, Some(NodeOperation::Msd), None, None],
+                &mounts.lock().unwrap()
+            ),
+            Some((NodeId::Node2, NodeOperation::Msd))
+        );
+    }
+
+    #[test]
+    fn node_limit_prefers_powered_nodes() {
+        assert!(check_node_limit(0b0000, 0b0011, 2).is_ok());
+        assert!(check_node_limit(0b1000, 0b1001, 2).is_ok());
+
+        let err = check_node_limit(0b1000, ALL_NODES, 2).unwrap_err();
+        assert_eq!(err.denied, vec![NodeId::Node2, NodeId::Node3]);
+
+        // powering off is always allowed
+        assert!(check_node_limit(ALL_NODES, 0b0111, 2).is_ok());
+    }
+
+    #[test]
+    fn presence_matches_routed_node_and_serials() {
+        let device = |serial: &str, backend: Option<&str>| UsbDeviceInfo {
+            vid: 0x0a5c,
+            pid: 0x2711,
+            serial: Some(serial.to_string()),
+            path: "1-1".to_string(),
+            backend: backend.map(str::to_string),
+        };
+        let devices = [device("new", Some("rpiboot")), device("b", None)];
+        let serials: NodeSerials = [None, Some("b".to_string()), Some("c".to_string()), None];
+        let modules: NodeModules = [None, None, None, Some((0x2207, 0x350b))];
+
+        assert_eq!(
+            resolve_presence(&devices, &serials, &modules, Some(NodeId::Node1)),
+            [
+                NodePresence::Enumerated,
+                NodePresence::Enumerated,
+                NodePresence::Recorded,
+                NodePresence::Recorded,
+            ]
+        );
+        assert_eq!(
+            resolve_presence(&[], &serials, &Default::default(), None)[0],
+            NodePresence::Unknown
+        );
+    }
+
+    #[test]
+    fn power_on_is_staged_by_priority() {
+        // equal priorities power up together
+        assert_eq!(power_stages(0b1111, 0b1111, &[0; 4]), vec![0b1111]);
+        // powering off needs no stages
+        assert_eq!(power_stages(0b0000, 0b0110, &[3, 2, 1, 0]), vec![0b0110]);
+
+        let priorities = [0, 5, 0, 9];
+        assert_eq!(
+            power_stages(0b1011, 0b1111, &priorities),
+            vec![0b1000 | 0b0100, 0b0010, 0b0001]
+        );
+    }
+}
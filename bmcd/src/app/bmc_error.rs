@@ -0,0 +1,161 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Classified errors of the power and flash operations.
+use super::bmc_application::{NodeBusyError, NodeLimitError, UsbMuxError};
+use super::upgrade_worker::{ChecksumMismatchError, NodeRunningError};
+use crate::hal::board_revision::BoardRevision;
+use crate::usb_boot::UsbBootError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors returned by the public power and flash operations of
+/// [`BmcApplication`](super::bmc_application::BmcApplication). Internally
+/// these flows use anyhow; errors are classified when they leave the public
+/// methods, so that callers can tell the kinds of failures apart.
+#[derive(Debug, Error)]
+pub enum BmcError {
+    #[error("invalid node mask {0:#06b}")]
+    InvalidNodeMask(u8),
+    #[error("the BMC is in maintenance mode")]
+    MaintenanceMode,
+    #[error("{0} is not supported on board revision {1}")]
+    NotSupported(&'static str, BoardRevision),
+    #[error("cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    NodeLimit(#[from] NodeLimitError),
+    #[error(transparent)]
+    NodeBusy(#[from] NodeBusyError),
+    #[error(transparent)]
+    NodeRunning(#[from] NodeRunningError),
+    #[error("power supply did not report power-good within {0:?}")]
+    PowerGoodTimeout(Duration),
+    #[error("GPIO: {0:#}")]
+    Gpio(anyhow::Error),
+    /// The module could not be found or loaded on the USB bus.
+    #[error("{0:#}")]
+    UsbEnumeration(anyhow::Error),
+    #[error(transparent)]
+    UsbMux(#[from] UsbMuxError),
+    /// The data read back from the node does not match the image.
+    #[error("{0:#}")]
+    FlashVerify(anyhow::Error),
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+/// Machine-readable kind of a [`BmcError`]. Unlike the error messages, the
+/// codes are kept stable for API clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidNodeMask,
+    MaintenanceMode,
+    NotSupported,
+    Cancelled,
+    NodeLimit,
+    NodeBusy,
+    NodeRunning,
+    PowerGoodTimeout,
+    Gpio,
+    UsbEnumeration,
+    UsbMux,
+    FlashVerify,
+}
+
+impl BmcError {
+    /// Returns `None` for errors that are not classified.
+    pub fn code(&self) -> Option<ErrorCode> {
+        let code = match self {
+            BmcError::InvalidNodeMask(_) => ErrorCode::InvalidNodeMask,
+            BmcError::MaintenanceMode => ErrorCode::MaintenanceMode,
+            BmcError::NotSupported(..) => ErrorCode::NotSupported,
+            BmcError::Cancelled => ErrorCode::Cancelled,
+            BmcError::NodeLimit(_) => ErrorCode::NodeLimit,
+            BmcError::NodeBusy(_) => ErrorCode::NodeBusy,
+            BmcError::NodeRunning(_) => ErrorCode::NodeRunning,
+            BmcError::PowerGoodTimeout(_) => ErrorCode::PowerGoodTimeout,
+            BmcError::Gpio(_) => ErrorCode::Gpio,
+            BmcError::UsbEnumeration(_) => ErrorCode::UsbEnumeration,
+            BmcError::UsbMux(_) => ErrorCode::UsbMux,
+            BmcError::FlashVerify(_) => ErrorCode::FlashVerify,
+            BmcError::Other(_) => return None,
+        };
+        Some(code)
+    }
+}
+
+impl From<UsbBootError> for BmcError {
+    fn from(error: UsbBootError) -> Self {
+        BmcError::UsbEnumeration(error.into())
+    }
+}
+
+impl From<anyhow::Error> for BmcError {
+    fn from(error: anyhow::Error) -> Self {
+        macro_rules! try_downcast {
+            ($error:ident, $type:ty) => {
+                let $error = match $error.downcast::<$type>() {
+                    Ok(e) => return e.into(),
+                    Err(e) => e,
+                };
+            };
+        }
+
+        try_downcast!(error, BmcError);
+        try_downcast!(error, NodeLimitError);
+        try_downcast!(error, NodeBusyError);
+        try_downcast!(error, NodeRunningError);
+        try_downcast!(error, UsbMuxError);
+
+        if error.is::<UsbBootError>() {
+            BmcError::UsbEnumeration(error)
+        } else if error.is::<ChecksumMismatchError>() {
+            BmcError::FlashVerify(error)
+        } else {
+            BmcError::Other(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::NodeId;
+    use anyhow::Context;
+
+    #[test]
+    fn anyhow_errors_are_classified() {
+        let error = anyhow::Error::from(NodeBusyError {
+            node: NodeId::Node2,
+            operation: None,
+        });
+        assert!(matches!(BmcError::from(error), BmcError::NodeBusy(_)));
+
+        let error = Err::<(), _>(UsbBootError::NotSupported)
+            .context("flash")
+            .unwrap_err();
+        assert!(matches!(BmcError::from(error), BmcError::UsbEnumeration(_)));
+
+        let error = anyhow::Error::from(BmcError::InvalidNodeMask(0));
+        assert!(matches!(
+            BmcError::from(error),
+            BmcError::InvalidNodeMask(0)
+        ));
+
+        let error = anyhow::anyhow!("something else");
+        assert!(matches!(BmcError::from(error), BmcError::Other(_)));
+    }
+}
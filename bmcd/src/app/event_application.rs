@@ -12,40 +12,153 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use super::bmc_application::BmcApplication;
-use crate::utils::EventListener;
-use anyhow::Context;
+use crate::utils::{find_input_device, EventListener};
 use evdev::KeyCode;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 
-pub fn run_event_listener(instance: Arc<BmcApplication>) -> anyhow::Result<()> {
-    EventListener::new(
-        (instance, Option::<oneshot::Sender<()>>::None),
-        "/dev/input/event0",
+/// Default input device of the front-panel buttons.
+pub const INPUT_DEVICE: &str = "/dev/input/event0";
+/// Handler of a front-panel action, see [`BmcApplication::with_event_action`].
+pub type EventAction = Arc<dyn Fn(Arc<BmcApplication>) + Send + Sync>;
+
+/// Presses of the power buttons within this time after the previous toggle
+/// completed are ignored.
+const TOGGLE_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Suppresses power toggles while a toggle is in progress and within
+/// [`TOGGLE_COOLDOWN`] after it completed.
+#[derive(Clone, Default)]
+struct ToggleCooldown(Arc<Mutex<ToggleState>>);
+
+#[derive(Default)]
+struct ToggleState {
+    running: bool,
+    completed: Option<Instant>,
+}
+
+impl ToggleCooldown {
+    /// Returns false if the toggle must be ignored. Otherwise, the caller
+    /// must call [`Self::finish`] when the toggle completed.
+    fn try_start(&self) -> bool {
+        let mut state = self.0.lock().expect("toggle lock poisoned");
+        let cooling_down = state
+            .completed
+            .is_some_and(|completed| completed.elapsed() < TOGGLE_COOLDOWN);
+        if state.running || cooling_down {
+            tracing::debug!("ignoring power toggle");
+            return false;
+        }
+        state.running = true;
+        true
+    }
+
+    fn finish(&self) {
+        let mut state = self.0.lock().expect("toggle lock poisoned");
+        state.running = false;
+        state.completed = Some(Instant::now());
+    }
+}
+
+/// Reboots the BMC, unless it is in maintenance mode.
+pub fn reboot_action(bmc: Arc<BmcApplication>) {
+    tokio::spawn(async move {
+        bmc.ensure_not_in_maintenance().await?;
+        bmc.reboot_self(false).await
+    });
+}
+
+/// Handles the front-panel buttons, extended with the actions registered on
+/// `instance`. `device` selects the input device, see [`find_input_device`],
+/// and defaults to [`INPUT_DEVICE`]. Boards or containers without an input
+/// device run headless: the buttons are not handled, everything else keeps
+/// working. The same applies when the device cannot be opened, or fails for
+/// good later on.
+pub fn run_event_listener(instance: Arc<BmcApplication>, device: Option<&str>) {
+    let spec = device.unwrap_or(INPUT_DEVICE);
+    let device = match find_input_device(spec) {
+        Ok(Some(device)) => device,
+        Ok(None) => {
+            tracing::warn!("{} not found, front-panel buttons disabled", spec);
+            return;
+        }
+        Err(e) => {
+            tracing::warn!("{}: {}, front-panel buttons disabled", spec, e);
+            return;
+        }
+    };
+    tracing::info!("front-panel buttons on {}", device.to_string_lossy());
+
+    let actions = instance.event_actions.clone();
+    let mut listener = EventListener::new(
+        (
+            instance,
+            Option::<oneshot::Sender<()>>::None,
+            ToggleCooldown::default(),
+        ),
+        device,
     )
-    .add_action(KeyCode::KEY_1, 1, |(app, s)| {
+    .add_action(KeyCode::KEY_1, 1, |(app, s, cooldown)| {
+        if !cooldown.try_start() {
+            return;
+        }
         let (sender, receiver) = oneshot::channel();
         *s = Some(sender);
 
         let bmc = app.clone();
+        let cooldown = cooldown.clone();
         tokio::spawn(async move {
-            let long_press = tokio::time::timeout(Duration::from_secs(3), receiver)
+            let long_press = tokio::time::timeout(bmc.long_press_duration().await, receiver)
                 .await
                 .is_err();
-            bmc.toggle_power_states(long_press).await
+            let result = bmc.toggle_power(long_press).await;
+            cooldown.finish();
+            result
         });
     })
-    .add_action(KeyCode::KEY_1, 0, |(_, sender)| {
+    .add_action(KeyCode::KEY_1, 0, |(_, sender, _)| {
         let _ = sender.take().and_then(|s| s.send(()).ok());
     })
-    .add_action(KeyCode::KEY_POWER, 1, move |(app, _)| {
+    .add_action(KeyCode::KEY_POWER, 1, move |(app, _, cooldown)| {
+        if !cooldown.try_start() {
+            return;
+        }
         let bmc = app.clone();
-        tokio::spawn(async move { bmc.toggle_power_states(false).await });
-    })
-    .add_action(KeyCode::KEY_RESTART, 1, |(app, _)| {
-        let bmc = app.clone();
-        tokio::spawn(async move { bmc.reboot(false).await });
-    })
-    .run()
-    .context("event_listener error")
+        let cooldown = cooldown.clone();
+        tokio::spawn(async move {
+            let result = bmc.toggle_power(false).await;
+            cooldown.finish();
+            result
+        });
+    });
+
+    for (key, value, action) in actions {
+        listener = listener.add_action(key, value, move |(app, _, _)| action(app.clone()));
+    }
+
+    if let Err(e) = listener.run() {
+        tracing::warn!("event listener: {}, front-panel buttons disabled", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn toggles_are_suppressed_during_cooldown() {
+        let cooldown = ToggleCooldown::default();
+        assert!(cooldown.try_start());
+        assert!(!cooldown.try_start());
+
+        cooldown.finish();
+        assert!(!cooldown.try_start());
+
+        tokio::time::advance(TOGGLE_COOLDOWN).await;
+        assert!(cooldown.try_start());
+    }
 }
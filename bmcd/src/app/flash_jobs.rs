@@ -0,0 +1,214 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Background execution of flash jobs. Unlike the
+//! [`crate::streaming_data_service::StreamingDataService`], multiple jobs can
+//! be tracked at the same time, and a job is not cancelled when a new one is
+//! started.
+use super::bmc_error::{BmcError, ErrorCode};
+use super::upgrade_worker::FlashSummary;
+use crate::streaming_data_service::transfer_context::TransferProgress;
+use crate::streaming_data_service::TransferRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio_stream::wrappers::WatchStream;
+use tokio_util::sync::CancellationToken;
+
+/// How long the status of a finished job stays available.
+const RETENTION: Duration = Duration::from_secs(300);
+
+pub type FlashJobId = u32;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashJobState {
+    Running,
+    /// Holds the summary of the flashed image, if the job flashed a node.
+    Done(Option<FlashSummary>),
+    Cancelled,
+    Error(FlashJobError),
+}
+
+/// Why a job failed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlashJobError {
+    pub message: String,
+    /// Set when the failure is of a known kind.
+    pub code: Option<ErrorCode>,
+}
+
+impl FlashJobError {
+    pub fn new(message: impl Display) -> Self {
+        Self {
+            message: message.to_string(),
+            code: None,
+        }
+    }
+}
+
+impl FlashJobState {
+    /// Classifies the result of a finished job. `cancelled` tells whether the
+    /// job was cancelled, which makes it fail.
+    pub fn finished(result: &Result<Option<FlashSummary>, BmcError>, cancelled: bool) -> Self {
+        match result {
+            Ok(summary) => FlashJobState::Done(summary.clone()),
+            Err(_) if cancelled => FlashJobState::Cancelled,
+            Err(e) => FlashJobState::Error(FlashJobError {
+                message: format!("{:#}", e),
+                code: e.code(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlashJobStatus {
+    pub state: FlashJobState,
+    pub progress: TransferProgress,
+}
+
+/// A started job that hands out its progress as a stream, see
+/// [`crate::app::bmc_application::BmcApplication::start_flash_stream`].
+pub struct FlashJobHandle {
+    pub id: FlashJobId,
+    /// Yields the progress whenever it changes, and ends once the job
+    /// finished. Intermediate updates are skipped when polled slowly.
+    pub progress: WatchStream<TransferProgress>,
+    /// Resolves to the final state of the job.
+    pub done: oneshot::Receiver<FlashJobState>,
+}
+
+struct FlashJob {
+    state: FlashJobState,
+    progress: watch::Receiver<TransferProgress>,
+    cancel: CancellationToken,
+    pause: watch::Sender<bool>,
+}
+
+type JobMap = Arc<Mutex<HashMap<FlashJobId, FlashJob>>>;
+
+#[derive(Default)]
+pub struct FlashJobs {
+    jobs: JobMap,
+    next_id: AtomicU32,
+}
+
+impl FlashJobs {
+    /// Spawns the worker of `request`. The returned id can be used to
+    /// query the status of the job, or to cancel it.
+    pub fn start(&self, request: TransferRequest) -> FlashJobId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = request.cancel;
+        let job = FlashJob {
+            state: FlashJobState::Running,
+            progress: request.progress_watcher,
+            cancel: cancel.clone(),
+            pause: request.pause,
+        };
+        lock(&self.jobs).insert(id, job);
+
+        let jobs = self.jobs.clone();
+        let worker = request.worker;
+        tokio::spawn(async move {
+            tracing::info!("flash job #{} '{}' started", id, request.process_name);
+            let result = worker.await.map_err(BmcError::from);
+            let state = FlashJobState::finished(&result, cancel.is_cancelled());
+            tracing::info!("flash job #{} finished: {:?}", id, state);
+
+            if let Some(job) = lock(&jobs).get_mut(&id) {
+                job.state = state;
+            }
+
+            tokio::time::sleep(RETENTION).await;
+            lock(&jobs).remove(&id);
+        });
+
+        id
+    }
+
+    pub fn status(&self, id: FlashJobId) -> Option<FlashJobStatus> {
+        lock(&self.jobs).get(&id).map(|job| FlashJobStatus {
+            state: job.state.clone(),
+            progress: job.progress.borrow().clone(),
+        })
+    }
+
+    /// Returns the progress of the job as a stream, see
+    /// [`FlashJobHandle::progress`].
+    pub fn progress(&self, id: FlashJobId) -> Option<WatchStream<TransferProgress>> {
+        lock(&self.jobs)
+            .get(&id)
+            .map(|job| WatchStream::new(job.progress.clone()))
+    }
+
+    /// Pauses or resumes the job. Writing halts in between chunks, the node
+    /// stays powered. Returns false if no job with the given id exists.
+    pub fn pause(&self, id: FlashJobId, paused: bool) -> bool {
+        lock(&self.jobs)
+            .get(&id)
+            .map(|job| job.pause.send_replace(paused))
+            .is_some()
+    }
+
+    /// Returns false if no job with the given id exists.
+    pub fn cancel(&self, id: FlashJobId) -> bool {
+        lock(&self.jobs)
+            .get(&id)
+            .map(|job| job.cancel.cancel())
+            .is_some()
+    }
+}
+
+fn lock(jobs: &JobMap) -> std::sync::MutexGuard<'_, HashMap<FlashJobId, FlashJob>> {
+    jobs.lock().expect("flash jobs lock poisoned")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::bmc_application::NodeBusyError;
+    use crate::hal::NodeId;
+    use serde_json::json;
+
+    #[test]
+    fn errors_carry_a_code() {
+        let error = BmcError::NodeBusy(NodeBusyError {
+            node: NodeId::Node3,
+            operation: None,
+        });
+        let state = FlashJobState::finished(&Err(error), false);
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["error"]["code"], json!("node_busy"));
+
+        let error: FlashJobError = serde_json::from_value(json["error"].clone()).unwrap();
+        assert_eq!(error.code, Some(ErrorCode::NodeBusy));
+
+        let state = FlashJobState::finished(&Err(BmcError::Other(anyhow::anyhow!("x"))), true);
+        assert!(matches!(state, FlashJobState::Cancelled));
+    }
+
+    #[test]
+    fn progress_survives_a_round_trip() {
+        let mut progress = TransferProgress::default();
+        progress.set_message("booting".to_string());
+        let json = serde_json::to_string(&progress).unwrap();
+        let parsed: TransferProgress = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.message.as_deref(), Some("booting"));
+        assert_eq!(parsed.phase, progress.phase);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Images that are kept on the BMC, so that nodes can be flashed without
+//! uploading an image first.
+use crate::utils::{self, Checksum, ChecksumAlgo};
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CHECKSUM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// An image in the image directory, see [`ImageLibrary::list`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageInfo {
+    /// File name, which is accepted by [`ImageLibrary::resolve`].
+    pub name: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch at which the file was last modified.
+    pub modified: u64,
+    /// SHA-256 of the file as hex string, `None` when it was not computed yet.
+    pub checksum: Option<String>,
+}
+
+/// The image directory of the configuration. Checksums are computed on
+/// request only, as they take a full read of the image, and are kept until
+/// the file changes.
+pub struct ImageLibrary {
+    dir: Option<PathBuf>,
+    checksums: std::sync::Mutex<HashMap<String, (u64, u64, String)>>,
+}
+
+impl ImageLibrary {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            checksums: Default::default(),
+        }
+    }
+
+    /// Lists the files in the image directory, sorted by name. Checksums
+    /// that are not cached are computed when `checksums` is set. A missing
+    /// or unconfigured directory holds no images.
+    pub async fn list(&self, checksums: bool) -> Vec<ImageInfo> {
+        let Some(dir) = &self.dir else {
+            return Vec::new();
+        };
+
+        let mut images = match list_images(dir).await {
+            Ok(images) => images,
+            Err(e) => {
+                if e.downcast_ref::<std::io::Error>()
+                    .map_or(true, |e| e.kind() != ErrorKind::NotFound)
+                {
+                    tracing::warn!("listing images: {:#}", e);
+                }
+                return Vec::new();
+            }
+        };
+
+        for image in &mut images {
+            image.checksum = self.cached_checksum(image);
+            if image.checksum.is_some() || !checksums {
+                continue;
+            }
+
+            match file_checksum(dir.join(&image.name)).await {
+                Ok(checksum) => {
+                    self.lock_checksums().insert(
+                        image.name.clone(),
+                        (image.size, image.modified, checksum.clone()),
+                    );
+                    image.checksum = Some(checksum);
+                }
+                Err(e) => tracing::warn!("checksum of {}: {:#}", image.name, e),
+            }
+        }
+        images
+    }
+
+    /// Returns the path of the image `name` in the image directory. Only plain
+    /// file names of regular files are accepted, so that no file outside the
+    /// directory can be referred to, see [`utils::file_in_dir`].
+    pub fn resolve(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let Some(dir) = &self.dir else {
+            bail!("no image directory configured");
+        };
+        let path = utils::file_in_dir(dir, name)?;
+        if !path.symlink_metadata().is_ok_and(|m| m.is_file()) {
+            bail!("image `{name}` does not exist");
+        }
+        Ok(path)
+    }
+
+    fn cached_checksum(&self, image: &ImageInfo) -> Option<String> {
+        self.lock_checksums()
+            .get(&image.name)
+            .filter(|(size, modified, _)| (*size, *modified) == (image.size, image.modified))
+            .map(|(_, _, checksum)| checksum.clone())
+    }
+
+    fn lock_checksums(&self) -> std::sync::MutexGuard<'_, HashMap<String, (u64, u64, String)>> {
+        self.checksums.lock().expect("checksum cache poisoned")
+    }
+}
+
+async fn list_images(dir: &Path) -> anyhow::Result<Vec<ImageInfo>> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut images = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let modified = metadata
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        images.push(ImageInfo {
+            name,
+            size: metadata.len(),
+            modified,
+            checksum: None,
+        });
+    }
+    images.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(images)
+}
+
+async fn file_checksum(path: PathBuf) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file =
+            std::fs::File::open(&path).with_context(|| path.to_string_lossy().to_string())?;
+        let mut checksum = Checksum::new(ChecksumAlgo::Sha256);
+        let mut buffer = vec![0u8; CHECKSUM_CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            checksum.update(&buffer[..read]);
+        }
+        Ok(hex::encode(checksum.finalize()))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn images_are_listed_with_cached_checksums() {
+        let dir = TempDir::new("images").unwrap();
+        std::fs::write(dir.path().join("b.img"), b"image").unwrap();
+        std::fs::write(dir.path().join("a.img"), b"").unwrap();
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+
+        let library = ImageLibrary::new(Some(dir.path().to_path_buf()));
+        let images = library.list(false).await;
+        let names = images.iter().map(|i| i.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a.img", "b.img"]);
+        assert_eq!(images[1].size, 5);
+        assert!(images.iter().all(|i| i.checksum.is_none()));
+
+        let sha256 = "6105d6cc76af400325e94d588ce511be5bfdbb73b437dc51eca43917d7a43e3d";
+        assert_eq!(
+            library.list(true).await[1].checksum.as_deref(),
+            Some(sha256)
+        );
+        assert_eq!(
+            library.list(false).await[1].checksum.as_deref(),
+            Some(sha256)
+        );
+
+        assert!(library.resolve("b.img").is_ok());
+        assert!(library.resolve("../b.img").is_err());
+        assert!(library.resolve("c.img").is_err());
+        assert!(library.resolve("subdir").is_err());
+    }
+
+    #[tokio::test]
+    async fn symlinked_images_are_rejected() {
+        let dir = TempDir::new("images").unwrap();
+        let outside = TempDir::new("outside").unwrap();
+        std::fs::write(outside.path().join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), dir.path().join("link.img"))
+            .unwrap();
+
+        let library = ImageLibrary::new(Some(dir.path().to_path_buf()));
+        assert!(library.resolve("link.img").is_err());
+        assert!(library.list(false).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_directory_holds_no_images() {
+        let dir = TempDir::new("images").unwrap();
+        let library = ImageLibrary::new(Some(dir.path().join("missing")));
+        assert!(library.list(true).await.is_empty());
+        assert!(ImageLibrary::new(None).list(true).await.is_empty());
+    }
+}
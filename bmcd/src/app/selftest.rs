@@ -0,0 +1,159 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Probes of the subsystems of the BMC, executed at startup.
+use super::bmc_application::{BmcApplication, ACTIVATED_NODES_KEY};
+use super::event_application::INPUT_DEVICE;
+use crate::utils::find_input_device;
+use serde::Serialize;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestResult {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestItem {
+    pub subsystem: &'static str,
+    pub result: SelfTestResult,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SelfTestReport {
+    pub items: Vec<SelfTestItem>,
+}
+
+impl SelfTestReport {
+    fn add(&mut self, subsystem: &'static str, result: SelfTestResult) {
+        self.items.push(SelfTestItem { subsystem, result });
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let items = self
+            .items
+            .iter()
+            .map(|item| match &item.result {
+                SelfTestResult::Pass => format!("{}: OK", item.subsystem),
+                SelfTestResult::Fail(reason) | SelfTestResult::Skip(reason) => {
+                    format!("{}: {}", item.subsystem, reason)
+                }
+            })
+            .collect::<Vec<_>>();
+        f.write_str(&items.join(", "))
+    }
+}
+
+impl BmcApplication {
+    /// Probes each subsystem and collects the results. None of the probes
+    /// change the state of the hardware. `input_device` is the configured
+    /// input device, see [`find_input_device`].
+    pub(super) async fn selftest(&self, input_device: Option<&str>) -> SelfTestReport {
+        let mut report = SelfTestReport::default();
+
+        report.add(
+            "pin controller",
+            match self.pin_controller.usb_boot_state() {
+                Ok(_) => SelfTestResult::Pass,
+                Err(e) => SelfTestResult::Fail(e.to_string()),
+            },
+        );
+
+        let ignored = self.ignore_power_good().await;
+        report.add(
+            "power-good",
+            match self.pin_controller.power_good() {
+                _ if ignored => SelfTestResult::Skip("ignored".to_string()),
+                Ok(Some(true)) => SelfTestResult::Pass,
+                Ok(Some(false)) => SelfTestResult::Fail("not asserted".to_string()),
+                Ok(None) => SelfTestResult::Skip("not available".to_string()),
+                Err(e) => SelfTestResult::Fail(e.to_string()),
+            },
+        );
+
+        let persistency = match self.app_db.try_get::<u8>(ACTIVATED_NODES_KEY).await {
+            Err(e) => SelfTestResult::Fail(e.to_string()),
+            Ok(_) if !self.app_db.is_writable() => SelfTestResult::Fail("read-only".to_string()),
+            Ok(_) => SelfTestResult::Pass,
+        };
+        report.add("persistency", persistency);
+
+        report.add(
+            "input device",
+            input_device_probe(input_device.unwrap_or(INPUT_DEVICE), find_input_device),
+        );
+
+        report
+    }
+}
+
+/// Passes when `resolve`, the same lookup as the event listener uses, finds
+/// an input device for `spec`.
+fn input_device_probe(
+    spec: &str,
+    resolve: impl FnOnce(&str) -> std::io::Result<Option<PathBuf>>,
+) -> SelfTestResult {
+    match resolve(spec) {
+        Ok(Some(_)) => SelfTestResult::Pass,
+        Ok(None) => SelfTestResult::Fail(format!("{spec} missing")),
+        Err(e) => SelfTestResult::Fail(format!("{spec}: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::find_by_name;
+    use tempdir::TempDir;
+
+    #[test]
+    fn input_device_is_resolved_by_name() {
+        let root = TempDir::new("input").unwrap();
+        let sys = root.path().join("sys");
+        let dev = root.path().join("dev");
+        std::fs::create_dir_all(sys.join("event2/device")).unwrap();
+        std::fs::write(sys.join("event2/device/name"), "gpio-keys\n").unwrap();
+        let by_name = |spec: &str| find_by_name(&sys, &dev, spec.strip_prefix("name:").unwrap());
+
+        assert!(matches!(
+            input_device_probe("name:gpio-keys", by_name),
+            SelfTestResult::Pass
+        ));
+        assert!(matches!(
+            input_device_probe("name:other-keys", by_name),
+            SelfTestResult::Fail(_)
+        ));
+    }
+
+    #[test]
+    fn input_device_is_resolved_by_pattern() {
+        let dir = TempDir::new("input").unwrap();
+        std::fs::write(dir.path().join("event3"), b"").unwrap();
+        let pattern = dir.path().join("event*");
+        assert!(matches!(
+            input_device_probe(&pattern.to_string_lossy(), find_input_device),
+            SelfTestResult::Pass
+        ));
+        let missing = dir.path().join("event0");
+        assert!(matches!(
+            input_device_probe(&missing.to_string_lossy(), find_input_device),
+            SelfTestResult::Fail(_)
+        ));
+    }
+}
@@ -12,13 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use super::bmc_application::BmcApplication;
-use super::upgrade_worker::UpgradeWorker;
+use super::flash_jobs::FlashJobState;
+use super::upgrade_worker::{FlashOptions, FlashSummary, UpgradeWorker, VerifyMode};
 use crate::hal::NodeId;
 use crate::streaming_data_service::data_transfer::DataTransfer;
+use crate::streaming_data_service::transfer_context::{TransferPhase, TransferProgress};
 use crate::streaming_data_service::TransferRequest;
 use futures::future::BoxFuture;
+use futures::TryFutureExt;
 use std::sync::Arc;
-use tokio::sync::watch;
+use tokio::sync::{oneshot, watch};
 use tokio_util::sync::CancellationToken;
 
 pub struct InitializeTransfer {
@@ -52,38 +55,76 @@ impl TryInto<TransferRequest> for InitializeTransfer {
         let sender = self.data_transfer.sender_half();
         let cancel = CancellationToken::new();
         let cancel_child = cancel.child_token();
-        let (written_sender, written_receiver) = watch::channel(0u64);
+        let (pause, pause_receiver) = watch::channel(false);
+        let phases = self.upgrade_command.phases(self.do_crc_validation);
+        let (progress_sender, progress_receiver) =
+            watch::channel(TransferProgress::new(size, phases));
         let worker = self.upgrade_command.run(UpgradeWorker::new(
             self.do_crc_validation,
             self.data_transfer,
             cancel_child,
-            written_sender,
+            pause_receiver,
+            progress_sender,
         ));
 
         Ok(TransferRequest {
             process_name: self.transfer_name,
             size,
             sender,
-            progress_watcher: written_receiver,
+            progress_watcher: progress_receiver,
             worker,
             cancel,
+            pause,
         })
     }
 }
 
 pub enum UpgradeCommand {
     OsUpgrade,
-    Module(NodeId, Arc<BmcApplication>),
+    /// Flashes the node, the sender receives the outcome of the flash, see
+    /// [`UpgradeWorker::flash_node`].
+    Module(
+        NodeId,
+        Arc<BmcApplication>,
+        FlashOptions,
+        Option<oneshot::Sender<FlashJobState>>,
+    ),
 }
 
 impl UpgradeCommand {
+    /// The phases the upgrade goes through, used to weigh its progress.
+    fn phases(&self, do_crc_validation: bool) -> Vec<TransferPhase> {
+        match self {
+            UpgradeCommand::OsUpgrade => vec![TransferPhase::Writing],
+            UpgradeCommand::Module(_, _, options, _)
+                if options.verify.effective(do_crc_validation) == VerifyMode::Full =>
+            {
+                vec![
+                    TransferPhase::Preparing,
+                    TransferPhase::Writing,
+                    TransferPhase::Verifying,
+                    TransferPhase::Finalizing,
+                ]
+            }
+            UpgradeCommand::Module(..) => vec![
+                TransferPhase::Preparing,
+                TransferPhase::Writing,
+                TransferPhase::Finalizing,
+            ],
+        }
+    }
+
     pub fn run(
         self,
         upgrade_worker: UpgradeWorker,
-    ) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+    ) -> BoxFuture<'static, anyhow::Result<Option<FlashSummary>>> {
         match self {
-            UpgradeCommand::OsUpgrade => Box::pin(upgrade_worker.os_update()),
-            UpgradeCommand::Module(bmc, node) => Box::pin(upgrade_worker.flash_node(node, bmc)),
+            UpgradeCommand::OsUpgrade => Box::pin(upgrade_worker.os_update().map_ok(|_| None)),
+            UpgradeCommand::Module(node, bmc, options, done) => Box::pin(
+                upgrade_worker
+                    .flash_node(bmc, node, options, done)
+                    .err_into(),
+            ),
         }
     }
 }
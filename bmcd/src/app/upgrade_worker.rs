@@ -11,33 +11,342 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crate::app::bmc_application::BmcApplication;
+use crate::app::bmc_application::{BmcApplication, NodeOperation};
+use crate::app::bmc_error::BmcError;
+use crate::app::flash_jobs::FlashJobState;
+use crate::hal::led::Led;
 use crate::hal::{NodeId, UsbRoute};
 use crate::streaming_data_service::data_transfer::DataTransfer;
-use crate::utils::WriteMonitor;
-use anyhow::bail;
-use crc::{Crc, CRC_64_REDIS};
+use crate::streaming_data_service::transfer_context::{TransferPhase, TransferProgress};
+use crate::usb_boot::{DataTransport, DetectedModule};
+use crate::utils::{
+    ensure_not_boot_disk, get_timestamp_unix, logging_sink_stdio, BlockVerifier, Checksum,
+    ChecksumAlgo, WriteMonitor,
+};
+use anyhow::{bail, ensure, Context};
 use humansize::{format_size, DECIMAL};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+use std::future::Future;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncSeekExt;
+use tokio::io::BufReader;
 use tokio::io::BufStream;
-use tokio::io::{sink, AsyncRead};
-use tokio::sync::watch;
+use tokio::io::{sink, AsyncRead, AsyncSeek};
+use tokio::sync::{oneshot, watch};
 use tokio::task::spawn_blocking;
 use tokio::{
     fs,
     io::{self, AsyncWrite, AsyncWriteExt},
 };
 use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 
 const TMP_UPGRADE_DIR: &str = "/tmp/os_upgrade";
-const BLOCK_WRITE_SIZE: usize = BLOCK_READ_SIZE; // 512Kib
-const BLOCK_READ_SIZE: usize = 524288; // 512Kib
+const DEFAULT_BLOCK_SIZE: usize = 524288; // 512Kib
+/// Default of [`FlashOptions::read_ahead`].
+const DEFAULT_READ_AHEAD: usize = 4 * 1024 * 1024;
+const ACTIVITY_BLINK_INTERVAL: Duration = Duration::from_millis(250);
+/// Duration of a simulated phase in a dry run, see [`FlashOptions::dry_run`].
+const DRY_RUN_PHASE_DURATION: Duration = Duration::from_secs(2);
+const DRY_RUN_STEPS: u32 = 20;
+/// Time every phase of a flash gets on top of the time scaled by its size, see
+/// [`phase_timeout`].
+const PHASE_TIMEOUT_BASE: Duration = Duration::from_secs(300);
+/// Lowest throughput in bytes per second a phase is expected to reach.
+const MIN_THROUGHPUT: u64 = 512 * 1024;
+/// Assumed compression ratio of compressed images, whose decompressed size is
+/// unknown.
+const COMPRESSION_RATIO: u64 = 10;
+/// Alignment of [`FlashOptions::write_offset`].
+const SECTOR_SIZE: u64 = 512;
+
+/// Options that alter how [`UpgradeWorker::flash_node`] programs a node.
+#[derive(Debug, Default, Clone)]
+pub struct FlashOptions {
+    /// Write to this block device instead of detecting the device of the
+    /// node. Must be a whole disk, not a partition.
+    pub device_override: Option<PathBuf>,
+    /// Flash the node even if it is powered on. Flashing power cycles the
+    /// node, which interrupts whatever it is running.
+    pub allow_flash_running: bool,
+    /// Wait for other operations on the node to finish, instead of failing
+    /// with [NodeBusyError](crate::app::bmc_application::NodeBusyError).
+    pub wait_for_node: bool,
+    /// Rewrite the image up to this many times when the verification of the
+    /// written data fails. Only applies to images that can be read again,
+    /// i.e. local files.
+    pub retries: u8,
+    /// Run the flow without touching the node: the image is read and
+    /// checksummed, but not written, and verification is simulated.
+    pub dry_run: bool,
+    /// Algorithm used to checksum the written data, and to verify it.
+    pub checksum: ChecksumAlgo,
+    /// Only write the blocks of the image that differ from the data already
+    /// on the node. Takes a read of every block, but saves writes and wear
+    /// when reflashing a slightly changed image.
+    pub delta: bool,
+    /// Size of the chunks in which the image is written and verified.
+    pub block_size: BlockSize,
+    /// Size of the reads from the device while verifying, 4MiB by default.
+    /// Bigger reads keep the USB link busy on large images, at the cost of
+    /// memory. Never smaller than [`Self::block_size`]. Only the size of the
+    /// reads changes, blocks are compared as before.
+    pub read_ahead: Option<BlockSize>,
+    pub verify: VerifyMode,
+    /// Maximum duration of the write and the verify phase each. A phase that
+    /// takes longer fails with a [`FlashTimeoutError`], e.g. when the device
+    /// stops responding. Defaults to a duration scaled by the image size.
+    pub timeout: Option<Duration>,
+    /// Byte offset on the device at which the image is written, e.g. to only
+    /// replace the root partition. The data before the offset is preserved,
+    /// and only the written region is verified. Must be a multiple of 512.
+    pub write_offset: u64,
+    /// Power the node on once it is flashed successfully. By default, the
+    /// node is left powered off, e.g. to boot many flashed nodes at once.
+    pub power_on_after: bool,
+    /// Run the post-flash hooks of the configuration once the node is powered
+    /// on again, implies [`Self::power_on_after`]. The outcome is reported in
+    /// [`FlashSummary::hooks`], a failing hook does not fail the flash.
+    pub run_hooks: bool,
+    /// Record a [`FlashManifest`] of the written image in the summary.
+    pub manifest: bool,
+    /// Also write the manifest as JSON to this file, implies
+    /// [`Self::manifest`]. The API only accepts files in the configured
+    /// manifest directory, see
+    /// [`BmcApplication::manifest_path`](crate::app::bmc_application::BmcApplication::manifest_path).
+    pub manifest_file: Option<PathBuf>,
+}
+
+/// How [`UpgradeWorker::flash_node`] verifies the written image. The mode that
+/// was used is reported in [`FlashSummary::verify_mode`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyMode {
+    /// Trusts the write, the fastest mode.
+    None,
+    /// Reads the whole image back once it is written. The strictest mode, it
+    /// also catches blocks that got corrupted by writes of later blocks.
+    #[default]
+    Full,
+    /// Reads each block back right after it is written, saving the second
+    /// pass over the image. The block is synced and dropped from the page
+    /// cache first, so that it is read from the medium.
+    Inline,
+}
+
+impl VerifyMode {
+    /// The mode that is in effect, given the CRC validation setting of the
+    /// transfer, which predates the modes.
+    pub fn effective(self, do_crc_validation: bool) -> Self {
+        if do_crc_validation {
+            self
+        } else {
+            VerifyMode::None
+        }
+    }
+}
+
+/// Chunk size of the data written to, and read from, a node. Bigger blocks
+/// speed up large sequential writes to eMMC, smaller blocks are more forgiving
+/// on a flaky USB link. Defaults to 512KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSize(usize);
+
+impl BlockSize {
+    pub const MIN: usize = 4096;
+    pub const MAX: usize = 16 * 1024 * 1024;
+
+    /// `size` must be a power of two between [`Self::MIN`] and [`Self::MAX`].
+    pub fn new(size: usize) -> Result<Self, InvalidBlockSizeError> {
+        if size.is_power_of_two() && (Self::MIN..=Self::MAX).contains(&size) {
+            Ok(Self(size))
+        } else {
+            Err(InvalidBlockSizeError(size))
+        }
+    }
+
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        Self(DEFAULT_BLOCK_SIZE)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "block size must be a power of two between {min} and {max} bytes, got {0}",
+    min = BlockSize::MIN,
+    max = BlockSize::MAX
+)]
+pub struct InvalidBlockSizeError(pub usize);
+
+/// Returned when a phase of the flash exceeds its timeout, see
+/// [`FlashOptions::timeout`].
+#[derive(Debug, Error)]
+#[error("{phase:?} did not finish within {timeout:?}")]
+pub struct FlashTimeoutError {
+    pub phase: TransferPhase,
+    pub timeout: Duration,
+}
+
+/// Returned when a powered node is about to be flashed without
+/// [`FlashOptions::allow_flash_running`].
+#[derive(Debug, Error)]
+#[error("{0} is powered on, refusing to flash a running node")]
+pub struct NodeRunningError(pub NodeId);
+
+/// Returned when the data read back from the node does not match the data
+/// that was written.
+#[derive(Debug, Error)]
+#[error(
+    "checksum error. expected {}, calculated {}{}",
+    hex::encode(.expected),
+    hex::encode(.calculated),
+    .first_divergence.map(|offset| format!(", first difference at byte {offset}")).unwrap_or_default()
+)]
+pub struct ChecksumMismatchError {
+    pub expected: Vec<u8>,
+    pub calculated: Vec<u8>,
+    /// See [`TransferProgress::first_divergence`].
+    pub first_divergence: Option<u64>,
+}
+
+/// The checksums of an image, taken while writing it.
+struct ImageDigest {
+    algo: ChecksumAlgo,
+    checksum: Vec<u8>,
+    /// Per-block digests, used to locate a divergence, see
+    /// [`BlockVerifier`].
+    blocks: Vec<u32>,
+}
+
+/// Checksum of the data read back while writing, see [`VerifyMode::Inline`].
+struct DeviceChecksum {
+    checksum: Vec<u8>,
+    first_divergence: Option<u64>,
+}
+
+/// Describes what [`UpgradeWorker::flash_node`] wrote to the node.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlashSummary {
+    /// Number of bytes written to the device, after decompression.
+    pub bytes: u64,
+    pub algorithm: ChecksumAlgo,
+    /// Checksum of the written bytes, serialized as hex string.
+    #[serde(serialize_with = "serialize_checksum")]
+    pub checksum: Vec<u8>,
+    /// Whether the written bytes were read back and verified.
+    pub verified: bool,
+    pub verify_mode: VerifyMode,
+    /// Number of bytes that were read back and matched the image.
+    pub verified_bytes: u64,
+    /// The detected module, `None` when writing to a device override.
+    pub module: Option<DetectedModule>,
+    /// Set when [`FlashOptions::delta`] was used.
+    pub blocks: Option<BlockStats>,
+    /// Whether the node was powered on after flashing, see
+    /// [`FlashOptions::power_on_after`].
+    pub powered_on: bool,
+    /// Set when [`FlashOptions::run_hooks`] was used.
+    pub hooks: Option<HookStatus>,
+    /// Set when [`FlashOptions::manifest`] was used.
+    pub manifest: Option<FlashManifest>,
+    /// Whether the image was only read and nothing was written, see
+    /// [`FlashOptions::dry_run`].
+    pub dry_run: bool,
+}
+
+/// Auditable record that ties the image to the node and the device it was
+/// written to, at a point in time. Unlike [`FlashSummary::verified`], it
+/// tells which bytes landed on the node.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlashManifest {
+    pub node: NodeId,
+    pub algorithm: ChecksumAlgo,
+    /// Checksum of the written bytes, serialized as hex string.
+    #[serde(serialize_with = "serialize_checksum")]
+    pub checksum: Vec<u8>,
+    pub bytes: u64,
+    /// Offset on the device at which the image was written.
+    pub offset: u64,
+    /// The block device that was written, `None` when it cannot be resolved.
+    pub device: Option<PathBuf>,
+    pub verified: bool,
+    /// Seconds since the Unix epoch at which the flash finished.
+    pub timestamp: u64,
+}
+
+/// Outcome of the post-flash hooks. The hooks run in order, and stop at the
+/// first one that fails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookStatus {
+    /// The last hook that ran, i.e. the failing one.
+    pub command: String,
+    /// `None` when the hook could not be started, or got killed by a signal.
+    pub exit_code: Option<i32>,
+    pub success: bool,
+}
+
+/// Blocks written and skipped by a delta write, see [`FlashOptions::delta`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BlockStats {
+    pub written: u64,
+    /// Blocks that already held the data of the image.
+    pub skipped: u64,
+}
+
+impl Display for FlashSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({:?} {}, {})",
+            if self.dry_run { "dry run of" } else { "wrote" },
+            format_size(self.bytes, DECIMAL),
+            self.algorithm,
+            hex::encode(&self.checksum),
+            match (self.verified, self.verify_mode) {
+                (false, _) => "not verified",
+                (true, VerifyMode::Inline) => "verified while writing",
+                (true, _) => "verified",
+            }
+        )?;
+        if let Some(blocks) = &self.blocks {
+            write!(
+                f,
+                ", {} blocks written, {} skipped",
+                blocks.written, blocks.skipped
+            )?;
+        }
+        if self.powered_on {
+            f.write_str(", powered on")?;
+        }
+        match &self.hooks {
+            Some(hooks) if !hooks.success => write!(f, ", hook `{}` failed", hooks.command)?,
+            Some(_) => f.write_str(", hooks ran")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn serialize_checksum<S: Serializer>(checksum: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(checksum))
+}
 
 // Contains collection of functions that execute some business flow in relation
 // to file transfers in the BMC. See `flash_node` and `os_update`.
@@ -45,7 +354,8 @@ pub struct UpgradeWorker {
     do_crc_validation: bool,
     data_transfer: DataTransfer,
     cancel: CancellationToken,
-    written_sender: watch::Sender<u64>,
+    pause: watch::Receiver<bool>,
+    progress: watch::Sender<TransferProgress>,
 }
 
 impl UpgradeWorker {
@@ -53,13 +363,15 @@ impl UpgradeWorker {
         do_crc_validation: bool,
         data_transfer: DataTransfer,
         cancel: CancellationToken,
-        written_sender: watch::Sender<u64>,
+        pause: watch::Receiver<bool>,
+        progress: watch::Sender<TransferProgress>,
     ) -> Self {
         Self {
             do_crc_validation,
             data_transfer,
             cancel,
-            written_sender,
+            pause,
+            progress,
         }
     }
 
@@ -68,44 +380,394 @@ impl UpgradeWorker {
     /// at any time when the `CancellationToken` is cancelled. When a transfer
     /// is interrupted or failed, it will always powers off the Node and
     /// restores the USB mode equally to a successful flow would.
+    ///
+    /// The outcome is also sent to `done`, if given, once the flow finished.
+    /// Unlike the progress channel, it only fires at the end.
+    #[instrument(skip(self, bmc, done))]
     pub async fn flash_node(
+        self,
+        bmc: Arc<BmcApplication>,
+        node: NodeId,
+        options: FlashOptions,
+        done: Option<oneshot::Sender<FlashJobState>>,
+    ) -> Result<Option<FlashSummary>, BmcError> {
+        let cancel = self.cancel.clone();
+        let result = self
+            .try_flash_node(bmc, node, options)
+            .await
+            .map_err(BmcError::from);
+        if let Some(done) = done {
+            // the receiver may not be interested anymore.
+            let _ = done.send(FlashJobState::finished(&result, cancel.is_cancelled()));
+        }
+        result
+    }
+
+    async fn try_flash_node(
         mut self,
         bmc: Arc<BmcApplication>,
         node: NodeId,
-    ) -> anyhow::Result<()> {
-        let device = bmc.node_in_flash(node, UsbRoute::Bmc).await?;
+        options: FlashOptions,
+    ) -> anyhow::Result<Option<FlashSummary>> {
+        if options.dry_run {
+            return self.dry_run(node, &options).await.map(Some);
+        }
+
+        bmc.ensure_not_in_maintenance().await?;
+        let _guard = bmc
+            .lock_node(node, NodeOperation::Flash, options.wait_for_node)
+            .await?;
+        if !options.allow_flash_running && bmc.get_node_power(node).await? {
+            return Err(NodeRunningError(node).into());
+        }
+
+        if let Some(path) = &options.device_override {
+            ensure_whole_block_device(path).await?;
+        }
+        ensure!(
+            !options.run_hooks || !bmc.post_flash_hooks().is_empty(),
+            "no post-flash hooks configured"
+        );
+        ensure!(
+            options.write_offset % SECTOR_SIZE == 0,
+            "write offset {} is not a multiple of {SECTOR_SIZE}",
+            options.write_offset
+        );
+
+        let (mut device, module) = bmc
+            .node_in_flash(
+                node,
+                UsbRoute::Bmc,
+                &self.progress,
+                options.device_override.as_deref(),
+                &self.cancel,
+            )
+            .await?;
+        bmc.leds.blink(Led::Status, ACTIVITY_BLINK_INTERVAL);
+        let device_path = device_path(&device);
 
+        let progress = self.progress.clone();
         let result = async move {
-            let reader = self.data_transfer.reader().await?;
+            self.ensure_image_fits(&mut device, options.write_offset)
+                .await?;
+            let block_size = options.block_size;
             let mut buf_stream =
-                BufStream::with_capacity(BLOCK_READ_SIZE, BLOCK_WRITE_SIZE, device);
-            let (bytes_written, written_crc) =
-                self.try_write_node(node, reader, &mut buf_stream).await?;
-
-            if self.do_crc_validation {
-                buf_stream.seek(std::io::SeekFrom::Start(0)).await?;
-                flush_file_caches().await?;
-                self.try_validate_crc(node, written_crc, buf_stream.take(bytes_written))
-                    .await?;
+                BufStream::with_capacity(block_size.get(), block_size.get(), device);
+            let write_timeout = match options.timeout {
+                Some(timeout) => timeout,
+                None => phase_timeout(self.expected_image_size()?),
+            };
+            let retries = if self.data_transfer.is_replayable() {
+                options.retries
             } else {
-                tracing::info!("user skipped crc check");
-            }
+                0
+            };
 
-            Ok::<(), anyhow::Error>(())
+            let mut attempt = 0;
+            loop {
+                self.progress.send_modify(|p| {
+                    p.enter(TransferPhase::Writing);
+                    if attempt > 0 {
+                        p.set_message(format!("retry {attempt}/{retries}"));
+                    }
+                });
+
+                let reader = self.data_transfer.reader(&self.progress).await?;
+                let verify_mode = options.verify.effective(self.do_crc_validation);
+                let inline = verify_mode == VerifyMode::Inline;
+                let (bytes_written, image, blocks, device_checksum) = if options.delta || inline {
+                    let read_back = inline
+                        .then(|| {
+                            Ok::<_, io::Error>(ReadBack {
+                                checksum: Checksum::new(options.checksum),
+                                handle: Some(buf_stream.get_ref().sync_handle()?),
+                                verified: 0,
+                                first_divergence: None,
+                            })
+                        })
+                        .transpose()?;
+                    let copy = BlockCopy {
+                        block_size,
+                        offset: options.write_offset,
+                        delta: options.delta,
+                        read_back,
+                    };
+                    let (bytes, image, blocks, device_checksum) = with_timeout(
+                        TransferPhase::Writing,
+                        write_timeout,
+                        self.pause.clone(),
+                        self.try_write_node_blocks(
+                            node,
+                            reader,
+                            &mut buf_stream,
+                            options.checksum,
+                            copy,
+                        ),
+                    )
+                    .await?;
+                    (
+                        bytes,
+                        image,
+                        options.delta.then_some(blocks),
+                        device_checksum,
+                    )
+                } else {
+                    let (bytes, image) = with_timeout(
+                        TransferPhase::Writing,
+                        write_timeout,
+                        self.pause.clone(),
+                        self.try_write_node(
+                            node,
+                            reader,
+                            &mut buf_stream,
+                            options.checksum,
+                            block_size,
+                        ),
+                    )
+                    .await?;
+                    (bytes, image, None, None)
+                };
+
+                if verify_mode == VerifyMode::None {
+                    tracing::info!("user skipped crc check");
+                    break Ok::<_, anyhow::Error>(FlashSummary {
+                        bytes: bytes_written,
+                        algorithm: options.checksum,
+                        checksum: image.checksum,
+                        verified: false,
+                        verify_mode,
+                        verified_bytes: 0,
+                        module,
+                        blocks,
+                        powered_on: false,
+                        hooks: None,
+                        manifest: None,
+                        dry_run: false,
+                    });
+                }
+
+                let validation = if let Some(device) = device_checksum {
+                    tracing::info!("verified {node} while writing");
+                    check_checksum(&image.checksum, device.checksum, device.first_divergence)
+                } else {
+                    let verify_timeout = options
+                        .timeout
+                        .unwrap_or_else(|| phase_timeout(bytes_written));
+                    let pause = self.pause.clone();
+                    with_timeout(TransferPhase::Verifying, verify_timeout, pause, async {
+                        sync_device(buf_stream.get_ref().sync_handle()?).await?;
+                        buf_stream
+                            .seek(std::io::SeekFrom::Start(options.write_offset))
+                            .await?;
+                        flush_file_caches().await?;
+                        let read_ahead = options
+                            .read_ahead
+                            .map_or(DEFAULT_READ_AHEAD, BlockSize::get)
+                            .max(block_size.get());
+                        self.try_validate_checksum(
+                            node,
+                            &image,
+                            BufReader::with_capacity(read_ahead, &mut buf_stream),
+                            bytes_written,
+                            block_size,
+                        )
+                        .await
+                    })
+                    .await
+                };
+
+                match validation {
+                    Ok(()) => {
+                        break Ok(FlashSummary {
+                            bytes: bytes_written,
+                            algorithm: options.checksum,
+                            checksum: image.checksum,
+                            verified: true,
+                            verify_mode,
+                            verified_bytes: bytes_written,
+                            module,
+                            blocks,
+                            powered_on: false,
+                            hooks: None,
+                            manifest: None,
+                            dry_run: false,
+                        })
+                    }
+                    Err(e) if attempt < retries && e.is::<ChecksumMismatchError>() => {
+                        attempt += 1;
+                        tracing::warn!("{node}: {e}, rewriting image ({attempt}/{retries})");
+                        buf_stream
+                            .seek(std::io::SeekFrom::Start(options.write_offset))
+                            .await?;
+                    }
+                    Err(e) => break Err(e),
+                }
+            }
         }
         .await;
 
-        if let Ok(()) = result {
-            tracing::info!("Flashing {node} successful, restoring USB & power settings.");
+        bmc.leds.set_led(Led::Status, false).await;
+
+        if let Ok(summary) = &result {
+            tracing::info!(
+                "Flashing {node} successful, {summary}. restoring USB & power settings."
+            );
         }
 
         // disregarding the result, set the BMC in the finalized state.
+        progress.send_modify(|p| {
+            p.enter(TransferPhase::Finalizing);
+            p.set_message(format!("powering off {node}"));
+        });
         bmc.activate_slot(node.to_inverse_bitfield(), node.to_bitfield())
             .await?;
+        progress.send_modify(|p| p.set_message("leaving flash mode".to_string()));
         bmc.usb_boot(node, false).await?;
+        progress.send_modify(|p| p.set_message("restoring USB mode".to_string()));
         let (mode, _) = bmc.get_usb_mode().await;
         bmc.configure_usb(mode).await?;
-        result
+
+        let mut summary = result?;
+        if options.power_on_after || options.run_hooks {
+            progress.send_modify(|p| p.set_message(format!("powering on {node}")));
+            bmc.activate_slot(node.to_bitfield(), node.to_bitfield())
+                .await?;
+            summary.powered_on = true;
+        }
+        if options.manifest || options.manifest_file.is_some() {
+            let manifest = FlashManifest {
+                node,
+                algorithm: summary.algorithm,
+                checksum: summary.checksum.clone(),
+                bytes: summary.bytes,
+                offset: options.write_offset,
+                device: device_path,
+                verified: summary.verified,
+                timestamp: get_timestamp_unix().unwrap_or_default(),
+            };
+            if let Some(path) = &options.manifest_file {
+                fs::write(path, serde_json::to_vec_pretty(&manifest)?)
+                    .await
+                    .with_context(|| format!("writing manifest {}", path.to_string_lossy()))?;
+            }
+            summary.manifest = Some(manifest);
+        }
+        if options.run_hooks {
+            progress.send_modify(|p| p.set_message("running post-flash hooks".to_string()));
+            let hooks = run_post_flash_hooks(node, bmc.post_flash_hooks()).await;
+            if !hooks.success {
+                tracing::warn!("{node}: post-flash hook `{}` failed", hooks.command);
+            }
+            summary.hooks = Some(hooks);
+        }
+        Ok(Some(summary))
+    }
+
+    /// Produces the same progress events as [`Self::flash_node`], without
+    /// touching the node. The image is read into a sink, so that uploads are
+    /// consumed and the checksum is real.
+    async fn dry_run(
+        &mut self,
+        node: NodeId,
+        options: &FlashOptions,
+    ) -> anyhow::Result<FlashSummary> {
+        tracing::info!(
+            "dry run: would power off {node}, route USB to the BMC and boot it into flash mode"
+        );
+        if let Some(path) = &options.device_override {
+            tracing::info!("dry run: would write to {}", path.to_string_lossy());
+        }
+        if options.write_offset != 0 {
+            tracing::info!("dry run: would write at offset {}", options.write_offset);
+        }
+        simulate_progress(&self.progress, &self.cancel, 0).await?;
+
+        self.progress
+            .send_modify(|p| p.enter(TransferPhase::Writing));
+        let reader = self.data_transfer.reader(&self.progress).await?;
+        let (bytes_written, image) = self
+            .try_write_node(
+                node,
+                reader,
+                &mut sink(),
+                options.checksum,
+                options.block_size,
+            )
+            .await?;
+
+        let verify_mode = options.verify.effective(self.do_crc_validation);
+        if verify_mode == VerifyMode::Full {
+            self.progress
+                .send_modify(|p| p.enter(TransferPhase::Verifying));
+            simulate_progress(&self.progress, &self.cancel, bytes_written).await?;
+        }
+
+        self.progress
+            .send_modify(|p| p.enter(TransferPhase::Finalizing));
+        tracing::info!("dry run: would restore USB & power settings of {node}");
+        if options.power_on_after || options.run_hooks {
+            tracing::info!("dry run: would power on {node}");
+        }
+        if options.run_hooks {
+            tracing::info!("dry run: would run the post-flash hooks");
+        }
+        Ok(FlashSummary {
+            bytes: bytes_written,
+            algorithm: options.checksum,
+            checksum: image.checksum,
+            verified: false,
+            verify_mode,
+            verified_bytes: 0,
+            module: None,
+            blocks: None,
+            powered_on: false,
+            hooks: None,
+            manifest: None,
+            dry_run: true,
+        })
+    }
+
+    /// Fails when the image, written at `offset`, exceeds the capacity of
+    /// `device`. Only the offset is checked for compressed images, as their
+    /// decompressed size is not known upfront. Leaves `device` positioned at
+    /// `offset`.
+    async fn ensure_image_fits(
+        &self,
+        device: &mut (impl AsyncSeek + Unpin),
+        offset: u64,
+    ) -> anyhow::Result<()> {
+        let capacity = device.seek(std::io::SeekFrom::End(0)).await?;
+        device.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let image_size = if self.data_transfer.is_compressed() {
+            tracing::warn!("size of compressed image unknown, skipping capacity check");
+            0
+        } else {
+            self.data_transfer.size()?
+        };
+
+        let end = offset.saturating_add(image_size);
+        if end > capacity {
+            bail!(
+                "image is {} at offset {}, target is {}",
+                format_size(image_size, DECIMAL),
+                offset,
+                format_size(capacity, DECIMAL)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Size of the image once it is written. Compressed images are estimated
+    /// by [`COMPRESSION_RATIO`].
+    fn expected_image_size(&self) -> anyhow::Result<u64> {
+        let size = self.data_transfer.size()?;
+        if self.data_transfer.is_compressed() {
+            Ok(size.saturating_mul(COMPRESSION_RATIO))
+        } else {
+            Ok(size)
+        }
     }
 
     async fn try_write_node(
@@ -113,51 +775,118 @@ impl UpgradeWorker {
         node: NodeId,
         source_reader: impl AsyncRead + 'static + Unpin,
         mut node_writer: &mut (impl AsyncWrite + 'static + Unpin),
-    ) -> anyhow::Result<(u64, u64)> {
+        algo: ChecksumAlgo,
+        block_size: BlockSize,
+    ) -> anyhow::Result<(u64, ImageDigest)> {
         tracing::info!("started writing to {node}");
 
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let mut write_watcher = WriteMonitor::new(&mut node_writer, &mut self.written_sender, &crc);
+        let mut write_watcher = WriteMonitor::new(&mut node_writer, &self.progress, algo)
+            .with_block_digests(block_size.get());
 
-        let bytes_written = copy_or_cancel(source_reader, &mut write_watcher, &self.cancel).await?;
-        let crc = write_watcher.crc();
+        let bytes_written = copy_or_cancel(
+            source_reader,
+            &mut write_watcher,
+            block_size,
+            &self.cancel,
+            &mut self.pause,
+            &self.progress,
+        )
+        .await?;
+        let blocks = write_watcher.block_digests();
+        let checksum = write_watcher.checksum();
 
         tracing::info!(
-            "Wrote {}, crc: {}",
+            "Wrote {}, {:?}: {}",
             format_size(bytes_written, DECIMAL),
-            crc
+            algo,
+            hex::encode(&checksum)
         );
 
-        Ok((bytes_written, crc))
+        Ok((
+            bytes_written,
+            ImageDigest {
+                algo,
+                checksum,
+                blocks,
+            },
+        ))
     }
 
-    async fn try_validate_crc(
+    /// Writes `source_reader` to `device` block by block, see [`copy_blocks`].
+    /// Returns the digest of the image, and the checksum of the data read back
+    /// from the device, if `copy` reads it back.
+    async fn try_write_node_blocks(
         &mut self,
         node: NodeId,
-        expected_crc: u64,
-        node_reader: impl AsyncRead + 'static + Unpin,
-    ) -> anyhow::Result<()> {
-        tracing::info!("Verifying checksum of data on node {node}");
+        source_reader: impl AsyncRead + Unpin,
+        device: &mut (impl AsyncRead + AsyncWrite + AsyncSeek + Unpin),
+        algo: ChecksumAlgo,
+        copy: BlockCopy,
+    ) -> anyhow::Result<(u64, ImageDigest, BlockStats, Option<DeviceChecksum>)> {
+        tracing::info!("started writing to {node}, block by block");
 
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let mut sink = WriteMonitor::new(sink(), &mut self.written_sender, &crc);
-        copy_or_cancel(node_reader, &mut sink, &self.cancel).await?;
-        let dev_checksum = sink.crc();
+        let mut monitor = WriteMonitor::new(sink(), &self.progress, algo)
+            .with_block_digests(copy.block_size.get());
+        let (bytes, blocks, device_checksum) = copy_blocks(
+            source_reader,
+            device,
+            &mut monitor,
+            copy,
+            &self.cancel,
+            &mut self.pause,
+            &self.progress,
+        )
+        .await?;
+        let block_digests = monitor.block_digests();
+        let checksum = monitor.checksum();
 
-        if expected_crc != dev_checksum {
-            bail!(
-                "crc error. expected {}, calculated {}",
-                expected_crc.to_string(),
-                dev_checksum.to_string()
-            );
-        }
+        tracing::info!(
+            "Processed {}, {} blocks written, {} skipped, {:?}: {}",
+            format_size(bytes, DECIMAL),
+            blocks.written,
+            blocks.skipped,
+            algo,
+            hex::encode(&checksum)
+        );
 
-        Ok(())
+        let image = ImageDigest {
+            algo,
+            checksum,
+            blocks: block_digests,
+        };
+        Ok((bytes, image, blocks, device_checksum))
+    }
+
+    /// Verifies the `len` bytes of the image, read from the current position
+    /// of `node_reader`. The device is usually larger than the image, the
+    /// sectors behind the image are not part of the verification.
+    async fn try_validate_checksum(
+        &mut self,
+        node: NodeId,
+        image: &ImageDigest,
+        node_reader: impl AsyncRead + Unpin,
+        len: u64,
+        block_size: BlockSize,
+    ) -> anyhow::Result<()> {
+        tracing::info!("Verifying checksum of data on node {node}");
+        self.progress
+            .send_modify(|p| p.enter(TransferPhase::Verifying));
+
+        verify_region(
+            node_reader,
+            len,
+            image,
+            block_size,
+            &self.cancel,
+            &mut self.pause,
+            &self.progress,
+        )
+        .await
     }
 
     pub async fn os_update(mut self) -> anyhow::Result<()> {
         let file_name = self.data_transfer.file_name()?.to_owned();
-        let source = self.data_transfer.reader().await?;
+        let source = self.data_transfer.reader(&self.progress).await?;
         tracing::info!("start firmware upgrade {}", file_name.to_string_lossy());
 
         let mut os_update_img = PathBuf::from(TMP_UPGRADE_DIR);
@@ -172,9 +901,18 @@ impl UpgradeWorker {
             .open(&os_update_img)
             .await?;
 
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let mut writer = WriteMonitor::new(&mut file, &mut self.written_sender, &crc);
-        copy_or_cancel(source, &mut writer, &self.cancel).await?;
+        self.progress
+            .send_modify(|p| p.enter(TransferPhase::Writing));
+        let mut writer = WriteMonitor::new(&mut file, &self.progress, ChecksumAlgo::default());
+        copy_or_cancel(
+            source,
+            &mut writer,
+            BlockSize::default(),
+            &self.cancel,
+            &mut self.pause,
+            &self.progress,
+        )
+        .await?;
 
         let result = spawn_blocking(move || {
             Command::new("sh")
@@ -195,18 +933,37 @@ impl UpgradeWorker {
     }
 }
 
-/// Copies bytes from `reader` to `writer` until the reader is exhausted. This function
-/// returns an `io::Error(Interrupted)` in case a cancel was issued.
+/// Copies bytes from `reader` to `writer` in chunks of up to `block_size`,
+/// until the reader is exhausted. This function
+/// returns an `io::Error(Interrupted)` in case a cancel was issued. While
+/// `pause` is true, the copy halts in between chunks, which is reflected in
+/// `progress`.
 async fn copy_or_cancel<L, W>(
     mut reader: L,
-    mut writer: &mut W,
+    writer: &mut W,
+    block_size: BlockSize,
     cancel: &CancellationToken,
+    pause: &mut watch::Receiver<bool>,
+    progress: &watch::Sender<TransferProgress>,
 ) -> std::io::Result<u64>
 where
     L: AsyncRead + std::marker::Unpin,
     W: AsyncWrite + std::marker::Unpin,
 {
-    let copy_task = tokio::io::copy(&mut reader, &mut writer);
+    let copy_task = async {
+        let mut buffer = vec![0u8; block_size.get()];
+        let mut copied = 0u64;
+        loop {
+            wait_if_paused(pause, progress, copied).await;
+
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok::<u64, Error>(copied);
+            }
+            writer.write_all(&buffer[..read]).await?;
+            copied += read as u64;
+        }
+    };
     let cancel = cancel.cancelled();
 
     let bytes_copied: u64;
@@ -220,6 +977,341 @@ where
     Ok(bytes_copied)
 }
 
+/// Options of [`copy_blocks`].
+struct BlockCopy {
+    block_size: BlockSize,
+    /// Position of the image on the device, see [`FlashOptions::write_offset`].
+    offset: u64,
+    /// Only write the blocks that differ from the device, see
+    /// [`FlashOptions::delta`].
+    delta: bool,
+    /// Read each block back from the device, see [`VerifyMode::Inline`].
+    read_back: Option<ReadBack>,
+}
+
+struct ReadBack {
+    /// Checksum of the data read back from the device.
+    checksum: Checksum,
+    /// Used to bypass the page cache, see [`Self::evict`].
+    handle: Option<std::fs::File>,
+    /// Bytes read back that match the image.
+    verified: u64,
+    first_divergence: Option<u64>,
+}
+
+impl ReadBack {
+    /// Compares `read`, read back from `offset` of the image, with `block` of
+    /// the image.
+    fn update(
+        &mut self,
+        offset: u64,
+        block: &[u8],
+        read: &[u8],
+        progress: &watch::Sender<TransferProgress>,
+    ) {
+        self.checksum.update(read);
+        match block.iter().zip(read).position(|(a, b)| a != b) {
+            None => self.verified += block.len() as u64,
+            Some(index) if self.first_divergence.is_none() => {
+                let divergence = offset + index as u64;
+                self.first_divergence = Some(divergence);
+                progress.send_modify(|p| p.set_divergence(divergence));
+            }
+            Some(_) => {}
+        }
+        let verified = self.verified;
+        progress.send_modify(|p| p.set_verified(verified));
+    }
+
+    fn finish(self) -> DeviceChecksum {
+        DeviceChecksum {
+            checksum: self.checksum.finalize(),
+            first_divergence: self.first_divergence,
+        }
+    }
+
+    /// Syncs the given range of the device to the medium and drops it from the
+    /// page cache, so that the next read of the range gets the data from the
+    /// medium.
+    async fn evict(&self, offset: u64, len: usize) -> io::Result<()> {
+        let Some(handle) = &self.handle else {
+            return Ok(());
+        };
+        let handle = handle.try_clone()?;
+        spawn_blocking(move || {
+            handle.sync_data()?;
+            posix_fadvise(
+                handle.as_raw_fd(),
+                offset as i64,
+                len as i64,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Like [`copy_or_cancel`], but writes `reader` to `device` one block at a
+/// time, as configured by `copy`. All blocks of the image are written to
+/// `monitor`, so that its checksum and progress cover the whole image.
+///
+/// The image is written to `device` starting at the offset of `copy`, the
+/// returned amount of bytes counts the image only.
+///
+/// In delta mode, each block is compared with the block at the same offset of
+/// `device`, and only written when they differ. The blocks are compared
+/// directly, which is as exact as comparing their hashes. When reading the
+/// device fails, all remaining blocks are written.
+///
+/// When reading back, the checksum of the data on the device is returned
+/// besides the amount of bytes, and the read back data is compared with the
+/// image.
+async fn copy_blocks<L, D, W>(
+    mut reader: L,
+    device: &mut D,
+    monitor: &mut W,
+    mut copy: BlockCopy,
+    cancel: &CancellationToken,
+    pause: &mut watch::Receiver<bool>,
+    progress: &watch::Sender<TransferProgress>,
+) -> std::io::Result<(u64, BlockStats, Option<DeviceChecksum>)>
+where
+    L: AsyncRead + Unpin,
+    D: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copy_task = async {
+        let mut image = vec![0u8; copy.block_size.get()];
+        let mut current = vec![0u8; copy.block_size.get()];
+        let mut blocks = BlockStats::default();
+        let mut compare = copy.delta;
+        let mut offset = 0u64;
+        device.seek(std::io::SeekFrom::Start(copy.offset)).await?;
+        loop {
+            wait_if_paused(pause, progress, offset).await;
+
+            let len = read_block(&mut reader, &mut image).await?;
+            if len == 0 {
+                return Ok::<_, Error>((offset, blocks));
+            }
+            let block = &image[..len];
+
+            let unchanged = compare
+                && match device.read_exact(&mut current[..len]).await {
+                    Ok(_) => current[..len] == *block,
+                    Err(e) => {
+                        tracing::warn!("cannot read device ({}), writing all blocks", e);
+                        compare = false;
+                        false
+                    }
+                };
+
+            if unchanged {
+                blocks.skipped += 1;
+            } else {
+                let position = copy.offset + offset;
+                device.seek(std::io::SeekFrom::Start(position)).await?;
+                device.write_all(block).await?;
+                // buffered writes must reach the device before the block is
+                // read again.
+                device.flush().await?;
+                blocks.written += 1;
+
+                if let Some(read_back) = &copy.read_back {
+                    read_back.evict(position, len).await?;
+                    device.seek(std::io::SeekFrom::Start(position)).await?;
+                    device.read_exact(&mut current[..len]).await?;
+                }
+            }
+
+            if let Some(read_back) = &mut copy.read_back {
+                read_back.update(offset, block, &current[..len], progress);
+            }
+            monitor.write_all(block).await?;
+            offset += len as u64;
+        }
+    };
+    let cancel = cancel.cancelled();
+
+    let (bytes, blocks);
+    tokio::select! {
+        res = copy_task => (bytes, blocks) = res?,
+        _ = cancel => return Err(Error::from(ErrorKind::Interrupted)),
+    };
+
+    device.flush().await?;
+    monitor.flush().await?;
+    let device_checksum = copy.read_back.map(ReadBack::finish);
+    Ok((bytes, blocks, device_checksum))
+}
+
+/// Fills `buffer` from `reader`, unless the reader is exhausted first.
+/// Returns the amount of bytes read.
+async fn read_block(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = reader.read(&mut buffer[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+/// Halts while `pause` is true, which is reflected in `progress`.
+async fn wait_if_paused(
+    pause: &mut watch::Receiver<bool>,
+    progress: &watch::Sender<TransferProgress>,
+    copied: u64,
+) {
+    if *pause.borrow_and_update() {
+        tracing::info!("paused after {}", format_size(copied, DECIMAL));
+        progress.send_modify(|p| p.set_paused(true));
+        // a dropped sender resumes the copy.
+        let _ = pause.wait_for(|paused| !paused).await;
+        progress.send_modify(|p| p.set_paused(false));
+        tracing::info!("resumed");
+    }
+}
+
+/// Verifies exactly `len` bytes of `reader` against `image`, and fails if the
+/// reader ends before. The blocks are compared as they are read, so that the
+/// verified share and the first divergence show up in `progress`.
+async fn verify_region(
+    reader: impl AsyncRead + Unpin,
+    len: u64,
+    image: &ImageDigest,
+    block_size: BlockSize,
+    cancel: &CancellationToken,
+    pause: &mut watch::Receiver<bool>,
+    progress: &watch::Sender<TransferProgress>,
+) -> anyhow::Result<()> {
+    let mut verifier = BlockVerifier::new(&image.blocks, block_size.get(), progress);
+    let mut monitor = WriteMonitor::new(&mut verifier, progress, image.algo);
+    let read = copy_or_cancel(
+        reader.take(len),
+        &mut monitor,
+        block_size,
+        cancel,
+        pause,
+        progress,
+    )
+    .await?;
+    ensure!(read == len, "device ended after {read} of {len} bytes");
+    let calculated = monitor.checksum();
+    let first_divergence = verifier.finish();
+    check_checksum(&image.checksum, calculated, first_divergence)
+}
+
+/// Default timeout of a phase that processes `bytes`, see
+/// [`FlashOptions::timeout`].
+fn phase_timeout(bytes: u64) -> Duration {
+    PHASE_TIMEOUT_BASE + Duration::from_secs(bytes / MIN_THROUGHPUT)
+}
+
+/// Fails with a [`FlashTimeoutError`] if `future` does not complete within
+/// `timeout`. The time during which the transfer is paused, see `pause`, does
+/// not count towards the timeout.
+async fn with_timeout<T>(
+    phase: TransferPhase,
+    timeout: Duration,
+    mut pause: watch::Receiver<bool>,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::pin!(future);
+    let mut remaining = timeout;
+    // a dropped sender resumes the transfer for good.
+    let mut pausable = true;
+    loop {
+        if pausable && *pause.borrow_and_update() {
+            tokio::select! {
+                result = &mut future => return result,
+                changed = pause.changed() => pausable = changed.is_ok(),
+            }
+            continue;
+        }
+
+        let started = tokio::time::Instant::now();
+        tokio::select! {
+            result = &mut future => return result,
+            _ = tokio::time::sleep(remaining) => {
+                return Err(FlashTimeoutError { phase, timeout }.into())
+            }
+            changed = pause.changed(), if pausable => {
+                pausable = changed.is_ok();
+                remaining = remaining.saturating_sub(started.elapsed());
+            }
+        }
+    }
+}
+
+/// A divergence fails the check even if the checksums match by chance.
+fn check_checksum(
+    expected: &[u8],
+    calculated: Vec<u8>,
+    first_divergence: Option<u64>,
+) -> anyhow::Result<()> {
+    if expected != calculated || first_divergence.is_some() {
+        return Err(ChecksumMismatchError {
+            expected: expected.to_vec(),
+            calculated,
+            first_divergence,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Advances the progress of the current phase to `total` bytes in
+/// [`DRY_RUN_STEPS`] steps, spread over [`DRY_RUN_PHASE_DURATION`].
+async fn simulate_progress(
+    progress: &watch::Sender<TransferProgress>,
+    cancel: &CancellationToken,
+    total: u64,
+) -> std::io::Result<()> {
+    for step in 1..=DRY_RUN_STEPS {
+        tokio::select! {
+            _ = tokio::time::sleep(DRY_RUN_PHASE_DURATION / DRY_RUN_STEPS) => {},
+            _ = cancel.cancelled() => return Err(Error::from(ErrorKind::Interrupted)),
+        }
+        progress.send_modify(|p| p.set_written(total * u64::from(step) / u64::from(DRY_RUN_STEPS)));
+    }
+    Ok(())
+}
+
+/// Refuses anything but a whole block device, e.g. `/dev/sda` is accepted
+/// while `/dev/sda1` is not.
+async fn ensure_whole_block_device(path: &Path) -> anyhow::Result<()> {
+    let path = fs::canonicalize(path)
+        .await
+        .with_context(|| path.to_string_lossy().to_string())?;
+    let metadata = fs::metadata(&path).await?;
+    if !metadata.file_type().is_block_device() {
+        bail!("{} is not a block device", path.to_string_lossy());
+    }
+    ensure_not_boot_disk(&path)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if fs::try_exists(format!("/sys/class/block/{name}/partition")).await? {
+        bail!(
+            "{} is a partition, expected a whole disk",
+            path.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+/// Waits until the data written to `device` reached the medium, see
+/// [`DataTransport::sync_handle`]. Without it, dirty pages survive
+/// [`flush_file_caches`], and the verification would read them instead of
+/// the data on the node.
+async fn sync_device(device: std::fs::File) -> io::Result<()> {
+    spawn_blocking(move || device.sync_all()).await?
+}
+
 async fn flush_file_caches() -> io::Result<()> {
     let mut file = fs::OpenOptions::new()
         .write(true)
@@ -230,10 +1322,61 @@ async fn flush_file_caches() -> io::Result<()> {
     file.write_u8(b'3').await
 }
 
+/// Resolves the path of the file `device` refers to.
+fn device_path(device: &impl DataTransport) -> Option<PathBuf> {
+    let handle = device.sync_handle().ok()?;
+    std::fs::read_link(format!("/proc/self/fd/{}", handle.as_raw_fd())).ok()
+}
+
+/// Runs `hooks` as shell commands, one after the other. The number of the
+/// node, e.g. "1", is passed in the `NODE` environment variable.
+async fn run_post_flash_hooks(node: NodeId, hooks: &[String]) -> HookStatus {
+    let mut status = HookStatus {
+        command: String::new(),
+        exit_code: Some(0),
+        success: true,
+    };
+    for hook in hooks {
+        tracing::info!("{node}: running post-flash hook `{hook}`");
+        let command = hook.clone();
+        let output = spawn_blocking(move || {
+            Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("NODE", (node as u8 + 1).to_string())
+                .output()
+        })
+        .await
+        .map_err(io::Error::from)
+        .and_then(|output| output);
+
+        status.command = hook.clone();
+        match output {
+            Ok(output) => {
+                if let Err(e) = logging_sink_stdio(&output).await {
+                    tracing::warn!("output of post-flash hook: {e}");
+                }
+                status.exit_code = output.status.code();
+                status.success = output.status.success();
+            }
+            Err(e) => {
+                tracing::error!("could not run post-flash hook `{hook}`: {e}");
+                status.exit_code = None;
+                status.success = false;
+            }
+        }
+        if !status.success {
+            break;
+        }
+    }
+    status
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
+    use crc::{Crc, CRC_64_REDIS};
     use rand::RngCore;
     use tokio::io::BufWriter;
 
@@ -243,23 +1386,423 @@ mod test {
         array
     }
 
+    #[tokio::test]
+    async fn device_path_resolves_the_written_file() {
+        let dir = tempdir::TempDir::new("device_path").unwrap();
+        let path = dir.path().join("disk.img");
+        let file = fs::File::create(&path).await.unwrap();
+        assert_eq!(device_path(&file), Some(path.canonicalize().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn post_flash_hooks_stop_at_the_first_failure() {
+        let hooks = [
+            "test \"$NODE\" = 2".to_string(),
+            "exit 3".to_string(),
+            "echo unreachable".to_string(),
+        ];
+        let status = run_post_flash_hooks(NodeId::Node2, &hooks).await;
+        assert_eq!(
+            status,
+            HookStatus {
+                command: "exit 3".to_string(),
+                exit_code: Some(3),
+                success: false,
+            }
+        );
+
+        let status = run_post_flash_hooks(NodeId::Node2, &hooks[..1]).await;
+        assert!(status.success);
+    }
+
     #[tokio::test]
     async fn crc_reader_test() {
         let crc = Crc::<u64>::new(&CRC_64_REDIS);
         let buffer = random_array::<{ 10024 * 1024 }>();
-        let expected_crc = crc.checksum(&buffer);
+        let expected_crc = crc.checksum(&buffer).to_be_bytes().to_vec();
 
         let mut buf_writer = BufWriter::new(Vec::new());
         let cursor = std::io::Cursor::new(&buffer);
 
-        let (mut sender, mut receiver) = watch::channel(0u64);
-        let mut write_watcher = WriteMonitor::new(&mut buf_writer, &mut sender, &crc);
-        copy_or_cancel(cursor, &mut write_watcher, &CancellationToken::new())
+        let (sender, mut receiver) = watch::channel(TransferProgress::default());
+        let mut write_watcher = WriteMonitor::new(&mut buf_writer, &sender, ChecksumAlgo::Crc64);
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        copy_or_cancel(
+            cursor,
+            &mut write_watcher,
+            BlockSize::default(),
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &sender,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(expected_crc, write_watcher.checksum());
+        assert_eq!(&buffer, buf_writer.get_ref());
+        assert_eq!(
+            receiver.borrow_and_update().bytes_written,
+            buffer.len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn checksum_is_independent_of_block_size() {
+        assert!(BlockSize::new(3 * BlockSize::MIN).is_err());
+        assert!(BlockSize::new(BlockSize::MAX * 2).is_err());
+
+        let buffer = random_array::<{ 100 * 1024 + 17 }>();
+        let mut checksums = Vec::new();
+        for size in [BlockSize::MIN, BlockSize::MAX] {
+            let (progress, receiver) = watch::channel(TransferProgress::default());
+            let (_pause, mut pause_receiver) = watch::channel(false);
+            let mut monitor = WriteMonitor::new(sink(), &progress, ChecksumAlgo::Crc64);
+            copy_or_cancel(
+                &buffer[..],
+                &mut monitor,
+                BlockSize::new(size).unwrap(),
+                &CancellationToken::new(),
+                &mut pause_receiver,
+                &progress,
+            )
             .await
             .unwrap();
+            assert_eq!(receiver.borrow().bytes_written, buffer.len() as u64);
+            checksums.push(monitor.checksum());
+        }
+        assert_eq!(checksums[0], checksums[1]);
+    }
 
-        assert_eq!(expected_crc, write_watcher.crc());
-        assert_eq!(&buffer, buf_writer.get_ref());
-        assert_eq!(*receiver.borrow_and_update(), buffer.len() as u64);
+    fn image_digest(image: &[u8], block_size: BlockSize) -> ImageDigest {
+        let mut checksum = Checksum::new(ChecksumAlgo::Crc64);
+        checksum.update(image);
+        ImageDigest {
+            algo: ChecksumAlgo::Crc64,
+            checksum: checksum.finalize(),
+            blocks: image
+                .chunks(block_size.get())
+                .map(crc32fast::hash)
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn verification_ignores_trailing_sectors() {
+        let image = random_array::<{ 100 * 1024 + 17 }>();
+        let mut device = image.clone();
+        device.extend(random_array::<4096>());
+        let digest = image_digest(&image, BlockSize::default());
+
+        let (progress, receiver) =
+            watch::channel(TransferProgress::new(image.len() as u64, vec![]));
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        verify_region(
+            &device[..],
+            image.len() as u64,
+            &digest,
+            BlockSize::default(),
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await
+        .unwrap();
+        assert_eq!(receiver.borrow().verified_percent, Some(100.0));
+
+        let short = verify_region(
+            &image[..1024],
+            image.len() as u64,
+            &digest,
+            BlockSize::default(),
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await;
+        assert!(short.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_ahead_does_not_change_verification() {
+        let block_size = BlockSize::new(BlockSize::MIN).unwrap();
+        let image = random_array::<{ 10 * BlockSize::MIN + 100 }>();
+        let digest = image_digest(&image, block_size);
+
+        let (progress, receiver) =
+            watch::channel(TransferProgress::new(image.len() as u64, vec![]));
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        verify_region(
+            BufReader::with_capacity(4 * BlockSize::MIN, &image[..]),
+            image.len() as u64,
+            &digest,
+            block_size,
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await
+        .unwrap();
+        assert_eq!(receiver.borrow().verified_percent, Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn verification_reports_first_divergent_block() {
+        let block_size = BlockSize::new(BlockSize::MIN).unwrap();
+        let image = random_array::<{ 10 * BlockSize::MIN }>();
+        let mut device = image.clone();
+        device[3 * BlockSize::MIN + 10] ^= 0xff;
+        device[7 * BlockSize::MIN] ^= 0xff;
+        let digest = image_digest(&image, block_size);
+
+        let (progress, receiver) =
+            watch::channel(TransferProgress::new(image.len() as u64, vec![]));
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        let error = verify_region(
+            &device[..],
+            image.len() as u64,
+            &digest,
+            block_size,
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await
+        .unwrap_err()
+        .downcast::<ChecksumMismatchError>()
+        .unwrap();
+
+        let offset = 3 * BlockSize::MIN as u64;
+        assert_eq!(error.first_divergence, Some(offset));
+        assert_eq!(receiver.borrow().first_divergence, Some(offset));
+        assert_eq!(receiver.borrow().verified_percent, Some(80.0));
+    }
+
+    #[tokio::test]
+    async fn copy_halts_while_paused() {
+        let (progress, receiver) = watch::channel(TransferProgress::default());
+        let (pause, mut pause_receiver) = watch::channel(true);
+        let cancel = CancellationToken::new();
+        let mut output = Vec::new();
+
+        {
+            let copy = copy_or_cancel(
+                &b"data"[..],
+                &mut output,
+                BlockSize::default(),
+                &cancel,
+                &mut pause_receiver,
+                &progress,
+            );
+            tokio::pin!(copy);
+
+            assert!(futures::poll!(copy.as_mut()).is_pending());
+            assert!(receiver.borrow().paused);
+
+            pause.send_replace(false);
+            assert_eq!(copy.await.unwrap(), 4);
+        }
+
+        assert!(!receiver.borrow().paused);
+        assert_eq!(output, b"data");
+    }
+
+    #[tokio::test]
+    async fn block_copy_skips_unchanged_blocks_and_reads_back() {
+        let image = random_array::<{ 3 * DEFAULT_BLOCK_SIZE }>();
+        let mut on_device = image.clone();
+        on_device[DEFAULT_BLOCK_SIZE + 7] ^= 0xff;
+        // the device is shorter than the image, the last block cannot be read
+        on_device.truncate(2 * DEFAULT_BLOCK_SIZE + 10);
+        let mut device = std::io::Cursor::new(on_device);
+
+        let (progress, _) = watch::channel(TransferProgress::default());
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        let mut monitor = WriteMonitor::new(sink(), &progress, ChecksumAlgo::Crc64);
+        let copy = BlockCopy {
+            block_size: BlockSize::default(),
+            offset: 0,
+            delta: true,
+            read_back: Some(ReadBack {
+                checksum: Checksum::new(ChecksumAlgo::Crc64),
+                handle: None,
+                verified: 0,
+                first_divergence: None,
+            }),
+        };
+        let (bytes, blocks, device_checksum) = copy_blocks(
+            &image[..],
+            &mut device,
+            &mut monitor,
+            copy,
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(bytes, image.len() as u64);
+        assert_eq!(
+            blocks,
+            BlockStats {
+                written: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(device.get_ref(), &image);
+        let device_checksum = device_checksum.unwrap();
+        assert_eq!(device_checksum.checksum, monitor.checksum());
+        assert_eq!(device_checksum.first_divergence, None);
+    }
+
+    #[tokio::test]
+    async fn block_copy_preserves_data_before_offset() {
+        let image = random_array::<{ 2 * DEFAULT_BLOCK_SIZE }>();
+        let boot = random_array::<4096>();
+        let mut device = std::io::Cursor::new(boot.clone());
+
+        let (progress, _) = watch::channel(TransferProgress::default());
+        let (_pause, mut pause_receiver) = watch::channel(false);
+        let mut monitor = WriteMonitor::new(sink(), &progress, ChecksumAlgo::Crc64);
+        let copy = BlockCopy {
+            block_size: BlockSize::default(),
+            offset: boot.len() as u64,
+            delta: false,
+            read_back: Some(ReadBack {
+                checksum: Checksum::new(ChecksumAlgo::Crc64),
+                handle: None,
+                verified: 0,
+                first_divergence: None,
+            }),
+        };
+        let (bytes, _, device_checksum) = copy_blocks(
+            &image[..],
+            &mut device,
+            &mut monitor,
+            copy,
+            &CancellationToken::new(),
+            &mut pause_receiver,
+            &progress,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(bytes, image.len() as u64);
+        assert_eq!(device.get_ref()[..boot.len()], boot);
+        assert_eq!(device.get_ref()[boot.len()..], image);
+        let device_checksum = device_checksum.unwrap();
+        assert_eq!(device_checksum.checksum, monitor.checksum());
+        assert_eq!(device_checksum.first_divergence, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulated_progress_reaches_total() {
+        let (sender, receiver) = watch::channel(TransferProgress::default());
+        let start = tokio::time::Instant::now();
+        simulate_progress(&sender, &CancellationToken::new(), 1000)
+            .await
+            .unwrap();
+
+        assert_eq!(start.elapsed(), DRY_RUN_PHASE_DURATION);
+        assert_eq!(receiver.borrow().bytes_written, 1000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_phase_times_out() {
+        let timeout = phase_timeout(1024 * 1024 * 1024);
+        assert_eq!(timeout, PHASE_TIMEOUT_BASE + Duration::from_secs(2048));
+
+        let (_pause, pause_receiver) = watch::channel(false);
+        let error = with_timeout(
+            TransferPhase::Writing,
+            timeout,
+            pause_receiver,
+            std::future::pending::<anyhow::Result<()>>(),
+        )
+        .await
+        .unwrap_err();
+        let error = error.downcast::<FlashTimeoutError>().unwrap();
+        assert_eq!(error.phase, TransferPhase::Writing);
+        assert_eq!(error.timeout, timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_time_does_not_count_towards_timeout() {
+        let timeout = Duration::from_secs(60);
+        let (pause, pause_receiver) = watch::channel(false);
+        let start = tokio::time::Instant::now();
+        let phase = with_timeout(TransferPhase::Writing, timeout, pause_receiver, async {
+            tokio::time::sleep(Duration::from_secs(100)).await;
+            Ok(())
+        });
+        let pauses = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            pause.send_replace(true);
+            tokio::time::sleep(Duration::from_secs(600)).await;
+            pause.send_replace(false);
+        };
+
+        let (result, ()) = tokio::join!(phase, pauses);
+        result.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(630));
+
+        // the 60s of activity still time out, with a pause in between
+        let (pause, pause_receiver) = watch::channel(false);
+        let phase = with_timeout(
+            TransferPhase::Writing,
+            timeout,
+            pause_receiver,
+            std::future::pending::<anyhow::Result<()>>(),
+        );
+        let pauses = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            pause.send_replace(true);
+            tokio::time::sleep(Duration::from_secs(600)).await;
+            pause.send_replace(false);
+            std::future::pending::<()>().await;
+        };
+        let start = tokio::time::Instant::now();
+        tokio::select! {
+            result = phase => assert!(result.is_err()),
+            _ = pauses => unreachable!(),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(660));
+    }
+
+    #[test]
+    fn summary_reports_verify_mode() {
+        assert_eq!(VerifyMode::Inline.effective(false), VerifyMode::None);
+        assert_eq!(VerifyMode::Inline.effective(true), VerifyMode::Inline);
+
+        let summary = FlashSummary {
+            bytes: 1000,
+            algorithm: ChecksumAlgo::Crc64,
+            checksum: vec![0xab],
+            verified: true,
+            verify_mode: VerifyMode::Inline,
+            verified_bytes: 1000,
+            module: None,
+            blocks: None,
+            powered_on: false,
+            hooks: None,
+            manifest: None,
+            dry_run: false,
+        };
+        assert!(summary.to_string().contains("verified while writing"));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["verify_mode"], "inline");
+        assert_eq!(json["verified_bytes"], 1000);
+        assert_eq!(json["dry_run"], false);
+
+        let dry_run = FlashSummary {
+            verified: false,
+            verified_bytes: 0,
+            dry_run: true,
+            ..summary
+        };
+        assert_eq!(
+            dry_run.to_string(),
+            "dry run of 1 kB (Crc64 ab, not verified)"
+        );
     }
 }
@@ -21,6 +21,7 @@ use std::time::Duration;
 
 const DEFAULT_YAML: &str = include_str!("../../default_config.yaml");
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub tls: Tls,
@@ -30,7 +31,27 @@ pub struct Config {
     pub port: u16,
     pub www: PathBuf,
     pub redirect_http: bool,
+    /// Reconcile the power state with the enable pins once the BMC is
+    /// initialized, see [`crate::app::bmc_application::BmcApplication::resync_power_state`].
+    #[serde(default)]
+    pub resync_power_on_start: bool,
+    /// Refuse to power on nodes while the power supply does not report
+    /// power-good, see
+    /// [`crate::app::bmc_application::BmcApplication::supply_power_good`].
+    #[serde(default)]
+    pub require_power_good: bool,
+    /// Time between powering nodes of different boot priorities, see
+    /// [`crate::app::bmc_application::BmcApplication::set_node_priority`].
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub power_on_gap: Duration,
     pub log: Log,
+    #[serde(default)]
+    pub leds: Leds,
+    pub flash: Flash,
+    #[serde(default)]
+    pub power_sensors: PowerSensors,
+    #[serde(default)]
+    pub input: Input,
 }
 
 #[serde_as]
@@ -54,6 +75,62 @@ pub struct Tls {
     pub certificate: PathBuf,
 }
 
+/// sysfs brightness files of the front-panel LEDs. LEDs which are not
+/// configured fall back to the LEDs present on the board.
+#[derive(Debug, Default, Deserialize)]
+pub struct Leds {
+    pub power: Option<PathBuf>,
+    pub status: Option<PathBuf>,
+}
+
+/// Input device of the front-panel buttons, see
+/// [`find_input_device`](crate::utils::find_input_device) for the accepted
+/// forms. Defaults to the device the board enumerates first.
+#[derive(Debug, Default, Deserialize)]
+pub struct Input {
+    pub device: Option<String>,
+}
+
+/// hwmon directories of the current sensors of the nodes, on carrier boards
+/// that have them.
+#[derive(Debug, Default, Deserialize)]
+pub struct PowerSensors {
+    pub node1: Option<PathBuf>,
+    pub node2: Option<PathBuf>,
+    pub node3: Option<PathBuf>,
+    pub node4: Option<PathBuf>,
+}
+
+impl PowerSensors {
+    pub fn paths(&self) -> [Option<PathBuf>; 4] {
+        [
+            self.node1.clone(),
+            self.node2.clone(),
+            self.node3.clone(),
+            self.node4.clone(),
+        ]
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Flash {
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub device_timeout: Duration,
+    /// Shell commands that are run after a flash that requested them, see
+    /// [`crate::app::upgrade_worker::FlashOptions::run_hooks`].
+    #[serde(default)]
+    pub post_flash_hooks: Vec<String>,
+    /// Directory of the images kept on the BMC, see
+    /// [`crate::app::image_library::ImageLibrary`].
+    #[serde(default)]
+    pub image_dir: Option<PathBuf>,
+    /// Directory that flash manifests are written to, see
+    /// [`crate::app::upgrade_worker::FlashOptions::manifest_file`].
+    #[serde(default)]
+    pub manifest_dir: Option<PathBuf>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Log {
     pub stdout: bool,
@@ -11,7 +11,12 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+pub mod board_revision;
 pub mod helpers;
+pub mod led;
+pub mod node_bits;
+pub mod power_sensor;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 
 macro_rules! conditional_import {
@@ -38,6 +43,11 @@ conditional_import! {
     pub use stub::*;
 }
 
+/// Amount of node slots on the board.
+pub const NODE_COUNT: u8 = 4;
+/// Bitfield with the bit of every node set, see [`NodeId::to_bitfield`].
+pub const ALL_NODES: u8 = (1 << NODE_COUNT) - 1;
+
 #[repr(C)]
 #[derive(Debug, Eq, Hash, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum NodeId {
@@ -65,17 +75,34 @@ impl TryFrom<i32> for NodeId {
     type Error = String;
 
     fn try_from(value: i32) -> Result<Self, Self::Error> {
-        (value as u8).try_into()
+        u8::try_from(value)
+            .map_err(|_| format!("node id {} does not exist", value))?
+            .try_into()
+    }
+}
+
+impl TryFrom<usize> for NodeId {
+    type Error = String;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        u8::try_from(value)
+            .map_err(|_| format!("node id {} does not exist", value))?
+            .try_into()
     }
 }
 
 impl NodeId {
+    /// Iterates over all nodes, in ascending order.
+    pub fn all() -> impl Iterator<Item = NodeId> {
+        (0..NODE_COUNT).filter_map(|n| NodeId::try_from(n).ok())
+    }
+
     pub fn to_bitfield(self) -> u8 {
         1 << self as u8
     }
 
     pub fn to_inverse_bitfield(self) -> u8 {
-        0b1111 & !(1 << self as u8)
+        ALL_NODES & !self.to_bitfield()
     }
 }
 
@@ -85,6 +112,7 @@ impl Display for NodeId {
     }
 }
 
+#[allow(unused)]
 #[repr(C)]
 #[derive(Debug, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum NodeType {
@@ -93,9 +121,14 @@ pub enum NodeType {
     RK1,
 }
 
+/// Where the USB bus of the selected node is connected to. The board has no
+/// route to an external OTG port: a host PC reaches a node only through the
+/// BMC, see [`crate::app::bmc_application::BmcApplication::node_in_msd`].
 #[derive(Debug, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum UsbRoute {
+    /// The USB controller of the BMC.
     Bmc,
+    /// The USB-A port of the board.
     AlternativePort,
 }
 
@@ -116,3 +149,90 @@ impl UsbMode {
         }
     }
 }
+
+/// The USB mode of each node, encoded as the levels of the otg-dev pins of
+/// the nodes: a set bit (see [`NodeId::to_bitfield`]) puts that node in
+/// device mode, a cleared bit in host mode. [`UsbMode::Flash`] is a device
+/// mode as well, and reads back as [`UsbMode::Device`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UsbModeBits(u8);
+
+impl UsbModeBits {
+    /// Every node in `mode`.
+    pub fn all(mode: UsbMode) -> Self {
+        let mut bits = Self::default();
+        NodeId::all().for_each(|node| bits.set(node, mode));
+        bits
+    }
+
+    pub fn get(self, node: NodeId) -> UsbMode {
+        if self.0 & node.to_bitfield() == 0 {
+            UsbMode::Host
+        } else {
+            UsbMode::Device
+        }
+    }
+
+    pub fn set(&mut self, node: NodeId, mode: UsbMode) {
+        match mode {
+            UsbMode::Host => self.0 &= node.to_inverse_bitfield(),
+            UsbMode::Device | UsbMode::Flash => self.0 |= node.to_bitfield(),
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl Display for UsbModeBits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let modes = NodeId::all()
+            .map(|node| format!("{node}: {:?}", self.get(node)))
+            .collect::<Vec<_>>();
+        f.write_str(&modes.join(", "))
+    }
+}
+
+/// Levels of the GPIO pins driven or read by the BMC, keyed by pin name.
+/// Intended for diagnostics, see [`PinController::dump_state`].
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct GpioSnapshot {
+    pub pins: BTreeMap<String, bool>,
+}
+
+impl GpioSnapshot {
+    pub fn add<const N: usize>(&mut self, names: [&str; N], values: [bool; N]) {
+        self.pins
+            .extend(names.into_iter().map(str::to_string).zip(values));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn node_id_conversions_are_bounds_checked() {
+        assert_eq!(NodeId::try_from(3u8), Ok(NodeId::Node4));
+        assert!(NodeId::try_from(4u8).is_err());
+        assert!(NodeId::try_from(-1i32).is_err());
+        // used to wrap around to node 1.
+        assert!(NodeId::try_from(256i32).is_err());
+        assert!(NodeId::try_from(256usize).is_err());
+    }
+
+    #[test]
+    fn usb_mode_bits_are_per_node() {
+        let mut modes = UsbModeBits::default();
+        assert_eq!(modes.get(NodeId::Node3), UsbMode::Host);
+
+        modes.set(NodeId::Node3, UsbMode::Flash);
+        assert_eq!(modes.bits(), 0b0100);
+        assert_eq!(modes.get(NodeId::Node3), UsbMode::Device);
+
+        let mut modes = UsbModeBits::all(UsbMode::Device);
+        modes.set(NodeId::Node2, UsbMode::Host);
+        assert_eq!(modes.bits(), NodeId::Node2.to_inverse_bitfield());
+    }
+}
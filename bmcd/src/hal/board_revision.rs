@@ -0,0 +1,149 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Detection of the hardware revision of the board.
+use board_info::{BoardInfo, BoardInfoAttribute};
+use serde::Serialize;
+use std::fmt::Display;
+use thiserror::Error;
+use tracing::warn;
+
+const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+
+/// Revisions of the board that differ in their GPIO layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BoardRevision {
+    #[serde(rename = "v2.4")]
+    V2_4,
+    /// v2.5 and its minor follow-ups.
+    #[serde(rename = "v2.5")]
+    V2_5,
+    /// A board whose revision could not be detected. It is driven with the
+    /// GPIO layout of v2.5, but features that depend on the exact wiring are
+    /// not available.
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[derive(Debug, Error)]
+#[error("unknown board revision (model: '{model}', EEPROM: {eeprom:?})")]
+pub struct UnknownBoardError {
+    pub model: String,
+    pub eeprom: Option<String>,
+}
+
+impl BoardRevision {
+    /// Reads the revision from the model in the device-tree, falling back to
+    /// the hardware version in the EEPROM. Unknown hardware is reported as
+    /// [`BoardRevision::Unknown`] with a warning.
+    pub fn detect() -> Self {
+        Self::try_detect().unwrap_or_else(|e| {
+            warn!("{}, assuming the GPIO layout of {}", e, BoardRevision::V2_5);
+            BoardRevision::Unknown
+        })
+    }
+
+    fn try_detect() -> Result<Self, UnknownBoardError> {
+        let model = std::fs::read_to_string(DEVICE_TREE_MODEL)
+            .unwrap_or_default()
+            .trim_end_matches('\0')
+            .to_string();
+        if let Some(revision) = Self::parse(&model) {
+            return Ok(revision);
+        }
+
+        let eeprom = BoardInfo::load()
+            .map(|info| info.value_of(&BoardInfoAttribute::HwVersion))
+            .ok();
+        eeprom
+            .as_deref()
+            .and_then(Self::parse)
+            .ok_or(UnknownBoardError { model, eeprom })
+    }
+
+    /// Parses the first "vMAJOR.MINOR" version in `text`.
+    fn parse(text: &str) -> Option<Self> {
+        text.split_whitespace().find_map(|word| {
+            let mut numbers = word.strip_prefix('v')?.split('.');
+            let major: u32 = numbers.next()?.parse().ok()?;
+            let minor: u32 = numbers.next()?.parse().ok()?;
+            match (major, minor) {
+                (2, 4) => Some(BoardRevision::V2_4),
+                (2, 5..) => Some(BoardRevision::V2_5),
+                _ => None,
+            }
+        })
+    }
+
+    /// v2.4 boards route the USB of the nodes through a mux switch, later
+    /// revisions through a USB hub.
+    pub fn has_usb_switch(self) -> bool {
+        self == BoardRevision::V2_4
+    }
+
+    /// v2.4 boards use latching power switches on a different GPIO chip.
+    pub fn has_latching_power(self) -> bool {
+        self == BoardRevision::V2_4
+    }
+
+    /// Whether the power lines of the nodes are known, so that a node can be
+    /// reset by power cycling it.
+    pub fn has_node_reset(self) -> bool {
+        match self {
+            BoardRevision::V2_4 | BoardRevision::V2_5 => true,
+            BoardRevision::Unknown => false,
+        }
+    }
+}
+
+impl Display for BoardRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoardRevision::V2_4 => f.write_str("v2.4"),
+            BoardRevision::V2_5 => f.write_str("v2.5"),
+            BoardRevision::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn revision_is_parsed_from_version() {
+        assert_eq!(
+            BoardRevision::parse("Turing Machines Turing Pi 2 v2.4"),
+            Some(BoardRevision::V2_4)
+        );
+        assert_eq!(BoardRevision::parse("v2.5.2"), Some(BoardRevision::V2_5));
+        assert_eq!(BoardRevision::parse("Turing Pi 2"), None);
+        assert_eq!(BoardRevision::parse("v3.0.0"), None);
+    }
+
+    #[test]
+    fn unknown_boards_use_v2_5_layout_without_reset() {
+        let unknown = BoardRevision::Unknown;
+        assert_eq!(
+            unknown.has_usb_switch(),
+            BoardRevision::V2_5.has_usb_switch()
+        );
+        assert_eq!(
+            unknown.has_latching_power(),
+            BoardRevision::V2_5.has_latching_power()
+        );
+        assert!(!unknown.has_node_reset());
+        assert!(BoardRevision::V2_4.has_node_reset());
+        assert!(BoardRevision::V2_5.has_node_reset());
+    }
+}
@@ -16,7 +16,6 @@ pub const GPIO_PIN_PG: u32 = 192;
 
 #[allow(unused)]
 pub const SYS_RESET: u32 = GPIO_PIN_PG + 11;
-#[allow(unused)]
 pub const POWER_DETECT: u32 = GPIO_PIN_PG + 10;
 #[allow(unused)]
 pub const POWER_BOARD: u32 = GPIO_PIN_PG + 15;
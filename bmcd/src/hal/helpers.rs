@@ -11,8 +11,8 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use super::NODE_COUNT;
 use std::collections::HashMap;
-const NODE_COUNT: u8 = 4;
 
 /// small helper macro which handles the code duplication of declaring gpio lines.
 #[macro_export]
@@ -43,8 +43,8 @@ macro_rules! gpio_output_array {
 /// # Arguments
 ///
 /// * `node_states`     bit-field where each bit represents a node on the
-///     turing-pi board, if bit(n) = 1 equals 'select' and bit(n) = 0 equals
-///     'unselect'.
+///   turing-pi board, if bit(n) = 1 equals 'select' and bit(n) = 0 equals
+///   'unselect'.
 /// * `node_mask`       mask which bits to select.
 ///
 /// # Returns
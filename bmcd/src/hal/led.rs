@@ -0,0 +1,158 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Control of the front-panel LEDs via the Linux LED subsystem.
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const SYS_LED: &str = "/sys/class/leds/fp::power/brightness";
+const SYS_LED_2_0_5: &str = "/sys/class/leds/fp:sys/brightness";
+const STATUS_LED: &str = "/sys/class/leds/fp::status/brightness";
+const STATUS_LED_2_0_5: &str = "/sys/class/leds/fp:reset/brightness";
+/// On and off time of a single pulse of [`LedController::blink_code`].
+const CODE_PULSE: Duration = Duration::from_millis(200);
+/// Pause between the repetitions of a code.
+const CODE_PAUSE: Duration = Duration::from_millis(1200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Led {
+    Power,
+    Status,
+}
+
+/// Drives the LEDs on the front-panel of the board. Each LED can either be
+/// set to a static on/off state, or blink with a given interval. A blinking
+/// LED is driven by a spawned task, which gets stopped on the next
+/// [`LedController::set_led`] call for that LED.
+///
+/// The LEDs are cosmetic, failing to drive them is logged but never reported
+/// to the caller.
+pub struct LedController {
+    power: PathBuf,
+    status: PathBuf,
+    blinking: Mutex<HashMap<Led, JoinHandle<()>>>,
+}
+
+impl LedController {
+    /// Creates a new controller. LEDs that are not given a path fall back to
+    /// the LEDs present on the board.
+    pub fn new(power: Option<PathBuf>, status: Option<PathBuf>) -> Self {
+        Self {
+            power: power.unwrap_or_else(|| fallback_if_not_exist(SYS_LED, SYS_LED_2_0_5)),
+            status: status.unwrap_or_else(|| fallback_if_not_exist(STATUS_LED, STATUS_LED_2_0_5)),
+            blinking: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn set_led(&self, led: Led, on: bool) {
+        self.stop_blinking(led);
+        if let Err(e) = write_brightness(self.path(led), on).await {
+            tracing::warn!("{:?} LED: {:#}", led, e);
+        }
+    }
+
+    /// Toggles the given LED every `interval` until the next call to
+    /// [`Self::set_led`].
+    pub fn blink(&self, led: Led, interval: Duration) {
+        let path = self.path(led).to_path_buf();
+        let handle = tokio::spawn(async move {
+            let mut on = true;
+            loop {
+                if let Err(e) = write_brightness(&path, on).await {
+                    tracing::warn!("stopped blinking {:?} LED: {:#}", led, e);
+                    break;
+                }
+                on = !on;
+                tokio::time::sleep(interval).await;
+            }
+        });
+        self.replace_blinking(led, handle);
+    }
+
+    /// Blinks `pulses` times in quick succession followed by a pause, repeated
+    /// for `duration`. The LED is switched off afterwards. Like
+    /// [`Self::blink`], the code stops on the next call to [`Self::set_led`].
+    pub fn blink_code(&self, led: Led, pulses: u8, duration: Duration) {
+        let path = self.path(led).to_path_buf();
+        let handle = tokio::spawn(async move {
+            let code = async {
+                loop {
+                    for _ in 0..pulses {
+                        write_brightness(&path, true).await?;
+                        tokio::time::sleep(CODE_PULSE).await;
+                        write_brightness(&path, false).await?;
+                        tokio::time::sleep(CODE_PULSE).await;
+                    }
+                    tokio::time::sleep(CODE_PAUSE).await;
+                }
+            };
+
+            let result: anyhow::Result<()> = match tokio::time::timeout(duration, code).await {
+                Ok(result) => result,
+                Err(_) => write_brightness(&path, false).await,
+            };
+            if let Err(e) = result {
+                tracing::warn!("stopped blinking {:?} LED: {:#}", led, e);
+            }
+        });
+        self.replace_blinking(led, handle);
+    }
+
+    fn replace_blinking(&self, led: Led, handle: JoinHandle<()>) {
+        if let Some(previous) = self
+            .blinking
+            .lock()
+            .expect("blink lock poisoned")
+            .insert(led, handle)
+        {
+            previous.abort();
+        }
+    }
+
+    fn stop_blinking(&self, led: Led) {
+        if let Some(handle) = self
+            .blinking
+            .lock()
+            .expect("blink lock poisoned")
+            .remove(&led)
+        {
+            handle.abort();
+        }
+    }
+
+    fn path(&self, led: Led) -> &Path {
+        match led {
+            Led::Power => &self.power,
+            Led::Status => &self.status,
+        }
+    }
+}
+
+async fn write_brightness(path: &Path, on: bool) -> anyhow::Result<()> {
+    tokio::fs::write(path, if on { "1" } else { "0" })
+        .await
+        .with_context(|| path.to_string_lossy().to_string())
+}
+
+fn fallback_if_not_exist(sysfs: &str, fallback: &str) -> PathBuf {
+    let mut sysfs = PathBuf::from(sysfs);
+    if !sysfs.exists() {
+        sysfs = PathBuf::from(fallback);
+        tracing::info!("led: falling back to {}", fallback);
+    }
+    sysfs
+}
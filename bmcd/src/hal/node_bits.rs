@@ -0,0 +1,90 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Power logic on node bitfields, independent of the amount of nodes. The
+//! board has [`NODE_COUNT`](super::NODE_COUNT) nodes in `u8` bitfields.
+use std::fmt::Debug;
+use std::ops::{BitAnd, BitOr, Not};
+
+/// Integer that holds one bit per node, bit `n` belongs to node `n`.
+pub trait NodeBits:
+    Copy + Eq + Debug + BitAnd<Output = Self> + BitOr<Output = Self> + Not<Output = Self>
+{
+    const NONE: Self;
+
+    fn bit(node: u32) -> Self;
+    fn count_ones(self) -> u32;
+}
+
+impl NodeBits for u8 {
+    const NONE: Self = 0;
+
+    fn bit(node: u32) -> Self {
+        1 << node
+    }
+
+    fn count_ones(self) -> u32 {
+        u8::count_ones(self)
+    }
+}
+
+/// Iterates over the nodes set in `bits`, in ascending order.
+pub fn set_nodes<B: NodeBits>(bits: B, count: u32) -> impl Iterator<Item = u32> {
+    (0..count).filter(move |n| bits & B::bit(*n) != B::NONE)
+}
+
+/// Applies `node_states` to the nodes in `mask`, the other nodes of `state`
+/// are left as they are.
+pub fn next_state<B: NodeBits>(state: B, node_states: B, mask: B) -> B {
+    (state & !mask) | (node_states & mask)
+}
+
+/// Keeps the first `limit` nodes of `node_states`, in ascending order.
+pub fn limit_nodes<B: NodeBits>(node_states: B, count: u32, limit: u32) -> B {
+    set_nodes(node_states, count)
+        .take(limit as usize)
+        .fold(B::NONE, |acc, n| acc | B::bit(n))
+}
+
+/// Returns the nodes that exceed `limit` in the transition from `state` to
+/// `new_state`. Nodes that are already powered take precedence, newly powered
+/// nodes are granted in order until the limit is reached.
+pub fn denied_nodes<B: NodeBits>(state: B, new_state: B, count: u32, limit: u32) -> Vec<u32> {
+    let staying_on = (state & new_state).count_ones();
+    set_nodes(new_state & !state, count)
+        .skip(limit.saturating_sub(staying_on) as usize)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_state_only_changes_masked_nodes() {
+        assert_eq!(next_state(0b0011u8, 0b0100, 0b0110), 0b0101);
+        assert_eq!(next_state(0b1111u8, 0b0000, 0b0000), 0b1111);
+    }
+
+    #[test]
+    fn power_logic_handles_all_eight_bits() {
+        let all = 0xFFu8;
+        assert_eq!(
+            next_state(0b0000_1111u8, 0b1111_0000, 0b1010_1010),
+            0b1010_0101
+        );
+        assert_eq!(limit_nodes(all, 8, 5), 0b0001_1111);
+        assert_eq!(denied_nodes(0b1000_0000u8, all, 8, 3), vec![2, 3, 4, 5, 6]);
+        assert!(denied_nodes(0u8, 0b1100_0000, 8, 2).is_empty());
+    }
+}
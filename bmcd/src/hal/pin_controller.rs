@@ -16,15 +16,19 @@ use super::helpers::load_lines;
 use crate::gpio_output_array;
 use crate::gpio_output_lines;
 
+use super::board_revision::BoardRevision;
 use super::gpio_definitions::*;
+use super::GpioSnapshot;
 use super::NodeId;
+use super::ALL_NODES;
 use super::UsbMode;
+use super::UsbModeBits;
 use super::UsbRoute;
 use anyhow::Context;
-use gpiod::{Chip, Lines, Output};
+use gpiod::{Chip, Input, Lines, Output};
 use std::fmt::Display;
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, warn};
 
 const USB_PORT_POWER: &str = "/sys/bus/platform/devices/usb-port-power/state";
 
@@ -98,11 +102,13 @@ const NODE4_RPIBOOT: &str = "node4-rpiboot";
 pub struct PinController {
     usb_switch: Box<dyn UsbConfiguration + Sync + Send>,
     rpi_boot: [Lines<Output>; 4],
+    power_detect: Option<Lines<Input>>,
 }
 
 impl PinController {
-    /// create a new Pin controller
-    pub fn new(has_usb_switch: bool) -> anyhow::Result<Self> {
+    /// create a new Pin controller for the GPIO layout of `revision`
+    pub fn new(revision: BoardRevision) -> anyhow::Result<Self> {
+        let has_usb_switch = revision.has_usb_switch();
         let chip1 = if has_usb_switch {
             "/dev/gpiochip1"
         } else {
@@ -127,6 +133,10 @@ impl PinController {
             .ok_or(anyhow::anyhow!("cannot find node4-rpiboot gpio"))?;
 
         let rpi_boot = gpio_output_array!(chip1, rpi1, rpi2, rpi3, rpi4);
+        let power_detect = chip0
+            .request_lines(gpiod::Options::input([POWER_DETECT]))
+            .map_err(|e| warn!("power-good detection not available: {}", e))
+            .ok();
 
         let usb_switch = if has_usb_switch {
             Box::new(UsbMuxSwitch::new(&chip0, &chip1)?) as Box<dyn UsbConfiguration + Send + Sync>
@@ -137,6 +147,7 @@ impl PinController {
         Ok(Self {
             usb_switch,
             rpi_boot,
+            power_detect,
         })
     }
 
@@ -148,7 +159,7 @@ impl PinController {
         if UsbMode::Flash == mode {
             self.set_usb_boot(node.to_bitfield(), node.to_bitfield())?;
         } else {
-            self.set_usb_boot(0, 0b1111)?;
+            self.set_usb_boot(0, ALL_NODES)?;
         }
 
         Ok(())
@@ -181,6 +192,43 @@ impl PinController {
         Ok(())
     }
 
+    /// Returns a bitfield of the nodes that have their usb boot pin asserted.
+    pub fn usb_boot_state(&self) -> Result<u8, PowerControllerError> {
+        let mut state = 0u8;
+        for (idx, line) in self.rpi_boot.iter().enumerate() {
+            let [value] = line.get_values([false; 1])?;
+            state |= u8::from(value) << idx;
+        }
+        Ok(state)
+    }
+
+    /// Reads the power-good signal of the power supply. Returns `None` when
+    /// the signal is not available on this board.
+    pub fn power_good(&self) -> Result<Option<bool>, PowerControllerError> {
+        let Some(power_detect) = &self.power_detect else {
+            return Ok(None);
+        };
+
+        let [value] = power_detect.get_values([false; 1])?;
+        Ok(Some(value))
+    }
+
+    /// Reads the current level of every pin this controller manages.
+    pub fn dump_state(&self) -> Result<GpioSnapshot, PowerControllerError> {
+        let mut snapshot = GpioSnapshot::default();
+        let names = [NODE1_RPIBOOT, NODE2_RPIBOOT, NODE3_RPIBOOT, NODE4_RPIBOOT];
+        for (name, line) in names.into_iter().zip(&self.rpi_boot) {
+            snapshot.add([name], line.get_values([false; 1])?);
+        }
+
+        if let Some(power_detect) = &self.power_detect {
+            snapshot.add(["power-detect"], power_detect.get_values([false; 1])?);
+        }
+
+        self.usb_switch.dump_state(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
     pub fn set_node1_usb_route(&self, alternative_port: bool) -> Result<(), PowerControllerError> {
         debug!("setting alternative port for Node 1 USB");
         self.usb_switch.set_node1_usb_route(alternative_port)
@@ -196,6 +244,7 @@ trait UsbConfiguration {
     fn set_usb_route(&self, route: UsbRoute) -> Result<(), PowerControllerError>;
     fn set_node1_usb_route(&self, alternative_port: bool) -> Result<(), PowerControllerError>;
     fn configure_usb(&self, node: NodeId, mode: UsbMode) -> Result<(), PowerControllerError>;
+    fn dump_state(&self, snapshot: &mut GpioSnapshot) -> Result<(), PowerControllerError>;
 }
 
 #[derive(Debug, PartialEq)]
@@ -275,17 +324,35 @@ impl UsbConfiguration for UsbMuxSwitch {
             NodeId::Node4 => 0b0111,
         };
         self.usb_mux.set_values(values)?;
-        let vbus = match mode {
-            UsbMode::Host => node.to_inverse_bitfield(),
-            UsbMode::Device | UsbMode::Flash => 0b1111,
-        };
-        self.usb_vbus.set_values(vbus)?;
+        // nodes that are not connected to the bus stay in device mode.
+        let mut modes = UsbModeBits::all(UsbMode::Device);
+        modes.set(node, mode);
+        debug!("usb modes {}", modes);
+        self.usb_vbus.set_values(modes.bits())?;
         Ok(())
     }
 
     fn set_node1_usb_route(&self, _alternative_port: bool) -> Result<(), PowerControllerError> {
         Err(PowerControllerError::Node1UsbNotApplicable)
     }
+
+    fn dump_state(&self, snapshot: &mut GpioSnapshot) -> Result<(), PowerControllerError> {
+        snapshot.add(
+            ["usb-sel1", "usb-oe1", "usb-sel2", "usb-oe2"],
+            self.usb_mux.get_values([false; 4])?,
+        );
+        snapshot.add(
+            [
+                NODE1_USBOTG_DEV,
+                NODE2_USBOTG_DEV,
+                NODE3_USBOTG_DEV,
+                NODE4_USBOTG_DEV,
+            ],
+            self.usb_vbus.get_values([false; 4])?,
+        );
+        snapshot.add(["usb-switch"], self.output_switch.get_values([false; 1])?);
+        Ok(())
+    }
 }
 
 struct UsbHub {
@@ -333,6 +400,15 @@ impl UsbConfiguration for UsbHub {
         let value = if alternative_port { 0b11 } else { 0u8 };
         Ok(self.node1_source.set_values(value)?)
     }
+
+    fn dump_state(&self, snapshot: &mut GpioSnapshot) -> Result<(), PowerControllerError> {
+        snapshot.add(
+            ["node1-output-switch", "node1-source-switch"],
+            self.node1_source.get_values([false; 2])?,
+        );
+        snapshot.add(["usb-switch"], self.output_switch.get_values([false; 1])?);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Error)]
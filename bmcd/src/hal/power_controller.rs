@@ -12,37 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use super::{
+    board_revision::BoardRevision,
     helpers::{bit_iterator, load_lines},
-    NodeId,
+    GpioSnapshot, NodeId,
 };
 use crate::gpio_output_array;
 use anyhow::Context;
 use gpiod::{Chip, Lines, Output};
-use std::path::PathBuf;
-use std::{str::FromStr, time::Duration};
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::time::sleep;
 use tracing::{debug, trace};
 
-const SYS_LED: &str = "/sys/class/leds/fp::power/brightness";
-const SYS_LED_2_0_5: &str = "/sys/class/leds/fp:sys/brightness";
-const STATUS_LED: &str = "/sys/class/leds/fp::status/brightness";
-const STATUS_LED_2_0_5: &str = "/sys/class/leds/fp:reset/brightness";
 const PORT1_EN: &str = "node1-en";
 const PORT2_EN: &str = "node2-en";
 const PORT3_EN: &str = "node3-en";
 const PORT4_EN: &str = "node4-en";
+/// Time between updating the power state in sysfs and driving the enable pin.
+const MODE_SWITCH_DELAY: Duration = Duration::from_millis(100);
+/// Time a node stays powered off during [`PowerController::reset_node`].
+const RESET_DELAY: Duration = Duration::from_secs(1);
+
+/// Returned when changing the power of the nodes failed partway.
+#[derive(Debug, Error)]
+#[error("changing power of node {} failed", .failed + 1)]
+pub struct PartialPowerError {
+    /// Bitfield of the nodes whose power was changed before the failure.
+    pub applied: u8,
+    /// Index of the node that failed.
+    pub failed: usize,
+    #[source]
+    pub source: anyhow::Error,
+}
 
 // This structure is a thin layer that abstracts away the interaction details
 // with Linux's power subsystem.
 pub struct PowerController {
     enable: [Lines<Output>; 4],
-    sysfs_power: PathBuf,
-    sysfs_reset: PathBuf,
 }
 
 impl PowerController {
-    pub fn new(is_latching_system: bool) -> anyhow::Result<Self> {
-        let chip1 = if is_latching_system {
+    pub fn new(revision: BoardRevision) -> anyhow::Result<Self> {
+        let chip1 = if revision.has_latching_power() {
             "/dev/gpiochip1"
         } else {
             "/dev/gpiochip2"
@@ -65,14 +77,7 @@ impl PowerController {
 
         let enable = gpio_output_array!(chip1, port1, port2, port3, port4);
 
-        let sysfs_power = fallback_if_not_exist(SYS_LED, SYS_LED_2_0_5);
-        let sysfs_reset = fallback_if_not_exist(STATUS_LED, STATUS_LED_2_0_5);
-
-        Ok(PowerController {
-            enable,
-            sysfs_power,
-            sysfs_reset,
-        })
+        Ok(PowerController { enable })
     }
 
     /// Function to power on/off given nodes. Powering of the nodes is controlled by
@@ -81,25 +86,49 @@ impl PowerController {
     /// # Arguments
     ///
     /// * `node_states`     bit-field representing the nodes on the turing-pi board,
-    ///     where bit 1 is on and 0 equals off.
+    ///   where bit 1 is on and 0 equals off.
     /// * `node_mask`       bit-field to describe which nodes to control.
     ///
     /// # Returns
     ///
     /// * `Ok(())` when routine was executed successfully.
-    /// * `Err(io error)` in the case there was a failure to write to the Linux
-    ///     subsystem that handles the node powering.
-    pub async fn set_power_node(&self, node_states: u8, node_mask: u8) -> anyhow::Result<()> {
-        let updates = bit_iterator(node_states, node_mask);
-
-        for (idx, state) in updates {
+    /// * `Err(PartialPowerError)` in the case there was a failure to write to
+    ///   the Linux subsystem that handles the node powering. The nodes before
+    ///   the failing node are already switched.
+    pub async fn set_power_node(
+        &self,
+        node_states: u8,
+        node_mask: u8,
+    ) -> Result<(), PartialPowerError> {
+        let enable = &self.enable;
+        apply_power(node_states, node_mask, |idx, state| async move {
             trace!("setting power of node {}. state:{}", idx + 1, state);
             set_mode(idx + 1, state).await?;
-            sleep(Duration::from_millis(100)).await;
-            self.enable[idx].set_values(state)?;
+            sleep(MODE_SWITCH_DELAY).await;
+            enable[idx].set_values(state)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns a bitfield of the nodes whose enable pin is driven high.
+    pub fn power_state(&self) -> anyhow::Result<u8> {
+        let mut state = 0u8;
+        for (idx, line) in self.enable.iter().enumerate() {
+            let [value] = line.get_values([false; 1])?;
+            state |= u8::from(value) << idx;
         }
+        Ok(state)
+    }
 
-        Ok(())
+    /// Reads the level of the enable pin of each node.
+    pub fn dump_state(&self) -> anyhow::Result<GpioSnapshot> {
+        let mut snapshot = GpioSnapshot::default();
+        let names = [PORT1_EN, PORT2_EN, PORT3_EN, PORT4_EN];
+        for (name, line) in names.into_iter().zip(&self.enable) {
+            snapshot.add([name], line.get_values([false; 1])?);
+        }
+        Ok(snapshot)
     }
 
     /// Reset a given node by setting the reset pin logically high for 1 second
@@ -108,22 +137,35 @@ impl PowerController {
         let bits = node.to_bitfield();
 
         self.set_power_node(0u8, bits).await?;
-        sleep(Duration::from_secs(1)).await;
+        sleep(RESET_DELAY).await;
         self.set_power_node(bits, bits).await?;
         Ok(())
     }
+}
 
-    pub async fn power_led(&self, on: bool) -> anyhow::Result<()> {
-        tokio::fs::write(&self.sysfs_power, if on { "1" } else { "0" })
-            .await
-            .context(SYS_LED)
-    }
-
-    pub async fn status_led(&self, on: bool) -> anyhow::Result<()> {
-        tokio::fs::write(&self.sysfs_reset, if on { "1" } else { "0" })
+/// Calls `set_power` for each node in `node_mask`, in order, and stops at the
+/// first failure.
+async fn apply_power<F, Fut>(
+    node_states: u8,
+    node_mask: u8,
+    mut set_power: F,
+) -> Result<(), PartialPowerError>
+where
+    F: FnMut(usize, u8) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut applied = 0u8;
+    for (idx, state) in bit_iterator(node_states, node_mask) {
+        set_power(idx, state)
             .await
-            .context(STATUS_LED)
+            .map_err(|source| PartialPowerError {
+                applied,
+                failed: idx,
+                source,
+            })?;
+        applied |= 1 << idx;
     }
+    Ok(())
 }
 
 async fn set_mode(node_id: usize, node_state: u8) -> std::io::Result<()> {
@@ -137,11 +179,25 @@ async fn set_mode(node_id: usize, node_state: u8) -> std::io::Result<()> {
     tokio::fs::write(sys_path, node_value).await
 }
 
-fn fallback_if_not_exist(sysfs: &str, fallback: &str) -> PathBuf {
-    let mut sysfs = PathBuf::from_str(sysfs).expect("valid utf8 path");
-    if !sysfs.exists() {
-        sysfs = PathBuf::from_str(fallback).expect("valid utf8 path");
-        tracing::info!("power led: falling back to {}", fallback);
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn partial_failure_reports_applied_nodes() {
+        let mut switched = Vec::new();
+        let err = apply_power(0b1111, 0b1101, |idx, _| {
+            switched.push(idx);
+            async move {
+                anyhow::ensure!(idx != 2, "pin {} failed", idx);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.applied, 0b0001);
+        assert_eq!(err.failed, 2);
+        assert_eq!(switched, vec![0, 2]);
     }
-    sysfs
 }
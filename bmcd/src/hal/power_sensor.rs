@@ -0,0 +1,98 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Current sensing of the nodes via the Linux hwmon subsystem. The board has
+//! no current sensors of its own, some carrier boards add INA2xx sensors on
+//! I2C.
+use super::{NodeId, NODE_COUNT};
+use anyhow::Context;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Electrical readings of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PowerMetrics {
+    pub volts: f64,
+    pub amps: f64,
+    pub watts: f64,
+}
+
+/// The hwmon devices that measure the supply of each node.
+pub struct PowerSensors {
+    sensors: [Option<PathBuf>; NODE_COUNT as usize],
+}
+
+impl PowerSensors {
+    /// `sensors` are the hwmon directories, e.g. `/sys/class/hwmon/hwmon1`,
+    /// of the nodes that have a sensor.
+    pub fn new(sensors: [Option<PathBuf>; NODE_COUNT as usize]) -> Self {
+        Self { sensors }
+    }
+
+    /// Reads the sensor of `node`, `Ok(None)` when it has no sensor.
+    pub async fn read(&self, node: NodeId) -> anyhow::Result<Option<PowerMetrics>> {
+        let Some(hwmon) = &self.sensors[node as usize] else {
+            return Ok(None);
+        };
+        read_hwmon(hwmon)
+            .await
+            .with_context(|| hwmon.to_string_lossy().to_string())
+            .map(Some)
+    }
+}
+
+/// INA2xx sensors report the bus voltage as `in1` in mV, the current in mA
+/// and the power in µW. Sensors without a power channel get it calculated.
+async fn read_hwmon(hwmon: &Path) -> anyhow::Result<PowerMetrics> {
+    let volts = read_channel(hwmon, "in1_input").await? / 1e3;
+    let amps = read_channel(hwmon, "curr1_input").await? / 1e3;
+    let watts = match read_channel(hwmon, "power1_input").await {
+        Ok(microwatts) => microwatts / 1e6,
+        Err(_) => volts * amps,
+    };
+    Ok(PowerMetrics { volts, amps, watts })
+}
+
+async fn read_channel(hwmon: &Path, channel: &str) -> anyhow::Result<f64> {
+    let value = tokio::fs::read_to_string(hwmon.join(channel))
+        .await
+        .with_context(|| channel.to_string())?;
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("{channel}: invalid value {value:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn metrics_are_read_from_hwmon() {
+        let hwmon = TempDir::new("hwmon").unwrap();
+        std::fs::write(hwmon.path().join("in1_input"), "12000\n").unwrap();
+        std::fs::write(hwmon.path().join("curr1_input"), "1500\n").unwrap();
+
+        let sensors = PowerSensors::new([None, Some(hwmon.path().to_path_buf()), None, None]);
+        assert_eq!(sensors.read(NodeId::Node1).await.unwrap(), None);
+        let metrics = sensors.read(NodeId::Node2).await.unwrap().unwrap();
+        assert_eq!(metrics.volts, 12.0);
+        assert_eq!(metrics.amps, 1.5);
+        assert_eq!(metrics.watts, 18.0);
+
+        std::fs::write(hwmon.path().join("power1_input"), "17500000\n").unwrap();
+        let metrics = sensors.read(NodeId::Node2).await.unwrap().unwrap();
+        assert_eq!(metrics.watts, 17.5);
+    }
+}
@@ -36,9 +36,13 @@ use actix_web::{
     App, HttpRequest, HttpResponse, HttpServer,
 };
 use anyhow::Context;
-use app::{bmc_application::BmcApplication, event_application::run_event_listener};
+use app::{
+    bmc_application::BmcApplication,
+    event_application::{reboot_action, run_event_listener},
+};
 use clap::{command, value_parser, Arg};
 use config::Log;
+use evdev::KeyCode;
 use futures::future::join_all;
 use openssl::{
     pkey::{PKey, Private},
@@ -50,11 +54,14 @@ use std::{
     io::Read,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use tracing_appender::{non_blocking::WorkerGuard, rolling::Rotation};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
 const HTTP_PORT: u16 = 80;
+/// Upper bound for the cleanup of the BMC after the servers stopped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -62,7 +69,11 @@ async fn main() -> anyhow::Result<()> {
     let _logger_lifetime = init_logger(&config.log);
 
     let tls = load_tls_config(&config)?;
-    let bmc = Data::new(BmcApplication::new(config.store.write_timeout).await?);
+    let bmc = Data::new(BmcApplication::new(&config).await?.with_event_action(
+        KeyCode::KEY_RESTART,
+        1,
+        reboot_action,
+    ));
     let serial_service = Data::new(SerialConnections::new());
     let streaming_data_service = Data::new(StreamingDataService::new());
     let authentication = Arc::new(
@@ -75,8 +86,9 @@ async fn main() -> anyhow::Result<()> {
         .await?,
     );
 
-    run_event_listener(bmc.clone().into_inner())?;
+    run_event_listener(bmc.clone().into_inner(), config.input.device.as_deref());
 
+    let shutdown_bmc = bmc.clone();
     let run_server = HttpServer::new(move || {
         let www_root = config.www.clone();
         App::new()
@@ -117,8 +129,14 @@ async fn main() -> anyhow::Result<()> {
         );
     }
 
-    // run server(s)
+    // run server(s). They stop on SIGINT or SIGTERM.
     join_all(futures).await;
+
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown_bmc.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::error!("shutdown: {:#}", e),
+        Err(_) => tracing::error!("shutdown did not complete within {:?}", SHUTDOWN_TIMEOUT),
+    }
     tracing::info!("exiting {}", env!("CARGO_PKG_NAME"));
     Ok(())
 }
@@ -70,6 +70,13 @@ impl PersistencyBuilder {
     pub async fn build(self) -> anyhow::Result<ApplicationPersistency> {
         ApplicationPersistency::new(self.keys, BIN_DATA, self.write_timeout).await
     }
+
+    /// Construct an [`ApplicationPersistency`] that is not backed by a file,
+    /// see [`ApplicationPersistency::in_memory`].
+    #[cfg(test)]
+    pub fn build_in_memory(self) -> ApplicationPersistency {
+        ApplicationPersistency::in_memory(self.keys)
+    }
 }
 
 #[derive(Debug)]
@@ -193,6 +200,27 @@ impl ApplicationPersistency {
         Ok(Self { context })
     }
 
+    /// A store that starts with the defaults and never touches the
+    /// file-system, so that code using the store can be tested without
+    /// storage. It is not [writable](Self::is_writable).
+    #[cfg(test)]
+    pub fn in_memory<I>(keys_with_default: I) -> Self
+    where
+        I: IntoIterator<Item = (&'static str, Vec<u8>)>,
+    {
+        let inner = PersistencyStore::new(keys_with_default, Empty::default())
+            .expect("an empty source to load");
+        Self {
+            context: Arc::new(MonitorContext { file: None, inner }),
+        }
+    }
+
+    /// Returns false when changes to the store cannot be written back to the
+    /// file-system.
+    pub fn is_writable(&self) -> bool {
+        self.context.file.is_some()
+    }
+
     async fn filesystem_writer(
         write_timeout: Duration,
         context: Arc<MonitorContext>,
@@ -292,6 +320,19 @@ mod tests {
         });
     }
 
+    #[tokio::test]
+    async fn in_memory_store_keeps_values() {
+        let persistency = PersistencyBuilder::default()
+            .register_key("test", &123u128)
+            .build_in_memory();
+        assert!(!persistency.is_writable());
+        assert_eq!(persistency.get::<u128>("test").await, 123u128);
+
+        persistency.set("test", 777u128).await;
+        assert_eq!(persistency.get::<u128>("test").await, 777u128);
+        assert!(persistency.try_get::<u128>("unknown").await.is_err());
+    }
+
     #[tokio::test]
     async fn persistency_monitor_test() {
         tokio::task::spawn_blocking(|| {
@@ -73,6 +73,8 @@ type Context = (HashMap<u64, Vec<u8>>, Option<Sender<Instant>>);
 #[derive(Debug)]
 pub struct PersistencyStore {
     cache: RwLock<Context>,
+    /// The registered keys with their defaults, see [`Self::factory_reset`].
+    defaults: HashMap<u64, Vec<u8>>,
     dirty: AtomicBool,
 }
 
@@ -83,7 +85,8 @@ impl<'a> PersistencyStore {
         S: Read + Seek + 'a,
     {
         let iter = keys.into_iter().map(|(k, v)| (default_hash(k), v));
-        let mut cache = HashMap::from_iter(iter);
+        let defaults = HashMap::from_iter(iter);
+        let mut cache = defaults.clone();
 
         if let Err(e) = Self::try_deserialize_source(source, &mut cache) {
             tracing::error!("coninue-ing without loading persistency: {}", e);
@@ -91,6 +94,7 @@ impl<'a> PersistencyStore {
 
         Ok(Self {
             cache: RwLock::new((cache, None)),
+            defaults,
             dirty: AtomicBool::new(false),
         })
     }
@@ -214,19 +218,31 @@ impl<'a> PersistencyStore {
         let previous = cache.0.insert(k, encoded.clone());
 
         if previous.as_ref() != Some(&encoded) {
-            self.dirty.store(true, Ordering::Relaxed);
-
-            if let Some(observer) = cache.1.as_ref() {
-                if observer.send(Instant::now()).is_err() {
-                    tracing::info!("persistency watcher dropped");
-                    cache.1 = None;
-                }
-            }
+            self.mark_dirty(&mut cache);
         }
 
         Ok(())
     }
 
+    /// Restores the registered defaults of all keys. Stored values of keys that
+    /// are not registered anymore are dropped as well.
+    pub async fn factory_reset(&self) {
+        let mut cache = self.cache.write().await;
+        cache.0 = self.defaults.clone();
+        self.mark_dirty(&mut cache);
+    }
+
+    fn mark_dirty(&self, cache: &mut Context) {
+        self.dirty.store(true, Ordering::Relaxed);
+
+        if let Some(observer) = cache.1.as_ref() {
+            if observer.send(Instant::now()).is_err() {
+                tracing::info!("persistency watcher dropped");
+                cache.1 = None;
+            }
+        }
+    }
+
     pub fn is_dirty(&self) -> bool {
         self.dirty.load(Ordering::Relaxed)
     }
@@ -336,4 +352,25 @@ mod tests {
         assert!(store.is_dirty());
         assert_eq!(store.get::<u128>("test").await, 333u128);
     }
+
+    #[tokio::test]
+    async fn factory_reset_restores_defaults() {
+        let source = Cursor::new(Vec::new());
+        let store =
+            PersistencyStore::new([("test", bincode::serialize(&123u128).unwrap())], source)
+                .unwrap();
+        store.set("test", 333u128).await;
+        let mut written = Cursor::new(Vec::new());
+        store.write(&mut written).await.unwrap();
+
+        let store =
+            PersistencyStore::new([("test", bincode::serialize(&123u128).unwrap())], written)
+                .unwrap();
+        assert_eq!(store.get::<u128>("test").await, 333u128);
+        let watcher = store.get_watcher().await;
+        store.factory_reset().await;
+        assert!(watcher.has_changed().unwrap());
+        assert!(store.is_dirty());
+        assert_eq!(store.get::<u128>("test").await, 123u128);
+    }
 }
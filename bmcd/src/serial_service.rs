@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use self::serial::SerialConnections;
-use self::serial_handler::Encoding;
+use self::serial_handler::{Encoding, SerialError};
 use crate::api::{
     get_node_param,
     into_legacy_response::{LegacyResponse, LegacyResult},
@@ -24,6 +24,10 @@ use actix_web::{
     HttpRequest, HttpResponse, Responder,
 };
 use bytes::BytesMut;
+use futures::SinkExt;
+use std::io;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_util::sync::PollSender;
 type Query = web::Query<std::collections::HashMap<String, String>>;
 
 pub mod serial;
@@ -101,6 +105,22 @@ async fn handle_ws(
     serials: web::Data<SerialConnections>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let node = get_node_param(&query)?;
+
+    if query.contains_key("exclusive") {
+        return match serials[node].open_uart() {
+            Ok((receiver, sender)) => {
+                let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+                let stream = ReceiverStream::new(receiver).map(Ok::<_, io::Error>);
+                let sink = PollSender::new(sender)
+                    .sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+                run_websocket(session, msg_stream, stream, sink).await;
+                Ok(res)
+            }
+            Err(e @ SerialError::InUse) => Ok(HttpResponse::Conflict().body(e.to_string())),
+            Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+        };
+    }
+
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
     match serials[node].open_channel() {
         Ok((stream, sink)) => {
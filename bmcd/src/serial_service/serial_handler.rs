@@ -17,10 +17,11 @@ use futures::StreamExt;
 use futures::{Sink, SinkExt, Stream};
 use serde::Serialize;
 use std::io::{self, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::{
-    broadcast,
+    broadcast::{self, error::RecvError},
     mpsc::{self, error::SendError, WeakSender},
     Mutex,
 };
@@ -53,6 +54,7 @@ pub struct Handler {
     ring_buffer: Arc<Mutex<Box<RingBuffer>>>,
     worker_context: Option<(broadcast::Sender<Bytes>, mpsc::Sender<Bytes>)>,
     writer: Option<WeakSender<Bytes>>,
+    uart_consumer: Arc<AtomicBool>,
 }
 
 impl Handler {
@@ -74,6 +76,7 @@ impl Handler {
             ring_buffer: Arc::new(Mutex::new(RingBuffer::boxed())),
             worker_context: None,
             writer: None,
+            uart_consumer: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -92,7 +95,7 @@ impl Handler {
     /// # Returns
     ///
     /// * `SerialError::NotStarted` when [`Self::run`] was not called
-    ///     successfully
+    ///   successfully
     pub fn open_channel(
         &self,
     ) -> Result<
@@ -113,6 +116,70 @@ impl Handler {
         Ok((stream, poll_sender))
     }
 
+    /// Opens an exclusive console to the serial port. In contrast to
+    /// [`Self::open_channel`], only one console can be open per node at any
+    /// given time. Two tasks are spawned which forward the serial output to the
+    /// returned receiver and drain the returned sender into the serial port.
+    /// The console is released again once both channel ends are dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `SerialError::NotStarted` when [`Self::run`] was not called
+    ///   successfully
+    /// * `SerialError::InUse` when another console is currently open
+    pub fn open_uart(&self) -> Result<(mpsc::Receiver<Bytes>, mpsc::Sender<Bytes>), SerialError> {
+        let Some((read_sender, write_sender)) = &self.worker_context else {
+            return Err(SerialError::NotStarted);
+        };
+
+        if self.uart_consumer.swap(true, Ordering::AcqRel) {
+            return Err(SerialError::InUse);
+        }
+
+        let guard = Arc::new(ConsumerGuard(self.uart_consumer.clone()));
+        let (output_sender, output_receiver) = mpsc::channel::<Bytes>(8);
+        let (input_sender, mut input_receiver) = mpsc::channel::<Bytes>(8);
+        let mut subscriber = read_sender.subscribe();
+        let serial_writer = write_sender.clone();
+        let node = self.node;
+
+        let reader_guard = guard.clone();
+        tokio::spawn(async move {
+            let _guard = reader_guard;
+            loop {
+                let bytes = tokio::select! {
+                    _ = output_sender.closed() => break,
+                    res = subscriber.recv() => res,
+                };
+
+                match bytes {
+                    Ok(bytes) => {
+                        if output_sender.send(bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("uart console of node {} dropped {} messages", node, n);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            trace!("uart console reader of node {} stopped", node);
+        });
+
+        tokio::spawn(async move {
+            let _guard = guard;
+            while let Some(bytes) = input_receiver.recv().await {
+                if serial_writer.send(bytes).await.is_err() {
+                    break;
+                }
+            }
+            trace!("uart console writer of node {} stopped", node);
+        });
+
+        Ok((output_receiver, input_sender))
+    }
+
     /// Reads the whole circular buffer encoded as a String object
     pub async fn read_as_string(&self, encoding: Encoding) -> Result<String, SerialError> {
         let bytes = self.read_whole_buffer().await?;
@@ -141,7 +208,7 @@ impl Handler {
     /// # Returns
     ///
     /// * `SerialError::NotStarted` when [`Self::run`] was not called
-    ///     successfully.
+    ///   successfully.
     /// * `SerialError::Stopped` when the handler is not running anymore.
     ///
     pub async fn write(&self, bytes: Bytes) -> Result<(), SerialError> {
@@ -237,6 +304,8 @@ pub enum SerialError {
     AlreadyRunning,
     #[error("Stopped")]
     Stopped,
+    #[error("uart console is already in use")]
+    InUse,
     #[error(transparent)]
     SendError(#[from] SendError<bytes::Bytes>),
     #[error(transparent)]
@@ -245,6 +314,15 @@ pub enum SerialError {
     IoError(#[from] std::io::Error),
 }
 
+/// Releases the exclusive uart console of a [`Handler`] when dropped.
+struct ConsumerGuard(Arc<AtomicBool>);
+
+impl Drop for ConsumerGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
 #[derive(Serialize)]
 pub enum HandlerState {
     Initialized,
@@ -12,10 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 pub mod data_transfer;
+mod image_reader;
 pub mod transfer_context;
 
 use crate::api::into_legacy_response::LegacyResponse;
-use crate::streaming_data_service::transfer_context::TransferContext;
+use crate::app::upgrade_worker::FlashSummary;
+use crate::streaming_data_service::transfer_context::{TransferContext, TransferProgress};
 use actix_web::http::StatusCode;
 use bytes::Bytes;
 use futures::future::BoxFuture;
@@ -111,7 +113,7 @@ impl StreamingDataService {
     async fn execute_worker(
         &self,
         context: &TransferContext,
-        future: impl Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+        future: impl Future<Output = anyhow::Result<Option<FlashSummary>>> + Send + 'static,
     ) {
         let id = context.id;
         let cancel = context.get_child_token();
@@ -166,9 +168,9 @@ impl StreamingDataService {
     /// This function returns:
     ///
     /// * 'Err(StreamingServiceError::WrongState)' if this function is called when
-    ///     ['StreamingDataService'] is not in 'Transferring' state.
+    ///   ['StreamingDataService'] is not in 'Transferring' state.
     /// * 'Err(StreamingServiceError::HandlesDoNotMatch)', the passed id is
-    ///     unknown
+    ///   unknown
     /// * 'Err(StreamingServiceError::SenderTaken(_)'
     /// * Ok(()) on success
     pub async fn take_sender(
@@ -279,7 +281,9 @@ pub struct TransferRequest {
     pub process_name: String,
     pub size: u64,
     pub sender: Option<mpsc::Sender<bytes::Bytes>>,
-    pub progress_watcher: watch::Receiver<u64>,
-    pub worker: BoxFuture<'static, anyhow::Result<()>>,
+    pub progress_watcher: watch::Receiver<TransferProgress>,
+    pub worker: BoxFuture<'static, anyhow::Result<Option<FlashSummary>>>,
     pub cancel: CancellationToken,
+    /// Halts the worker in between chunks while set to true.
+    pub pause: watch::Sender<bool>,
 }
@@ -11,6 +11,8 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use super::image_reader::image_stream;
+use super::transfer_context::TransferProgress;
 use crate::utils::Sha256StreamValidator;
 use crate::Path;
 use anyhow::Context;
@@ -28,8 +30,7 @@ use tokio::fs::OpenOptions;
 use tokio::io;
 use tokio::io::AsyncBufRead;
 use tokio::io::AsyncRead;
-use tokio::io::BufReader;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 use tokio_util::io::StreamReader;
@@ -78,7 +79,7 @@ impl DataTransfer {
     pub async fn url(url: Url, sha256: Option<bytes::Bytes>) -> anyhow::Result<Self> {
         let file_name = url
             .path_segments()
-            .and_then(|seg| seg.last())
+            .and_then(|mut seg| seg.next_back())
             .or_else(|| url.host_str())
             .unwrap_or("http_file")
             .into();
@@ -145,7 +146,26 @@ impl DataTransfer {
         }
     }
 
-    pub async fn reader(&mut self) -> anyhow::Result<impl AsyncRead + Sync + Send + Unpin> {
+    /// Returns true if the data gets decompressed while reading, in which case
+    /// [`Self::size`] does not reflect the amount of bytes read.
+    pub fn is_compressed(&self) -> bool {
+        self.file_name()
+            .is_ok_and(|name| Path::new(name).extension() == Some(OsStr::new("xz")))
+    }
+
+    /// Returns true if [`Self::reader`] can be called more than once, reading
+    /// the data from the start each time.
+    pub fn is_replayable(&self) -> bool {
+        matches!(self, DataTransfer::Local { .. })
+    }
+
+    /// Local images are read with a timeout, and reopened when a read stalls,
+    /// e.g. on an unresponsive network share. Stalls are reported to
+    /// `progress`.
+    pub async fn reader(
+        &mut self,
+        progress: &watch::Sender<TransferProgress>,
+    ) -> anyhow::Result<impl AsyncRead + Sync + Send + Unpin> {
         match self {
             DataTransfer::Local { path } => {
                 let file = OpenOptions::new()
@@ -154,7 +174,8 @@ impl DataTransfer {
                     .await
                     .with_context(|| path.to_string_lossy().to_string())?;
 
-                Ok(with_decompression_support(path, BufReader::new(file)))
+                let stream = image_stream(path.clone(), file, progress.clone());
+                Ok(with_decompression_support(path, StreamReader::new(stream)))
             }
             DataTransfer::Remote {
                 file_name,
@@ -181,7 +202,7 @@ impl DataTransfer {
                     .take()
                     .expect("request taken")
                     .bytes_stream()
-                    .map(|res| res.map_err(|e| std::io::Error::new(ErrorKind::Other, e)));
+                    .map(|res| res.map_err(std::io::Error::other));
 
                 Ok(build_reader_object(file_name, sha256.clone(), bytes_stream))
             }
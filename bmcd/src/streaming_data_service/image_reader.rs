@@ -0,0 +1,170 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Reading of local images, which may live on a network share (NFS, SMB). A
+//! share that stops responding blocks reads indefinitely, so reads are timed
+//! out and retried on a fresh handle of the file.
+use super::transfer_context::TransferProgress;
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use std::io::{self, ErrorKind, SeekFrom};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::watch;
+
+/// Time after which a read of the image counts as stalled.
+const READ_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Stalled or failed reads in a row after which reading the image fails.
+const READ_RETRIES: u32 = 5;
+const READ_RETRY_DELAY: Duration = Duration::from_secs(2);
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Reads the image at `path` from the start. Stalls are reported to
+/// `progress`, so that the operator can tell a slow source from a slow node.
+pub fn image_stream(
+    path: PathBuf,
+    file: File,
+    progress: watch::Sender<TransferProgress>,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + Sync + Unpin {
+    let reader = ImageReader {
+        path,
+        file: Some(file),
+        offset: 0,
+        failures: 0,
+        progress,
+    };
+    Box::pin(futures::stream::try_unfold(reader, |mut reader| async {
+        let chunk = reader.read_chunk().await?;
+        Ok((!chunk.is_empty()).then_some((chunk, reader)))
+    }))
+}
+
+struct ImageReader {
+    path: PathBuf,
+    /// `None` when the file must be reopened.
+    file: Option<File>,
+    offset: u64,
+    failures: u32,
+    progress: watch::Sender<TransferProgress>,
+}
+
+impl ImageReader {
+    /// Returns the next chunk of the image, an empty chunk at its end.
+    async fn read_chunk(&mut self) -> io::Result<Bytes> {
+        loop {
+            let reason = match tokio::time::timeout(READ_STALL_TIMEOUT, self.try_read_chunk()).await
+            {
+                Ok(Ok(chunk)) => {
+                    if self.failures > 0 {
+                        tracing::info!("reading {} resumed", self.path.to_string_lossy());
+                        self.progress.send_modify(|p| p.message = None);
+                    }
+                    self.failures = 0;
+                    self.offset += chunk.len() as u64;
+                    return Ok(chunk);
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("no data for {}s", READ_STALL_TIMEOUT.as_secs()),
+            };
+
+            self.failures += 1;
+            let path = self.path.to_string_lossy();
+            if self.failures > READ_RETRIES {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("reading image {path} failed: {reason}"),
+                ));
+            }
+            tracing::warn!(
+                "reading image {} stalled at offset {}: {}, reopening ({}/{})",
+                path,
+                self.offset,
+                reason,
+                self.failures,
+                READ_RETRIES
+            );
+            self.progress.send_modify(|p| {
+                p.set_message(format!(
+                    "image source stalled: {reason}, retry {}/{READ_RETRIES}",
+                    self.failures
+                ))
+            });
+            self.file = None;
+            tokio::time::sleep(READ_RETRY_DELAY).await;
+        }
+    }
+
+    async fn try_read_chunk(&mut self) -> io::Result<Bytes> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let mut file = File::open(&self.path).await?;
+                file.seek(SeekFrom::Start(self.offset)).await?;
+                self.file.insert(file)
+            }
+        };
+
+        let mut chunk = BytesMut::with_capacity(READ_CHUNK_SIZE);
+        file.read_buf(&mut chunk).await?;
+        Ok(chunk.freeze())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn image_is_read_in_chunks() {
+        let dir = TempDir::new("image_reader").unwrap();
+        let path = dir.path().join("image.img");
+        let image = (0..READ_CHUNK_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &image).unwrap();
+
+        let file = File::open(&path).await.unwrap();
+        let progress = watch::Sender::new(TransferProgress::default());
+        let mut stream = image_stream(path, file, progress);
+        let mut read = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            read.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(read, image);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unavailable_source_fails_after_retries() {
+        let dir = TempDir::new("image_reader").unwrap();
+        let progress = watch::Sender::new(TransferProgress::default());
+        let mut reader = ImageReader {
+            path: dir.path().join("gone.img"),
+            file: None,
+            offset: 0,
+            failures: 0,
+            progress: progress.clone(),
+        };
+        let error = reader.read_chunk().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(reader.failures, READ_RETRIES + 1);
+        assert!(progress
+            .borrow()
+            .message
+            .as_ref()
+            .is_some_and(|m| m.contains("image source stalled")));
+    }
+}
@@ -13,25 +13,251 @@
 // limitations under the License.
 
 use bytes::Bytes;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_with::{serde_as, DurationSeconds};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, watch};
 use tokio_util::sync::CancellationToken;
 
+/// Rough duration of the [`TransferPhase::Preparing`] phase, there is nothing
+/// to measure until the module shows up on the USB bus.
+const PREPARING_ESTIMATE: Duration = Duration::from_secs(15);
+/// Period over which [`TransferProgress::bytes_per_sec`] is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The stage a transfer is in. Used by clients to present what the BMC is
+/// doing while there is no data being written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferPhase {
+    /// Setting up the target, e.g. booting a module into mass-storage mode.
+    #[default]
+    Preparing,
+    Writing,
+    Verifying,
+    /// Restoring the power and USB state of the target after writing.
+    Finalizing,
+}
+
+impl TransferPhase {
+    /// Share of the total transfer time spent in this phase, relative to the
+    /// other phases.
+    fn weight(&self) -> f64 {
+        match self {
+            TransferPhase::Preparing => 1.0,
+            TransferPhase::Writing => 6.0,
+            TransferPhase::Verifying => 3.0,
+            TransferPhase::Finalizing => 0.5,
+        }
+    }
+}
+
+/// Progress of a running transfer, published by the worker over a
+/// [`watch`] channel.
+///
+/// `percent` and `eta` cover all planned phases of the transfer. The ETA is
+/// extrapolated from the write rate, so it stays `None` until the first
+/// bytes are written.
+#[serde_as]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TransferProgress {
+    pub phase: TransferPhase,
+    pub bytes_written: u64,
+    pub percent: f64,
+    #[serde_as(as = "Option<DurationSeconds<f64>>")]
+    pub eta: Option<Duration>,
+    /// Throughput of the current phase over the last few seconds. Unlike the
+    /// ETA, this reflects the current speed of the USB link and the storage.
+    pub bytes_per_sec: Option<f64>,
+    /// Human readable description of the current step, if any.
+    pub message: Option<String>,
+    pub paused: bool,
+    /// Share of the image that was read back and matches the image, in
+    /// percent. Unlike `percent`, it only counts data known to be correct.
+    pub verified_percent: Option<f64>,
+    /// Offset in the image of the first data read back that differs from the
+    /// image. Exact when reading back inline, otherwise the start of the first
+    /// block that differs.
+    pub first_divergence: Option<u64>,
+    #[serde(skip)]
+    size: u64,
+    #[serde(skip)]
+    phases: Vec<TransferPhase>,
+    #[serde(skip)]
+    phase_start: Option<Instant>,
+    /// Time spent paused in the current phase, excluding the ongoing pause.
+    #[serde(skip)]
+    paused_time: Duration,
+    #[serde(skip)]
+    paused_since: Option<Instant>,
+    /// bytes/sec measured during the [`TransferPhase::Writing`] phase.
+    #[serde(skip)]
+    write_rate: Option<f64>,
+    /// Byte counts of the last [`RATE_WINDOW`].
+    #[serde(skip)]
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl TransferProgress {
+    /// `phases` lists the phases the transfer goes through, in order. The
+    /// first phase is entered immediately.
+    pub fn new(size: u64, phases: Vec<TransferPhase>) -> Self {
+        let mut progress = Self {
+            size,
+            phases,
+            ..Default::default()
+        };
+
+        if let Some(first) = progress.phases.first().copied() {
+            progress.enter(first);
+        }
+        progress
+    }
+
+    /// Moves the progress into `phase`, resetting the byte count and message.
+    /// The verification state is reset when (re)writing the image.
+    pub fn enter(&mut self, phase: TransferPhase) {
+        if phase == TransferPhase::Writing {
+            self.verified_percent = None;
+            self.first_divergence = None;
+        }
+        self.phase = phase;
+        self.bytes_written = 0;
+        self.message = None;
+        self.phase_start = Some(Instant::now());
+        self.paused_time = Duration::ZERO;
+        self.paused_since = self.paused.then(Instant::now);
+        self.samples.clear();
+        self.bytes_per_sec = None;
+        self.update();
+    }
+
+    pub fn set_written(&mut self, bytes_written: u64) {
+        self.bytes_written = bytes_written;
+
+        let now = Instant::now();
+        self.samples.push_back((now, bytes_written));
+        while self.samples.len() > 2
+            && self
+                .samples
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        self.bytes_per_sec = sliding_rate(&self.samples);
+        self.update();
+    }
+
+    /// `verified` bytes of the image were read back and match.
+    pub fn set_verified(&mut self, verified: u64) {
+        if self.size > 0 {
+            self.verified_percent = Some(100.0 * (verified as f64 / self.size as f64).min(1.0));
+        }
+    }
+
+    /// Records the first divergence only, later ones are less telling.
+    pub fn set_divergence(&mut self, offset: u64) {
+        self.first_divergence.get_or_insert(offset);
+    }
+
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+        self.update();
+    }
+
+    /// Paused time is excluded from the rate and ETA calculations.
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused == paused {
+            return;
+        }
+
+        self.paused = paused;
+        // the window must not span the pause.
+        self.samples.clear();
+        self.bytes_per_sec = None;
+        if paused {
+            self.paused_since = Some(Instant::now());
+        } else if let Some(since) = self.paused_since.take() {
+            self.paused_time += since.elapsed();
+        }
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let paused = self.paused_time
+            + self
+                .paused_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default();
+        let elapsed = self
+            .phase_start
+            .map(|start| start.elapsed().saturating_sub(paused))
+            .unwrap_or_default();
+        let rate = (!elapsed.is_zero() && self.bytes_written > 0)
+            .then(|| self.bytes_written as f64 / elapsed.as_secs_f64());
+
+        let fraction = match self.phase {
+            TransferPhase::Preparing => {
+                (elapsed.as_secs_f64() / PREPARING_ESTIMATE.as_secs_f64()).min(0.99)
+            }
+            TransferPhase::Writing | TransferPhase::Verifying if self.size > 0 => {
+                (self.bytes_written as f64 / self.size as f64).min(1.0)
+            }
+            _ => 0.0,
+        };
+
+        let total_weight: f64 = self.phases.iter().map(TransferPhase::weight).sum();
+        if total_weight > 0.0 {
+            let done: f64 = self
+                .phases
+                .iter()
+                .take_while(|p| **p != self.phase)
+                .map(TransferPhase::weight)
+                .sum();
+            self.percent = 100.0 * (done + fraction * self.phase.weight()) / total_weight;
+        }
+
+        let remaining = self.size.saturating_sub(self.bytes_written) as f64;
+        let remaining_secs = match self.phase {
+            TransferPhase::Preparing | TransferPhase::Finalizing => None,
+            TransferPhase::Writing => {
+                self.write_rate = rate.or(self.write_rate);
+                self.write_rate.map(|rate| {
+                    let verify = if self.phases.contains(&TransferPhase::Verifying) {
+                        self.size as f64 / rate
+                    } else {
+                        0.0
+                    };
+                    remaining / rate + verify
+                })
+            }
+            TransferPhase::Verifying => rate.or(self.write_rate).map(|rate| remaining / rate),
+        };
+        self.eta = remaining_secs.map(Duration::from_secs_f64);
+    }
+}
+
+/// Bytes per second between the first and the last sample.
+fn sliding_rate(samples: &VecDeque<(Instant, u64)>) -> Option<f64> {
+    let (first_at, first_bytes) = samples.front()?;
+    let (last_at, last_bytes) = samples.back()?;
+    let secs = last_at.duration_since(*first_at).as_secs_f64();
+    (secs > 0.0).then(|| last_bytes.saturating_sub(*first_bytes) as f64 / secs)
+}
+
 /// Context object for node flashing. This object acts as a "cancel-guard" for
 /// the [`crate::StreamingDataService`]. If [`TransferContext`] gets dropped, it will
 /// cancel its "cancel" token, effectively aborting the node flash task. This
 /// typically happens on a state transition inside the [`crate::StreamingDataService`].
-#[derive(Serialize)]
 pub struct TransferContext {
     pub id: u32,
     pub process_name: String,
     pub size: u64,
-    #[serde(skip)]
     pub data_sender: Option<mpsc::Sender<Bytes>>,
-    #[serde(serialize_with = "serialize_cancellation_token")]
     cancelled: CancellationToken,
-    #[serde(serialize_with = "serialize_written_bytes")]
-    bytes_written: watch::Receiver<u64>,
+    progress: watch::Receiver<TransferProgress>,
 }
 
 impl TransferContext {
@@ -39,7 +265,7 @@ impl TransferContext {
         id: u32,
         process_name: String,
         size: u64,
-        written_receiver: watch::Receiver<u64>,
+        progress: watch::Receiver<TransferProgress>,
         data_sender: Option<mpsc::Sender<Bytes>>,
         cancel_token: CancellationToken,
     ) -> Self {
@@ -48,7 +274,7 @@ impl TransferContext {
             size,
             process_name,
             cancelled: cancel_token,
-            bytes_written: written_receiver,
+            progress,
             data_sender,
         }
     }
@@ -64,19 +290,67 @@ impl Drop for TransferContext {
     }
 }
 
-fn serialize_cancellation_token<S>(
-    cancel_token: &CancellationToken,
-    s: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_bool(cancel_token.is_cancelled())
+/// The fields of the current [`TransferProgress`] are flattened into the
+/// context, so that `bytes_written` keeps its place for existing clients.
+impl Serialize for TransferContext {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct Flattened<'a> {
+            id: u32,
+            process_name: &'a str,
+            size: u64,
+            cancelled: bool,
+            #[serde(flatten)]
+            progress: &'a TransferProgress,
+        }
+
+        Flattened {
+            id: self.id,
+            process_name: &self.process_name,
+            size: self.size,
+            cancelled: self.cancelled.is_cancelled(),
+            progress: &self.progress.borrow(),
+        }
+        .serialize(s)
+    }
 }
 
-fn serialize_written_bytes<S>(receiver: &watch::Receiver<u64>, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_u64(*receiver.borrow())
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percent_is_weighted_over_phases() {
+        let mut progress = TransferProgress::new(
+            100,
+            vec![
+                TransferPhase::Preparing,
+                TransferPhase::Writing,
+                TransferPhase::Verifying,
+            ],
+        );
+        assert!(progress.percent < 10.0);
+        assert_eq!(progress.eta, None);
+
+        progress.enter(TransferPhase::Writing);
+        progress.set_written(50);
+        assert!((progress.percent - 40.0).abs() < f64::EPSILON);
+
+        progress.enter(TransferPhase::Verifying);
+        progress.set_written(100);
+        assert!((progress.percent - 100.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn rate_is_measured_between_samples() {
+        let start = Instant::now();
+        let mut samples = VecDeque::from([(start, 1000)]);
+        assert_eq!(sliding_rate(&samples), None);
+
+        samples.push_back((start + Duration::from_secs(2), 9000));
+        assert_eq!(sliding_rate(&samples), Some(4000.0));
+    }
 }
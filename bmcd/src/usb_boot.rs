@@ -11,25 +11,104 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+mod mass_storage;
 mod rockusb;
 mod rpiboot;
-use self::{rockusb::RockusbBoot, rpiboot::RpiBoot};
+pub use self::mass_storage::SupportedDevice;
+use self::{mass_storage::MassStorageBoot, rockusb::RockusbBoot, rpiboot::RpiBoot};
+use crate::hal::NodeId;
+use crate::streaming_data_service::transfer_context::TransferProgress;
 use async_trait::async_trait;
 use rusb::GlobalContext;
-use std::{fmt::Display, path::PathBuf};
+use serde::{Deserialize, Serialize};
+use std::os::fd::AsFd;
+use std::{fmt::Display, path::PathBuf, time::Duration};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use tokio::sync::watch;
+use tokio::time::Instant;
 use tracing::{info, warn};
 
-pub trait DataTransport: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin {}
-impl DataTransport for tokio::fs::File {}
+pub trait DataTransport: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin {
+    /// Returns a duplicate handle of the underlying device, which can be used
+    /// to sync the written data to the medium from a blocking task.
+    fn sync_handle(&self) -> std::io::Result<std::fs::File>;
+}
+
+impl DataTransport for tokio::fs::File {
+    fn sync_handle(&self) -> std::io::Result<std::fs::File> {
+        Ok(self.as_fd().try_clone_to_owned()?.into())
+    }
+}
+
+impl DataTransport for Box<dyn DataTransport> {
+    fn sync_handle(&self) -> std::io::Result<std::fs::File> {
+        (**self).sync_handle()
+    }
+}
+
+/// Delays in the flow of booting a module into mass-storage mode. The
+/// defaults are tuned for the CM4; other modules may enumerate faster or
+/// slower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsbTimings {
+    /// Time given to a module to enumerate on the USB bus after power on.
+    /// Modules that are known to need longer get their own default, see
+    /// [`UsbBoot::power_on_settle`].
+    pub power_on_settle: Duration,
+    /// Delay used by rpiboot in between its boot stages.
+    pub rpiboot_stage_delay: Duration,
+    /// Time given to a Rockchip module to reconnect after the usb-plug loader
+    /// was downloaded.
+    pub rockusb_reconnect: Duration,
+}
+
+impl Default for UsbTimings {
+    fn default() -> Self {
+        Self {
+            power_on_settle: Duration::from_secs(1),
+            rpiboot_stage_delay: Duration::from_millis(500),
+            rockusb_reconnect: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Parameters of a single boot of a module into mass-storage mode.
+pub struct BootContext<'a> {
+    /// Maximum time to wait for the block device to appear.
+    pub device_timeout: Duration,
+    pub timings: UsbTimings,
+    pub progress: &'a watch::Sender<TransferProgress>,
+}
+
+/// The module that a backend detected on the USB bus.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DetectedModule {
+    pub name: &'static str,
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl Display for DetectedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:04x}:{:04x})", self.name, self.vid, self.pid)
+    }
+}
 
 #[async_trait]
 pub trait UsbBoot: 'static + Send + Sync + Display {
     fn is_supported(&self, vid_pid: &(u16, u16)) -> bool;
+    /// Name of the module type this backend boots.
+    fn module_name(&self) -> &'static str;
+    /// Time the module needs after power on before it enumerates on the USB
+    /// bus.
+    fn power_on_settle(&self) -> Duration {
+        UsbTimings::default().power_on_settle
+    }
     async fn load_as_block_device(
         &self,
         _device: &rusb::Device<GlobalContext>,
+        _context: &BootContext<'_>,
     ) -> Result<PathBuf, UsbBootError> {
         Err(UsbBootError::NotSupported)
     }
@@ -37,8 +116,9 @@ pub trait UsbBoot: 'static + Send + Sync + Display {
     async fn load_as_stream(
         &self,
         device: &rusb::Device<GlobalContext>,
+        context: &BootContext<'_>,
     ) -> Result<Box<dyn DataTransport>, UsbBootError> {
-        let path = self.load_as_block_device(device).await?;
+        let path = self.load_as_block_device(device, context).await?;
         Ok(Box::new(
             tokio::fs::OpenOptions::new()
                 .read(true)
@@ -49,27 +129,85 @@ pub trait UsbBoot: 'static + Send + Sync + Display {
     }
 }
 
+/// Describes a device on the USB bus of the BMC, see
+/// [`NodeDrivers::list_usb_devices`].
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbDeviceInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    /// Bus and port path, e.g. "1-1.2".
+    pub path: String,
+    /// Name of the boot backend that supports this device, if any.
+    pub backend: Option<String>,
+}
+
+/// How often the bus is scanned for a module that did not enumerate yet, see
+/// [`NodeDrivers::wait_for_device`].
+const ENUMERATION_ATTEMPTS: u32 = 10;
+const ENUMERATION_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct NodeDrivers {
     backends: Vec<Box<dyn UsbBoot>>,
+    /// Also part of `backends`, kept to extend its device table.
+    custom: MassStorageBoot,
+    device_timeout: Duration,
 }
 
 impl NodeDrivers {
-    pub fn new() -> Self {
+    /// `device_timeout` is the maximum time to wait for the block device of a
+    /// module to appear. `custom` are the devices added by
+    /// [`Self::add_supported_device`] earlier.
+    pub fn new(device_timeout: Duration, custom: Vec<SupportedDevice>) -> Self {
+        let custom = MassStorageBoot::new(custom);
         NodeDrivers {
-            backends: vec![Box::new(RpiBoot {}), Box::new(RockusbBoot {})],
+            backends: vec![
+                Box::new(RpiBoot {}),
+                Box::new(RockusbBoot {}),
+                Box::new(custom.clone()),
+            ],
+            custom,
+            device_timeout,
         }
     }
 
+    pub fn is_supported(&self, vid_pid: (u16, u16)) -> bool {
+        self.backends
+            .iter()
+            .any(|backend| backend.is_supported(&vid_pid))
+    }
+
+    /// Supports a module type that is not known to any of the backends, e.g.
+    /// a custom or pre-release module. The module must expose its storage as
+    /// mass-storage device by itself. Returns all added devices.
+    pub fn add_supported_device(&self, device: SupportedDevice) -> Vec<SupportedDevice> {
+        self.custom.add(device)
+    }
+
+    /// Removes all devices added by [`Self::add_supported_device`].
+    pub fn clear_supported_devices(&self) {
+        self.custom.clear()
+    }
+
+    pub fn custom_devices(&self) -> Vec<SupportedDevice> {
+        self.custom.devices()
+    }
+
     /// Due to the hardware implementation, only one node can be visible at any given time.
-    /// This function tries to find the first USB device which exist a backend for.
-    fn find_first(&self) -> Result<(rusb::Device<GlobalContext>, &dyn UsbBoot), UsbBootError> {
+    /// This function tries to find the USB device which exist a backend for.
+    /// It fails when none, or more as one supported device is found.
+    fn find_one(
+        &self,
+        node: NodeId,
+    ) -> Result<(rusb::Device<GlobalContext>, &dyn UsbBoot), UsbBootError> {
         tracing::info!("Checking for presence of a USB device...");
         let devices = rusb::devices()?;
-        let mut backends = self.backends.iter().filter_map(|backend| {
-            let found = devices.iter().find(|dev| {
+        let mut found = devices
+            .iter()
+            .filter_map(|dev| {
                 let Ok(descriptor) = dev.device_descriptor() else {
                     warn!("dropping {:?}, could not load descriptor", dev);
-                    return false;
+                    return None;
                 };
 
                 let vid_pid = (descriptor.vendor_id(), descriptor.product_id());
@@ -78,39 +216,191 @@ impl NodeDrivers {
                     descriptor.vendor_id(),
                     descriptor.product_id(),
                 );
-                let supported = backend.is_supported(&vid_pid);
-
-                if supported {
-                    info!(
-                        "ID {:#06x}:{:#06x} {}",
-                        descriptor.vendor_id(),
-                        descriptor.product_id(),
-                        backend
-                    );
-                }
-                supported
+
+                let backend = self
+                    .backends
+                    .iter()
+                    .find(|backend| backend.is_supported(&vid_pid))?;
+                info!(
+                    "ID {:#06x}:{:#06x} {}",
+                    descriptor.vendor_id(),
+                    descriptor.product_id(),
+                    backend
+                );
+                Some((dev, backend.as_ref()))
+            })
+            .collect::<Vec<_>>();
+
+        match found.len() {
+            0 => Err(UsbBootError::NoDevice(node)),
+            1 => Ok(found.remove(0)),
+            count => Err(UsbBootError::MultipleDevices(count)),
+        }
+    }
+
+    /// Scans the bus until a supported module of `node` shows up. Modules that
+    /// are slow to enumerate after power on get [`ENUMERATION_ATTEMPTS`]
+    /// chances, every attempt is published to `progress` together with the
+    /// time waited so far.
+    pub async fn wait_for_device(
+        &self,
+        node: NodeId,
+        progress: &watch::Sender<TransferProgress>,
+    ) -> Result<(), UsbBootError> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            progress.send_modify(|p| {
+                p.set_message(format!(
+                    "waiting for USB device, attempt {}/{} ({:.1}s elapsed)",
+                    attempt,
+                    ENUMERATION_ATTEMPTS,
+                    start.elapsed().as_secs_f64()
+                ))
             });
-            found.map(|dev| (dev, backend.as_ref()))
-        });
+            match self.find_one(node) {
+                Err(UsbBootError::NoDevice(_)) if attempt < ENUMERATION_ATTEMPTS => {
+                    attempt += 1;
+                    tokio::time::sleep(ENUMERATION_INTERVAL).await;
+                }
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    /// Returns the power-on settle time of the backend that supports the
+    /// given USB device, see [`UsbBoot::power_on_settle`].
+    pub fn power_on_settle(&self, vid_pid: (u16, u16)) -> Option<Duration> {
+        self.backends
+            .iter()
+            .find(|backend| backend.is_supported(&vid_pid))
+            .map(|backend| backend.power_on_settle())
+    }
+
+    /// Lists all devices on the USB bus, including those no backend supports.
+    /// Devices whose descriptor cannot be loaded are left out.
+    pub fn list_usb_devices(&self) -> Result<Vec<UsbDeviceInfo>, UsbBootError> {
+        let devices = rusb::devices()?;
+        Ok(devices
+            .iter()
+            .filter_map(|dev| {
+                let descriptor = dev.device_descriptor().ok()?;
+                let vid_pid = (descriptor.vendor_id(), descriptor.product_id());
+                let serial = descriptor.serial_number_string_index().and_then(|_| {
+                    dev.open()
+                        .and_then(|handle| handle.read_serial_number_string_ascii(&descriptor))
+                        .ok()
+                });
+                let ports = dev
+                    .port_numbers()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(u8::to_string)
+                    .collect::<Vec<_>>()
+                    .join(".");
+                let backend = self
+                    .backends
+                    .iter()
+                    .find(|backend| backend.is_supported(&vid_pid))
+                    .map(|backend| backend.to_string());
+
+                Some(UsbDeviceInfo {
+                    vid: vid_pid.0,
+                    pid: vid_pid.1,
+                    serial,
+                    path: format!("{}-{}", dev.bus_number(), ports),
+                    backend,
+                })
+            })
+            .collect())
+    }
+
+    /// Reads the USB serial number of the module that is visible on the bus.
+    /// Returns `None` if the module does not report a serial.
+    pub fn read_serial(&self, node: NodeId) -> Result<Option<String>, UsbBootError> {
+        let (device, _) = self.find_one(node)?;
+        let descriptor = device.device_descriptor()?;
+        if descriptor.serial_number_string_index().is_none() {
+            return Ok(None);
+        }
 
-        backends.next().ok_or(UsbBootError::NotSupported)
+        let serial = device
+            .open()?
+            .read_serial_number_string_ascii(&descriptor)?;
+        Ok((!serial.is_empty()).then_some(serial))
     }
 
-    pub async fn load_as_block_device(&self) -> Result<PathBuf, UsbBootError> {
-        let (device, driver) = self.find_first()?;
-        driver.load_as_block_device(&device).await
+    pub async fn load_as_block_device(
+        &self,
+        node: NodeId,
+        timings: UsbTimings,
+        progress: &watch::Sender<TransferProgress>,
+    ) -> Result<(PathBuf, DetectedModule), UsbBootError> {
+        let (device, driver) = self.find_one(node)?;
+        let module = detected_module(&device, driver)?;
+        let path = driver
+            .load_as_block_device(&device, &self.context(timings, progress))
+            .await?;
+        Ok((path, module))
+    }
+
+    pub async fn load_as_stream(
+        &self,
+        node: NodeId,
+        timings: UsbTimings,
+        progress: &watch::Sender<TransferProgress>,
+    ) -> Result<(Box<dyn DataTransport>, DetectedModule), UsbBootError> {
+        let (device, driver) = self.find_one(node)?;
+        let module = detected_module(&device, driver)?;
+        let stream = driver
+            .load_as_stream(&device, &self.context(timings, progress))
+            .await?;
+        Ok((stream, module))
     }
 
-    pub async fn load_as_stream(&self) -> Result<Box<dyn DataTransport>, UsbBootError> {
-        let (device, driver) = self.find_first()?;
-        driver.load_as_stream(&device).await
+    fn context<'a>(
+        &self,
+        timings: UsbTimings,
+        progress: &'a watch::Sender<TransferProgress>,
+    ) -> BootContext<'a> {
+        BootContext {
+            device_timeout: self.device_timeout,
+            timings,
+            progress,
+        }
     }
 }
 
+fn detected_module(
+    device: &rusb::Device<GlobalContext>,
+    driver: &dyn UsbBoot,
+) -> Result<DetectedModule, UsbBootError> {
+    let descriptor = device.device_descriptor()?;
+    let module = DetectedModule {
+        name: driver.module_name(),
+        vid: descriptor.vendor_id(),
+        pid: descriptor.product_id(),
+    };
+    info!("Detected {}", module);
+    Ok(module)
+}
+
 #[derive(Error, Debug)]
 pub enum UsbBootError {
     #[error("Compute module's USB interface not found or supported")]
     NotSupported,
+    #[error("no module detected on {0}, is it seated?")]
+    NoDevice(NodeId),
+    #[error("{0} supported USB devices present, only flash one node at a time")]
+    MultipleDevices(usize),
+    #[error("no block device of vendor {0} found")]
+    NoBlockDevice(String),
+    #[error("block device of vendor {0} did not appear within {1:?}")]
+    BlockDeviceTimeout(String, Duration),
+    #[error("{0} block devices of vendor {1} found: {2}")]
+    MultipleBlockDevices(usize, String, String),
+    #[error("{} holds the root filesystem of the BMC, refusing to use it", .0.to_string_lossy())]
+    BootDisk(PathBuf),
     #[error("USB")]
     RusbError(#[from] rusb::Error),
     #[error(transparent)]
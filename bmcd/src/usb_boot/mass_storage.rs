@@ -0,0 +1,120 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::{BootContext, UsbBoot};
+use crate::{usb_boot::UsbBootError, utils::wait_for_device_path};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+
+/// A module type registered at runtime, see
+/// [`super::NodeDrivers::add_supported_device`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupportedDevice {
+    pub vid: u16,
+    pub pid: u16,
+    /// Vendor of the block device the module exposes, as reported in
+    /// `/sys/block/*/device/vendor`.
+    pub disk_vendor: String,
+}
+
+/// Backend of the [`SupportedDevice`]s. These modules expose their storage as
+/// mass-storage device by themselves, e.g. pre-release modules that run a USB
+/// gadget, hence there is nothing to boot.
+#[derive(Clone, Default)]
+pub struct MassStorageBoot {
+    devices: Arc<RwLock<Vec<SupportedDevice>>>,
+}
+
+impl MassStorageBoot {
+    pub fn new(devices: Vec<SupportedDevice>) -> Self {
+        Self {
+            devices: Arc::new(RwLock::new(devices)),
+        }
+    }
+
+    /// Adds `device` to the table, returns the updated table.
+    pub fn add(&self, device: SupportedDevice) -> Vec<SupportedDevice> {
+        let mut devices = self.devices.write().expect("device table poisoned");
+        devices.push(device);
+        devices.clone()
+    }
+
+    pub fn clear(&self) {
+        self.devices.write().expect("device table poisoned").clear();
+    }
+
+    pub fn devices(&self) -> Vec<SupportedDevice> {
+        self.devices.read().expect("device table poisoned").clone()
+    }
+
+    fn find(&self, vid_pid: &(u16, u16)) -> Option<SupportedDevice> {
+        self.devices
+            .read()
+            .expect("device table poisoned")
+            .iter()
+            .find(|device| (device.vid, device.pid) == *vid_pid)
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl UsbBoot for MassStorageBoot {
+    fn is_supported(&self, vid_pid: &(u16, u16)) -> bool {
+        self.find(vid_pid).is_some()
+    }
+
+    fn module_name(&self) -> &'static str {
+        "custom module"
+    }
+
+    async fn load_as_block_device(
+        &self,
+        device: &rusb::Device<rusb::GlobalContext>,
+        context: &BootContext<'_>,
+    ) -> Result<std::path::PathBuf, UsbBootError> {
+        let descriptor = device.device_descriptor()?;
+        let device = self
+            .find(&(descriptor.vendor_id(), descriptor.product_id()))
+            .ok_or(UsbBootError::NotSupported)?;
+        wait_for_device_path(&[&device.disk_vendor], context).await
+    }
+}
+
+impl Display for MassStorageBoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mass storage")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn added_devices_are_supported() {
+        let backend = MassStorageBoot::default();
+        assert!(!backend.is_supported(&(0x1234, 0x5678)));
+
+        let device = SupportedDevice {
+            vid: 0x1234,
+            pid: 0x5678,
+            disk_vendor: "Custom".to_string(),
+        };
+        let devices = backend.clone().add(device.clone());
+        assert_eq!(devices, vec![device]);
+        assert!(backend.is_supported(&(0x1234, 0x5678)));
+        assert!(!backend.is_supported(&(0x1234, 0x5679)));
+    }
+}
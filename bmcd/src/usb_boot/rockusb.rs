@@ -1,5 +1,3 @@
-use crate::utils::get_device_path;
-
 // Copyright 2023 Turing Machines
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -13,7 +11,8 @@ use crate::utils::get_device_path;
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::{UsbBoot, UsbBootError};
+use super::{BootContext, UsbBoot, UsbBootError};
+use crate::utils::wait_for_device_path;
 use async_trait::async_trait;
 use rockfile::boot::{
     RkBootEntry, RkBootEntryBytes, RkBootHeader, RkBootHeaderBytes, RkBootHeaderEntry,
@@ -34,20 +33,23 @@ impl UsbBoot for RockusbBoot {
         vid_pid == &RK3588_VID_PID
     }
 
+    fn module_name(&self) -> &'static str {
+        "Turing RK1"
+    }
+
     async fn load_as_block_device(
         &self,
         device: &rusb::Device<GlobalContext>,
+        context: &BootContext<'_>,
     ) -> Result<std::path::PathBuf, UsbBootError> {
         if BootMode::Maskrom == device.device_descriptor()?.into() {
             info!("Maskrom mode detected. loading usb-plug..");
             let mut transport =
                 Transport::from_usb_device(device.open()?).map_err(UsbBootError::internal_error)?;
-            download_boot(&mut transport).await?;
+            download_boot(&mut transport, context.timings.rockusb_reconnect).await?;
         }
 
-        get_device_path(&["Rockchip"])
-            .await
-            .map_err(UsbBootError::internal_error)
+        wait_for_device_path(&["Rockchip"], context).await
     }
 }
 
@@ -57,11 +59,14 @@ impl Display for RockusbBoot {
     }
 }
 
-async fn download_boot(transport: &mut Transport) -> Result<(), UsbBootError> {
+async fn download_boot(
+    transport: &mut Transport,
+    reconnect_delay: Duration,
+) -> Result<(), UsbBootError> {
     let boot_entries = parse_boot_entries(SPL_LOADER_RK3588)?;
     load_boot_entries(transport, boot_entries).await?;
     // Rockchip will reconnect to USB, back off a bit
-    tokio::time::sleep(Duration::from_secs(5)).await;
+    tokio::time::sleep(reconnect_delay).await;
     Ok(())
 }
 
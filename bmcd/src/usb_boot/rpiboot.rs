@@ -11,11 +11,10 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::UsbBoot;
-use crate::{usb_boot::UsbBootError, utils::get_device_path};
+use super::{BootContext, UsbBoot};
+use crate::{usb_boot::UsbBootError, utils::wait_for_device_path};
 use async_trait::async_trait;
 use std::{fmt::Display, time::Duration};
-use tokio::time::sleep;
 
 const VID_PID: (u16, u16) = (0x0a5c, 0x2711);
 
@@ -27,15 +26,24 @@ impl UsbBoot for RpiBoot {
         vid_pid == &VID_PID
     }
 
+    fn module_name(&self) -> &'static str {
+        "Raspberry Pi CM4"
+    }
+
+    /// The bootloader of the CM4 needs a handshake before the module shows up
+    /// on the bus.
+    fn power_on_settle(&self) -> Duration {
+        Duration::from_secs(2)
+    }
+
     async fn load_as_block_device(
         &self,
         _device: &rusb::Device<rusb::GlobalContext>,
+        context: &BootContext<'_>,
     ) -> Result<std::path::PathBuf, UsbBootError> {
-        load_rpi_boot().await?;
+        load_rpi_boot(context.timings.rpiboot_stage_delay)?;
         tracing::info!("Checking for presence of a device file ('RPi-MSD-.*')...");
-        get_device_path(&["RPi-MSD-"])
-            .await
-            .map_err(UsbBootError::internal_error)
+        wait_for_device_path(&["RPi-MSD-"], context).await
     }
 }
 
@@ -45,9 +53,9 @@ impl Display for RpiBoot {
     }
 }
 
-async fn load_rpi_boot() -> Result<(), UsbBootError> {
+fn load_rpi_boot(stage_delay: Duration) -> Result<(), UsbBootError> {
     let options = rustpiboot::Options {
-        delay: 500 * 1000,
+        delay: stage_delay.as_micros().try_into().unwrap_or(u64::MAX),
         ..Default::default()
     };
 
@@ -58,6 +66,5 @@ async fn load_rpi_boot() -> Result<(), UsbBootError> {
         ))
     })?;
 
-    sleep(Duration::from_secs(3)).await;
     Ok(())
 }
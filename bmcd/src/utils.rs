@@ -11,18 +11,38 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+mod boot_disk;
 mod event_listener;
 mod io;
 
-use anyhow::bail;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::usb_boot::{BootContext, UsbBootError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[doc(inline)]
+pub use boot_disk::ensure_not_boot_disk;
 pub use event_listener::*;
 pub use io::*;
-use std::{path::PathBuf, process::Output};
+use std::{
+    path::{Path, PathBuf},
+    process::Output,
+};
 use tokio::io::AsyncBufReadExt;
 
+/// Returns the path of the file `name` in `dir`. Only plain file names are
+/// accepted, and symbolic links are rejected, so that the path cannot point
+/// outside of `dir`. The file does not need to exist.
+pub fn file_in_dir(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || Path::new(name).file_name() != Some(name.as_ref()) {
+        anyhow::bail!("invalid file name `{name}`");
+    }
+
+    let path = dir.join(name);
+    if path.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        anyhow::bail!("`{name}` is a symbolic link");
+    }
+    Ok(path)
+}
+
 pub fn string_from_utf16(bytes: &[u8], little_endian: bool) -> String {
     let u16s = bytes.chunks_exact(2).map(|pair| {
         let Ok(owned) = pair.try_into() else {
@@ -66,7 +86,7 @@ pub fn string_from_utf32(bytes: &[u8], little_endian: bool) -> String {
         .collect()
 }
 
-pub async fn get_device_path(allowed_vendors: &[&str]) -> anyhow::Result<PathBuf> {
+pub async fn get_device_path(allowed_vendors: &[&str]) -> Result<PathBuf, UsbBootError> {
     let mut contents = tokio::fs::read_dir("/sys/block/").await.map_err(|err| {
         std::io::Error::new(err.kind(), format!("Failed to list devices: {}", err))
     })?;
@@ -97,15 +117,56 @@ pub async fn get_device_path(allowed_vendors: &[&str]) -> anyhow::Result<PathBuf
 
     let name = match &matching_devices[..] {
         [] => {
-            bail!("No supported USB devices found");
+            return Err(UsbBootError::NoBlockDevice(allowed_vendors.join("|")));
         }
         [device] => device.clone(),
-        _ => {
-            bail!("Several supported devices found");
+        devices => {
+            return Err(UsbBootError::MultipleBlockDevices(
+                devices.len(),
+                allowed_vendors.join("|"),
+                devices.join(","),
+            ));
         }
     };
 
-    Ok(tokio::fs::canonicalize(format!("/dev/{}", name)).await?)
+    let path = tokio::fs::canonicalize(format!("/dev/{}", name)).await?;
+    ensure_not_boot_disk(&path)?;
+    Ok(path)
+}
+
+/// Polls [`get_device_path`] until the block device shows up, or the timeout
+/// of `context` expires. Progress of the wait is published while polling.
+pub async fn wait_for_device_path(
+    allowed_vendors: &[&str],
+    context: &BootContext<'_>,
+) -> Result<PathBuf, UsbBootError> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    let start = Instant::now();
+    loop {
+        match get_device_path(allowed_vendors).await {
+            Err(UsbBootError::NoBlockDevice(vendors)) => {
+                let elapsed = start.elapsed();
+                if elapsed >= context.device_timeout {
+                    return Err(UsbBootError::BlockDeviceTimeout(
+                        vendors,
+                        context.device_timeout,
+                    ));
+                }
+
+                context.progress.send_modify(|p| {
+                    p.set_message(format!(
+                        "waiting for block device of vendor {} ({}s/{}s)",
+                        vendors,
+                        elapsed.as_secs(),
+                        context.device_timeout.as_secs()
+                    ))
+                });
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            result => return result,
+        }
+    }
 }
 
 /// Get current time in seconds since Unix epoch. Returns `None` if current time is before epoch.
@@ -128,3 +189,23 @@ pub async fn logging_sink_stdio(output: &Output) -> std::io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn files_must_stay_in_dir() {
+        let dir = TempDir::new("files").unwrap();
+        std::os::unix::fs::symlink("/etc/shadow", dir.path().join("link")).unwrap();
+
+        assert_eq!(
+            file_in_dir(dir.path(), "manifest.json").unwrap(),
+            dir.path().join("manifest.json")
+        );
+        for name in ["", "..", "../manifest.json", "/etc/shadow", "a/b", "link"] {
+            assert!(file_in_dir(dir.path(), name).is_err(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Guards the disk the BMC boots from against being flashed.
+use crate::usb_boot::UsbBootError;
+use nix::sys::stat::{major, minor};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Maps the device numbers of block devices to their sysfs directories.
+const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+
+/// Fails with [`UsbBootError::BootDisk`] when `device` is the disk that holds
+/// the root filesystem of the BMC, or a partition of it. Writing to it would
+/// destroy the running system. Passes when the root filesystem is not on a
+/// block device, e.g. on a ramdisk.
+pub fn ensure_not_boot_disk(device: &Path) -> Result<(), UsbBootError> {
+    let root = std::fs::metadata("/")?.dev();
+    let device_number = std::fs::metadata(device)?.rdev();
+    if is_boot_disk(Path::new(SYS_DEV_BLOCK), device_number, root) {
+        return Err(UsbBootError::BootDisk(device.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Whether the block device `device` is on the same disk as the block device
+/// `root`. Both are device numbers, resolved by `sys_dev_block`.
+fn is_boot_disk(sys_dev_block: &Path, device: u64, root: u64) -> bool {
+    let Ok(root_disk) = disk_of(sys_dev_block, root) else {
+        return false;
+    };
+    // a device that cannot be resolved is not a disk of this system.
+    disk_of(sys_dev_block, device).is_ok_and(|disk| disk == root_disk)
+}
+
+/// Returns the sysfs directory of the whole disk of the block device `dev`.
+fn disk_of(sys_dev_block: &Path, dev: u64) -> std::io::Result<PathBuf> {
+    let path = sys_dev_block.join(format!("{}:{}", major(dev), minor(dev)));
+    let path = std::fs::canonicalize(path)?;
+    match path.parent() {
+        Some(disk) if path.join("partition").exists() => Ok(disk.to_path_buf()),
+        _ => Ok(path),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::stat::makedev;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    #[test]
+    fn partitions_of_the_root_disk_are_refused() {
+        let sys = TempDir::new("boot_disk").unwrap();
+        let block = sys.path().join("devices/block");
+        let dev_block = sys.path().join("dev/block");
+        std::fs::create_dir_all(block.join("mmcblk0/mmcblk0p1")).unwrap();
+        std::fs::create_dir_all(block.join("mmcblk0/mmcblk0p2")).unwrap();
+        std::fs::create_dir_all(block.join("sda")).unwrap();
+        std::fs::create_dir_all(&dev_block).unwrap();
+        std::fs::write(block.join("mmcblk0/mmcblk0p1/partition"), "1").unwrap();
+        std::fs::write(block.join("mmcblk0/mmcblk0p2/partition"), "2").unwrap();
+        symlink(block.join("mmcblk0"), dev_block.join("179:0")).unwrap();
+        symlink(block.join("mmcblk0/mmcblk0p1"), dev_block.join("179:1")).unwrap();
+        symlink(block.join("mmcblk0/mmcblk0p2"), dev_block.join("179:2")).unwrap();
+        symlink(block.join("sda"), dev_block.join("8:0")).unwrap();
+
+        let root = makedev(179, 2);
+        assert!(is_boot_disk(&dev_block, makedev(179, 0), root));
+        assert!(is_boot_disk(&dev_block, makedev(179, 1), root));
+        assert!(is_boot_disk(&dev_block, root, root));
+        assert!(!is_boot_disk(&dev_block, makedev(8, 0), root));
+        // root on a ramdisk or network filesystem
+        assert!(!is_boot_disk(&dev_block, makedev(8, 0), makedev(0, 21)));
+    }
+}
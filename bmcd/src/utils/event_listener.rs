@@ -11,9 +11,19 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use evdev::{Device, EventSummary, KeyCode};
+use evdev::{Device, EventStream, EventSummary, KeyCode};
 use std::collections::{HashMap, HashSet};
-use tracing::{debug, trace, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, error, trace, warn};
+
+const SYS_CLASS_INPUT: &str = "/sys/class/input";
+const DEV_INPUT: &str = "/dev/input";
+/// Failures in a row, without any event read in between, after which the
+/// listener gives up on the device.
+const MAX_FAILURES: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 type ActionFn<T> = Box<dyn Fn(&'_ mut T) + Send + Sync>;
 
@@ -21,11 +31,11 @@ type ActionFn<T> = Box<dyn Fn(&'_ mut T) + Send + Sync>;
 pub struct EventListener<T> {
     context: T,
     map: HashMap<(KeyCode, i32), ActionFn<T>>,
-    device_path: &'static str,
+    device_path: PathBuf,
 }
 
 impl<T: Send + Sync + 'static> EventListener<T> {
-    pub fn new(context: T, device_path: &'static str) -> Self {
+    pub fn new(context: T, device_path: PathBuf) -> Self {
         Self {
             map: HashMap::new(),
             context,
@@ -41,28 +51,59 @@ impl<T: Send + Sync + 'static> EventListener<T> {
         self
     }
 
-    /// non-blocking call to start listening for events of interest.
+    /// non-blocking call to start listening for events of interest. Only
+    /// fails when the device cannot be opened. Read errors later on, e.g.
+    /// when the device disappeared during a suspend, reopen the device with
+    /// an increasing delay, up to [`MAX_FAILURES`] times in a row.
     pub fn run(mut self) -> std::io::Result<()> {
-        let device = Device::open(self.device_path)?;
+        let device = Device::open(&self.device_path)?;
         self.verify_required_keys(&device);
 
-        let mut event_stream = device.into_event_stream()?;
+        let event_stream = device.into_event_stream()?;
         tokio::spawn(async move {
-            while let Ok(event) = event_stream.next_event().await {
-                trace!("processing event {:?}", event);
-                if let EventSummary::Key(_, code, value) = event.destructure() {
-                    if let Some(action) = self.map.get(&(code, value)) {
-                        action(&mut self.context);
-                    } else {
-                        debug!("no handler defined for event {:?}", event);
-                    }
+            let mut failures = 0;
+            let mut stream = Ok(event_stream);
+            loop {
+                let error = match stream {
+                    Ok(mut stream) => self.dispatch(&mut stream, &mut failures).await,
+                    Err(e) => e,
+                };
+
+                failures += 1;
+                let path = self.device_path.to_string_lossy();
+                if failures >= MAX_FAILURES {
+                    error!("{}: {}, shutting down event listener", path, error);
+                    return;
                 }
+                let delay = backoff(failures);
+                warn!("{}: {}, reopening in {:?}", path, error, delay);
+                tokio::time::sleep(delay).await;
+                stream = Device::open(&self.device_path).and_then(Device::into_event_stream);
             }
-            tracing::info!("shutting down event listener");
         });
         Ok(())
     }
 
+    /// Handles the events of `stream` until reading fails. Every event read
+    /// resets `failures`.
+    async fn dispatch(&mut self, stream: &mut EventStream, failures: &mut u32) -> std::io::Error {
+        loop {
+            let event = match stream.next_event().await {
+                Ok(event) => event,
+                Err(e) => return e,
+            };
+            *failures = 0;
+            trace!("processing event {:?}", event);
+            if let EventSummary::Key(_, code, value) = event.destructure() {
+                if let Some(action) = self.map.get(&(code, value)) {
+                    action(&mut self.context);
+                } else {
+                    debug!("no handler defined for event {:?}", event);
+                }
+            }
+        }
+    }
+
     fn verify_required_keys(&self, device: &Device) {
         let required_keys = self
             .map
@@ -89,3 +130,132 @@ impl<T: Send + Sync + 'static> EventListener<T> {
         }
     }
 }
+
+/// Delay before reopening the device after the `failures`th failure in a row,
+/// doubled on every failure.
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+/// Resolves `spec` to the path of an input device. `spec` is either:
+/// * `name:<name>`, the device that the kernel reports by that name,
+/// * a path with `*` wildcards in its file name, e.g.
+///   `/dev/input/by-path/*-event`, of which the first match is taken,
+/// * or a plain path, e.g. a `/dev/input/by-id` symlink.
+///
+/// Unlike `/dev/input/eventN`, the first two do not depend on the order in
+/// which the devices are enumerated. Returns `None` when nothing matches or
+/// the plain path does not exist.
+pub fn find_input_device(spec: &str) -> std::io::Result<Option<PathBuf>> {
+    if let Some(name) = spec.strip_prefix("name:") {
+        return find_by_name(Path::new(SYS_CLASS_INPUT), Path::new(DEV_INPUT), name);
+    }
+
+    let path = Path::new(spec);
+    let pattern = path.file_name().and_then(|name| name.to_str());
+    match (path.parent(), pattern) {
+        (Some(dir), Some(pattern)) if pattern.contains('*') => {
+            let mut matches = Vec::new();
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| wildcard_match(pattern, name))
+                {
+                    matches.push(entry.path());
+                }
+            }
+            matches.sort();
+            Ok(matches.into_iter().next())
+        }
+        _ => Ok(path.exists().then(|| path.to_path_buf())),
+    }
+}
+
+/// Finds the `eventN` device in `dev_input` whose name in `sys_class_input`
+/// equals `name`.
+pub(crate) fn find_by_name(
+    sys_class_input: &Path,
+    dev_input: &Path,
+    name: &str,
+) -> std::io::Result<Option<PathBuf>> {
+    let mut events = Vec::new();
+    for entry in std::fs::read_dir(sys_class_input)? {
+        let event = entry?.file_name();
+        if event.to_string_lossy().starts_with("event") {
+            events.push(event);
+        }
+    }
+    events.sort();
+
+    for event in events {
+        let device_name = sys_class_input.join(&event).join("device/name");
+        if std::fs::read_to_string(device_name).is_ok_and(|n| n.trim() == name) {
+            return Ok(Some(dev_input.join(event)));
+        }
+    }
+    Ok(None)
+}
+
+/// Matches `name` against `pattern`, in which `*` matches any sequence of
+/// characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len())
+                .filter(|i| name.is_char_boundary(*i))
+                .any(|i| wildcard_match(rest, &name[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn reopening_backs_off() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(6), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn input_devices_are_found_by_name_and_pattern() {
+        let root = TempDir::new("input").unwrap();
+        let sys = root.path().join("sys");
+        let dev = root.path().join("dev");
+        for (event, name) in [("event0", "gpio-keys-other"), ("event1", "gpio-keys")] {
+            std::fs::create_dir_all(sys.join(event).join("device")).unwrap();
+            std::fs::write(sys.join(event).join("device/name"), format!("{name}\n")).unwrap();
+        }
+        std::fs::create_dir_all(&dev).unwrap();
+        std::fs::write(dev.join("platform-keys-event"), "").unwrap();
+
+        assert_eq!(
+            find_by_name(&sys, &dev, "gpio-keys").unwrap(),
+            Some(dev.join("event1"))
+        );
+        assert_eq!(find_by_name(&sys, &dev, "unknown").unwrap(), None);
+
+        let pattern = dev.join("platform-*-event");
+        assert_eq!(
+            find_input_device(pattern.to_str().unwrap()).unwrap(),
+            Some(dev.join("platform-keys-event"))
+        );
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match(
+            "platform-*-event",
+            "platform-keys-event-joystick"
+        ));
+    }
+}
@@ -11,8 +11,10 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crc::{Crc, Digest as CrcDigest};
+use crate::streaming_data_service::transfer_context::TransferProgress;
+use crc::{Crc, Digest as CrcDigest, CRC_64_REDIS};
 use futures::Stream;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::{io, pin::Pin, task::Poll};
 use tokio::{io::AsyncWrite, sync::watch};
@@ -99,13 +101,219 @@ where
     }
 }
 
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+/// Checksum algorithms that a [`WriteMonitor`] can compute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgo {
+    #[default]
+    Crc64,
+    Crc32,
+    Sha256,
+}
+
+enum Hasher {
+    Crc64(CrcDigest<'static, u64>),
+    Crc32(crc32fast::Hasher),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Crc64 => Hasher::Crc64(CRC64.digest()),
+            ChecksumAlgo::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Crc64(digest) => digest.update(bytes),
+            Hasher::Crc32(hasher) => hasher.update(bytes),
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// CRCs are returned in big-endian order, so that their hex encoding reads
+    /// as the number.
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Crc64(digest) => digest.finalize().to_be_bytes().to_vec(),
+            Hasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Incremental checksum of a stream of bytes.
+pub struct Checksum(Hasher);
+
+impl Checksum {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        Self(Hasher::new(algo))
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes)
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        self.0.finalize()
+    }
+}
+
+/// CRC32s of the consecutive blocks of a stream of bytes. Unlike a
+/// [`Checksum`] of the whole stream, they tell where two streams differ.
+pub struct BlockDigests {
+    block_size: usize,
+    hasher: crc32fast::Hasher,
+    filled: usize,
+    len: u64,
+    digests: Vec<u32>,
+}
+
+impl BlockDigests {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            hasher: crc32fast::Hasher::new(),
+            filled: 0,
+            len: 0,
+            digests: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let take = bytes.len().min(self.block_size - self.filled);
+            self.hasher.update(&bytes[..take]);
+            self.filled += take;
+            bytes = &bytes[take..];
+            if self.filled == self.block_size {
+                self.end_block();
+            }
+        }
+    }
+
+    /// Completes the last block if it is partially filled.
+    pub fn finish(&mut self) {
+        if self.filled > 0 {
+            self.end_block();
+        }
+    }
+
+    /// Digests of the completed blocks.
+    pub fn digests(&self) -> &[u32] {
+        &self.digests
+    }
+
+    /// Size of the block `index`, the last block may be shorter.
+    fn block_len(&self, index: usize) -> u64 {
+        let start = index as u64 * self.block_size as u64;
+        self.len.saturating_sub(start).min(self.block_size as u64)
+    }
+
+    fn end_block(&mut self) {
+        let hasher = std::mem::take(&mut self.hasher);
+        self.digests.push(hasher.finalize());
+        self.filled = 0;
+    }
+}
+
+/// Sink that compares data read back from a device with the [`BlockDigests`]
+/// of the image, as the data comes in. The amount of matching data, and the
+/// first block that differs, are published as [`TransferProgress`].
+pub struct BlockVerifier<'a> {
+    expected: &'a [u32],
+    digests: BlockDigests,
+    compared: usize,
+    verified: u64,
+    first_divergence: Option<u64>,
+    sender: &'a watch::Sender<TransferProgress>,
+}
+
+impl<'a> BlockVerifier<'a> {
+    /// `expected` are the digests of the image, taken with `block_size`.
+    pub fn new(
+        expected: &'a [u32],
+        block_size: usize,
+        sender: &'a watch::Sender<TransferProgress>,
+    ) -> Self {
+        Self {
+            expected,
+            digests: BlockDigests::new(block_size),
+            compared: 0,
+            verified: 0,
+            first_divergence: None,
+            sender,
+        }
+    }
+
+    /// Compares the last, partial block. Returns the offset of the first block
+    /// that differs from the image, if any.
+    pub fn finish(mut self) -> Option<u64> {
+        self.digests.finish();
+        self.compare();
+        self.first_divergence
+    }
+
+    fn compare(&mut self) {
+        let completed = self.digests.digests().len();
+        if self.compared == completed {
+            return;
+        }
+
+        for index in self.compared..completed {
+            if self.expected.get(index) == Some(&self.digests.digests()[index]) {
+                self.verified += self.digests.block_len(index);
+            } else if self.first_divergence.is_none() {
+                let offset = index as u64 * self.digests.block_size as u64;
+                self.first_divergence = Some(offset);
+                self.sender.send_modify(|p| p.set_divergence(offset));
+            }
+        }
+        self.compared = completed;
+
+        let verified = self.verified;
+        self.sender.send_modify(|p| p.set_verified(verified));
+    }
+}
+
+impl AsyncWrite for BlockVerifier<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = Pin::get_mut(self);
+        me.digests.update(buf);
+        me.compare();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
 pub struct WriteMonitor<'a, W>
 where
     W: AsyncWrite,
 {
     written: u64,
-    sender: &'a mut watch::Sender<u64>,
-    digest: CrcDigest<'a, u64>,
+    sender: &'a watch::Sender<TransferProgress>,
+    hasher: Hasher,
+    blocks: Option<BlockDigests>,
     inner: W,
 }
 
@@ -113,21 +321,40 @@ impl<'a, W> WriteMonitor<'a, W>
 where
     W: AsyncWrite,
 {
-    pub fn new(writer: W, sender: &'a mut watch::Sender<u64>, crc: &'a Crc<u64>) -> Self {
+    pub fn new(writer: W, sender: &'a watch::Sender<TransferProgress>, algo: ChecksumAlgo) -> Self {
         Self {
             written: 0,
             sender,
-            digest: crc.digest(),
+            hasher: Hasher::new(algo),
+            blocks: None,
             inner: writer,
         }
     }
 
-    pub fn crc(self) -> u64 {
-        self.digest.finalize()
+    /// Also takes the [`BlockDigests`] of the written data.
+    pub fn with_block_digests(mut self, block_size: usize) -> Self {
+        self.blocks = Some(BlockDigests::new(block_size));
+        self
+    }
+
+    /// The block digests of the data written so far, empty unless enabled
+    /// with [`Self::with_block_digests`].
+    pub fn block_digests(&mut self) -> Vec<u32> {
+        self.blocks
+            .take()
+            .map(|mut blocks| {
+                blocks.finish();
+                blocks.digests
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn checksum(self) -> Vec<u8> {
+        self.hasher.finalize()
     }
 }
 
-impl<'_, W> AsyncWrite for WriteMonitor<'_, W>
+impl<W> AsyncWrite for WriteMonitor<'_, W>
 where
     W: AsyncWrite + Unpin,
 {
@@ -140,9 +367,14 @@ where
 
         let result = Pin::new(&mut me.inner).poll_write(cx, buf);
         if let Poll::Ready(Ok(written)) = result {
-            me.digest.update(&buf[..written]);
+            me.hasher.update(&buf[..written]);
+            if let Some(blocks) = &mut me.blocks {
+                blocks.update(&buf[..written]);
+            }
             me.written += written as u64;
-            me.sender.send_replace(me.written);
+            let written = me.written;
+            me.sender
+                .send_modify(|progress| progress.set_written(written));
         }
         result
     }
@@ -167,7 +399,6 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
-    use crc::CRC_64_REDIS;
     use rand::RngCore;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
@@ -179,13 +410,12 @@ mod test {
 
     #[tokio::test]
     async fn write_watcher_test() {
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
         let mut reader = tokio::io::repeat(0b101).take(1044 * 1004);
-        let (mut sender, receiver) = watch::channel(0u64);
-        let mut writer = WriteMonitor::new(tokio::io::sink(), &mut sender, &crc);
+        let (sender, receiver) = watch::channel(TransferProgress::default());
+        let mut writer = WriteMonitor::new(tokio::io::sink(), &sender, ChecksumAlgo::Crc64);
         let copied = tokio::io::copy(&mut reader, &mut writer).await.unwrap();
         assert_eq!(copied, 1044 * 1004);
-        assert_eq!(*receiver.borrow(), 1044 * 1004);
+        assert_eq!(receiver.borrow().bytes_written, 1044 * 1004);
     }
 
     #[tokio::test]
@@ -194,9 +424,8 @@ mod test {
         let expected_crc = Crc::<u64>::new(&CRC_64_REDIS).checksum(&read_buffer);
 
         let mut data = Vec::new();
-        let crc = Crc::<u64>::new(&CRC_64_REDIS);
-        let (mut sender, _) = watch::channel(0u64);
-        let mut writer = WriteMonitor::new(&mut data, &mut sender, &crc);
+        let (sender, _) = watch::channel(TransferProgress::default());
+        let mut writer = WriteMonitor::new(&mut data, &sender, ChecksumAlgo::Crc64);
 
         let mut total_read = 0;
         while total_read < read_buffer.len() {
@@ -209,7 +438,54 @@ mod test {
             }
         }
 
-        assert_eq!(expected_crc, writer.crc());
+        assert_eq!(expected_crc.to_be_bytes().to_vec(), writer.checksum());
+    }
+
+    #[tokio::test]
+    async fn checksum_algorithms() {
+        let (sender, _) = watch::channel(TransferProgress::default());
+        let expected = [
+            (ChecksumAlgo::Crc32, "cbf43926"),
+            (
+                ChecksumAlgo::Sha256,
+                "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225",
+            ),
+        ];
+
+        for (algo, checksum) in expected {
+            let mut writer = WriteMonitor::new(tokio::io::sink(), &sender, algo);
+            writer.write_all(b"123456789").await.unwrap();
+            assert_eq!(hex::encode(writer.checksum()), checksum);
+        }
+    }
+
+    #[tokio::test]
+    async fn block_verifier_locates_divergent_block() {
+        let image = random_array::<{ 3 * 1024 + 100 }>();
+        let (sender, receiver) = watch::channel(TransferProgress::new(image.len() as u64, vec![]));
+        let mut writer = WriteMonitor::new(tokio::io::sink(), &sender, ChecksumAlgo::Crc64)
+            .with_block_digests(1024);
+        writer.write_all(&image).await.unwrap();
+        let expected = writer.block_digests();
+        assert_eq!(expected.len(), 4);
+
+        let mut verifier = BlockVerifier::new(&expected, 1024, &sender);
+        verifier.write_all(&image).await.unwrap();
+        assert_eq!(verifier.finish(), None);
+        assert_eq!(receiver.borrow().verified_percent, Some(100.0));
+
+        let mut device = image.clone();
+        device[2 * 1024 + 5] ^= 0xff;
+        let mut verifier = BlockVerifier::new(&expected, 1024, &sender);
+        // chunks that do not line up with the blocks
+        for chunk in device.chunks(700) {
+            verifier.write_all(chunk).await.unwrap();
+        }
+        assert_eq!(verifier.finish(), Some(2 * 1024));
+        let progress = receiver.borrow();
+        assert_eq!(progress.first_divergence, Some(2 * 1024));
+        let verified = 100.0 * (2 * 1024 + 100) as f64 / image.len() as f64;
+        assert!((progress.verified_percent.unwrap() - verified).abs() < 1e-9);
     }
 
     //   #[tokio::test]